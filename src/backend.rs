@@ -0,0 +1,82 @@
+// Copyright 2022-2023 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::Arc;
+
+use vhost::vhost_user::message::VhostUserProtocolFeatures;
+use vhost_user_frontend::{Generic, GuestMemoryMmap, VirtioDevice, VirtioInterrupt};
+use virtio_queue::Queue;
+use vm_memory::GuestMemoryAtomic;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::Result;
+
+// The vhost-user backend of a device, implemented by Generic over the backend's socket.
+pub trait Backend: Send {
+    fn queue_max_sizes(&self) -> &[u16];
+    fn device_features(&self) -> u64;
+    fn negotiate_features(
+        &mut self,
+        features: u64,
+        protocol: VhostUserProtocolFeatures,
+    ) -> Result<()>;
+    fn read_config(&self, offset: u64, data: &mut [u8]);
+    fn write_config(&mut self, offset: u64, data: &[u8]);
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt: Arc<dyn VirtioInterrupt>,
+        queues: Vec<(usize, Queue, EventFd)>,
+    ) -> Result<()>;
+    fn reset(&mut self);
+    fn shutdown(&mut self);
+}
+
+// Generic implements most of these as VirtioDevice, the calls are spelled out to not pick the
+// methods of this trait instead.
+impl Backend for Generic {
+    fn queue_max_sizes(&self) -> &[u16] {
+        VirtioDevice::queue_max_sizes(self)
+    }
+
+    fn device_features(&self) -> u64 {
+        Generic::device_features(self)
+    }
+
+    fn negotiate_features(
+        &mut self,
+        features: u64,
+        protocol: VhostUserProtocolFeatures,
+    ) -> Result<()> {
+        Generic::negotiate_features(self, features, protocol)?;
+        Ok(())
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        VirtioDevice::read_config(self, offset, data)
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        VirtioDevice::write_config(self, offset, data)
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt: Arc<dyn VirtioInterrupt>,
+        queues: Vec<(usize, Queue, EventFd)>,
+    ) -> Result<()> {
+        VirtioDevice::activate(self, mem, interrupt, queues)?;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        VirtioDevice::reset(self);
+    }
+
+    fn shutdown(&mut self) {
+        VirtioDevice::shutdown(self)
+    }
+}
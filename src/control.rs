@@ -0,0 +1,137 @@
+// Copyright 2022-2023 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Control socket, for management tools to look at and act on the devices without going through
+// Xenstore. The protocol is line based, each command gets a single line in reply:
+//
+//   list                    -> the state of the frontend as JSON, as dumped on SIGUSR1
+//   remove <domid> <devid>  -> "ok", once the device is removed
+//
+// Failures are replied to with "error: <reason>".
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::fs::FileTypeExt,
+    os::unix::net::{UnixListener, UnixStream},
+    sync::Arc,
+    thread::Builder,
+};
+
+use super::{frontend::XenFrontend, status::status_json, Error, Result};
+
+fn command(frontend: &XenFrontend, line: &str) -> String {
+    let words: Vec<&str> = line.split_whitespace().collect();
+
+    match words.as_slice() {
+        ["list"] => status_json(&frontend.list()),
+        ["remove", domid, dev_id] => match (domid.parse::<u16>(), dev_id.parse::<u32>()) {
+            (Ok(domid), Ok(dev_id)) => {
+                frontend.remove_device(domid, dev_id);
+                "ok".to_string()
+            }
+            _ => "error: invalid domid or devid".to_string(),
+        },
+        _ => format!("error: unknown command {:?}", line),
+    }
+}
+
+fn client(frontend: &XenFrontend, stream: UnixStream) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => {
+            println!("Control socket: Failed to set up connection: {}", e);
+            return;
+        }
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+
+        if writeln!(writer, "{}", command(frontend, &line)).is_err() {
+            return;
+        }
+    }
+}
+
+pub fn setup_control_socket(frontend: Arc<XenFrontend>, path: &str) -> Result<()> {
+    // A socket left behind by an earlier run would make the bind fail, anything else at the path
+    // is left alone.
+    if let Ok(meta) = fs::metadata(path) {
+        if meta.file_type().is_socket() {
+            fs::remove_file(path).ok();
+        }
+    }
+
+    let listener = UnixListener::bind(path).map_err(Error::ControlSocket)?;
+
+    Builder::new()
+        .name("control".to_string())
+        .spawn(move || {
+            // Connections are served one at a time, the commands are quick and meant for a single
+            // management tool.
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => client(&frontend, stream),
+                    Err(e) => println!("Control socket: Failed to accept connection: {}", e),
+                }
+            }
+        })
+        .unwrap();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stubs::{stub_args, Calls, StubBackend, StubGuest, STUB_DOMID};
+    use std::{env, process};
+
+    #[test]
+    fn test_control_socket() {
+        let path = env::temp_dir().join(format!("xen-vhost-frontend-{}-control", process::id()));
+        let path = path.to_str().unwrap();
+        let frontend = XenFrontend::new(stub_args(&[])).unwrap();
+        let stub = StubGuest::new(stub_args(&[]));
+        let backend = || StubBackend::new(vec![256], 0, Arc::new(Calls::default()));
+
+        stub.add_device(0, "i2c", backend(), true).unwrap();
+        stub.add_device(1, "gpio", backend(), true).unwrap();
+        frontend.add_guest(stub.guest.clone());
+        setup_control_socket(frontend.clone(), path).unwrap();
+
+        let stream = UnixStream::connect(path).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let mut send = |command: &str| {
+            writeln!(writer, "{}", command).unwrap();
+            lines.next().unwrap().unwrap()
+        };
+
+        let list = send("list");
+        assert!(list.contains("\"dev_id\":0,"));
+        assert_eq!(list, status_json(&frontend.list()));
+
+        assert_eq!(send(&format!("remove {} 0", STUB_DOMID)), "ok");
+        let devices: Vec<u32> = stub
+            .guest
+            .snapshot()
+            .devices
+            .iter()
+            .map(|dev| dev.dev_id)
+            .collect();
+        assert_eq!(devices, [1]);
+        assert_eq!(send("list"), status_json(&frontend.list()));
+
+        assert_eq!(send("remove x 0"), "error: invalid domid or devid");
+        assert_eq!(send("start"), "error: unknown command \"start\"");
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -3,141 +3,745 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use seccompiler::SeccompAction;
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex},
+    fs, io,
+    ops::RangeInclusive,
+    os::unix::io::AsRawFd,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, Builder, JoinHandle},
+    time::Duration,
 };
 
-use lazy_static::lazy_static;
-use vhost_user_frontend::{Generic, VhostUserConfig, VirtioDevice, VirtioDeviceType};
+use vhost_user_frontend::{Generic, VhostUserConfig, VirtioDeviceType};
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
-use xen_bindings::bindings::ioreq;
+use xen_bindings::bindings::{
+    ioreq, xenbus_state_XenbusStateClosed, xenbus_state_XenbusStateClosing,
+};
 
 use super::{
-    guest::XenGuest, interrupt::XenInterrupt, mmio::XenMmio, supported_devices::SUPPORTED_DEVICES,
+    backend::Backend,
+    epoll::XenEpoll,
+    guest::{join_thread, XenGuest},
+    interrupt::XenInterrupt,
+    layout::parse_banks,
+    mmio::{MmioSnapshot, XenMmio},
+    supported_devices::{config_queues, queues, SUPPORTED_DEVICES},
+    trace::MmioTrace,
     Error, Result, XsHandle, BACKEND_PATH,
 };
 
 pub const VIRTIO_MMIO_IO_SIZE: u64 = 0x200;
 
+// Pages Xen accounts to a domain on top of its RAM. The toolstack populates 4 magic pages for Arm
+// guests, the console, xenstore, memaccess and vuart pages (NR_MAGIC_PAGES in
+// tools/libs/guest/xg_dom_arm.c), which are counted in nr_pages but lie outside of the RAM banks.
+#[cfg(target_arch = "aarch64")]
+pub const DOM_EXTRA_PAGES: u64 = 4;
+
+// The special pages of x86 HVM guests instead, X86_HVM_NR_SPECIAL_PAGES in
+// tools/libs/guest/xg_dom_x86.c.
+#[cfg(target_arch = "x86_64")]
+pub const DOM_EXTRA_PAGES: u64 = 8;
+
+// Interrupts a device can be given, the SPIs on Arm (interrupts below 32 are private to the cpus)
+// and the IO-APIC pins of x86 HVM guests.
+#[cfg(target_arch = "aarch64")]
+const GUEST_IRQS: RangeInclusive<u32> = 32..=1019;
+
+#[cfg(target_arch = "x86_64")]
+const GUEST_IRQS: RangeInclusive<u32> = 0..=47;
+
+// Largest size of a split virtqueue.
+const VIRTQUEUE_MAX_SIZE: u32 = 32768;
+
+/// Options of the frontend, usually parsed from the command line.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
-struct DeviceArgs {
+pub struct DeviceArgs {
     /// Location of vhost-user Unix domain socket.
     #[clap(short, long)]
-    socket_path: String,
+    pub socket_path: String,
     /// Memory mapping, foreign or grant.
     #[clap(short, long)]
-    foreign_mapping: bool,
+    pub foreign_mapping: bool,
+    /// Offset the guest kernel adds to grant mapped addresses, XEN_GRANT_DMA_ADDR_OFF in Linux.
+    #[clap(long, default_value_t = 1 << 63)]
+    pub grant_addr_offset: u64,
+    /// Maximum virtqueue size offered to the guest, defaults to the backend's maximum.
+    #[clap(long)]
+    pub max_queue_size: Option<u32>,
+    /// Number of times to retry connecting to a backend whose socket isn't there yet.
+    #[clap(long, default_value_t = 10)]
+    pub connect_retries: u32,
+    /// Delay before the first connect retry in milliseconds, doubled for each retry.
+    #[clap(long, default_value_t = 100)]
+    pub connect_delay: u64,
+    /// Number of attempts to reconnect to a backend that went away.
+    #[clap(long, default_value_t = 5)]
+    pub reconnect_retries: u32,
+    /// Delay before the first reconnect attempt in milliseconds, doubled for each attempt.
+    #[clap(long, default_value_t = 500)]
+    pub reconnect_delay: u64,
+    /// Time to wait for the guest to move through the xenbus states, in milliseconds.
+    #[clap(long, default_value_t = 5000)]
+    pub xenbus_timeout: u64,
+    /// How the device interrupts are delivered to the guests.
+    #[clap(long, value_enum, default_value_t = InterruptMode::Irqfd)]
+    pub interrupt: InterruptMode,
+    /// Pins the thread handling a guest's ioreqs to the cpus, as "<domid>:<cpu>[,<cpu>..]".
+    #[clap(long)]
+    pub guest_cpus: Vec<String>,
+    /// Don't require the guest to accept VIRTIO_F_VERSION_1, for experimenting with legacy drivers.
+    #[clap(long)]
+    pub allow_legacy: bool,
+    /// Warn if handling an ioreq takes longer than this, in milliseconds.
+    #[clap(long)]
+    pub ioreq_timeout: Option<u64>,
+    /// Warn when a virtqueue has more buffers waiting for the backend than this.
+    #[clap(long)]
+    pub backlog_threshold: Option<u16>,
+    /// Guest RAM banks as "<base>:<size>[,<base>:<size>..]", for guests not laid out as Xen does.
+    #[clap(long)]
+    pub ram_banks: Option<String>,
+    /// Pages counted in a domain's size that aren't part of its RAM, like the magic pages.
+    #[clap(long, default_value_t = DOM_EXTRA_PAGES)]
+    pub dom_extra_pages: u64,
+    /// Xenstore directory holding the backend nodes of the devices.
+    #[clap(long, default_value = BACKEND_PATH)]
+    pub backend_path: String,
+    /// Path of a Unix socket to accept control commands on, like removing a device.
+    #[clap(long)]
+    pub control_socket: Option<String>,
+    /// File to append a trace of all the MMIO accesses of the guests to, as JSON lines.
+    #[clap(long)]
+    pub mmio_trace: Option<String>,
+    /// Check that the backends are reachable and exit.
+    #[clap(long)]
+    pub check: bool,
 }
 
+// How the device's interrupt is delivered to the guest.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum InterruptMode {
+    /// The backend's notifications raise the device's SPI via a Xen irqfd.
+    Irqfd,
+    /// Notify an event channel port the guest allocated, for platforms without irqfd.
+    EventChannel,
+}
+
+const COMPATIBLE_PREFIX: &str = "virtio,device";
+
+#[derive(Clone)]
 struct DeviceInfo {
     name: &'static str,
+    id: u32,
     compatible: String,
-    index: u32,
+    // Number of queues and their maximum size.
+    queues: (usize, u16),
 }
 
 impl DeviceInfo {
-    fn new(name: &'static str, id: u32) -> Self {
+    fn new(name: &'static str, id: u32, queues: (usize, u16)) -> Self {
         DeviceInfo {
             name,
-            compatible: format!("virtio,device{}", id),
-            index: 0,
+            id,
+            compatible: format!("{}{}", COMPATIBLE_PREFIX, id),
+            queues,
         }
     }
 
-    fn index(&mut self) -> String {
-        self.index += 1;
-        (self.index - 1).to_string()
+    // The devices supported out of the box, see src/supported_devices.rs.
+    fn builtin() -> Vec<Self> {
+        SUPPORTED_DEVICES
+            .iter()
+            .map(|(name, id)| {
+                let queues = queues(name).unwrap_or_else(|| {
+                    let (num, size) = VirtioDeviceType::from(*name).queue_num_and_size();
+                    (num, size as u16)
+                });
+
+                Self::new(name, *id, queues)
+            })
+            .collect()
     }
 }
 
-lazy_static! {
-    static ref DEVICES: Mutex<HashMap<String, DeviceInfo>> = {
-        let mut map = HashMap::new();
+// State shared by all the devices of a frontend.
+pub struct DeviceContext {
+    pub args: DeviceArgs,
+    pub ram_banks: Option<Vec<(u64, usize)>>,
+    trace: Option<MmioTrace>,
+    devices: Mutex<HashMap<String, DeviceInfo>>,
+}
+
+impl DeviceContext {
+    pub fn new(args: DeviceArgs) -> Result<Self> {
+        let trace = match &args.mmio_trace {
+            Some(path) => Some(MmioTrace::new(path)?),
+            None => None,
+        };
+
+        let ram_banks = match &args.ram_banks {
+            Some(banks) => Some(parse_banks(banks)?),
+            None => None,
+        };
+
+        let map = DeviceInfo::builtin()
+            .into_iter()
+            .map(|dev| (dev.compatible.clone(), dev))
+            .collect();
 
-        for entry in SUPPORTED_DEVICES.iter() {
-            let dev = DeviceInfo::new(entry.0, entry.1);
-            map.insert(dev.compatible.clone(), dev);
+        Ok(Self {
+            args,
+            ram_banks,
+            trace,
+            devices: Mutex::new(map),
+        })
+    }
+
+    // Adds a device type, or replaces the one with the same virtio device id.
+    pub fn register_device(&self, name: &'static str, id: u32, queues: (usize, u16)) {
+        let dev = DeviceInfo::new(name, id, queues);
+        self.devices
+            .lock()
+            .unwrap()
+            .insert(dev.compatible.clone(), dev);
+    }
+
+    // Returns the device type for the Xenstore "type" node and its backend socket. Toolstacks write
+    // either the "virtio,device<id>" compatible or the device's name there. The socket is named
+    // after the guest and the device, so a device finds the same backend across restarts of the
+    // frontend, whatever order the devices show up in.
+    fn device(&self, dev_type: &str, fe_domid: u16, dev_id: u32) -> Result<(DeviceInfo, String)> {
+        let devices = self.devices.lock().unwrap();
+
+        let dev = if dev_type.starts_with(COMPATIBLE_PREFIX) {
+            devices
+                .get(dev_type)
+                .ok_or_else(|| Error::XenDevNotSupported(dev_type.to_string()))?
+        } else {
+            devices
+                .values()
+                .find(|dev| dev.name == dev_type)
+                .ok_or_else(|| Error::XenDevUnknownName(dev_type.to_string()))?
         }
-        Mutex::new(map)
+        .clone();
+
+        let socket = socket_name(&self.args.socket_path, dev.name, fe_domid, dev_id);
+        Ok((dev, socket))
+    }
+}
+
+fn socket_name(socket_path: &str, name: &str, fe_domid: u16, dev_id: u32) -> String {
+    format!("{}{}.sock{}-{}", socket_path, name, fe_domid, dev_id)
+}
+
+// Returns the backend sockets present for the device, for any guest.
+fn backend_sockets(socket_path: &str, name: &str) -> Vec<String> {
+    let prefix = format!("{}{}.sock", socket_path, name);
+    let (dir, file) = match prefix.rsplit_once('/') {
+        Some((dir, file)) => (if dir.is_empty() { "/" } else { dir }, file),
+        None => (".", prefix.as_str()),
     };
-    static ref DEVICE_ARGS: DeviceArgs = DeviceArgs::parse();
+
+    let mut sockets: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter(|entry| entry.starts_with(file))
+            .map(|entry| Path::new(dir).join(entry).to_string_lossy().into_owned())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+
+    sockets.sort();
+    sockets
+}
+
+// The number of queues and their size can be changed per device with the "num-queues" and
+// "queue-size" nodes, for tuning, the defaults of the device type are used otherwise. These become
+// the limits offered to the guest and the backend is asked for them on connection, so the size can
+// go above the default but not above the largest split virtqueue a backend can handle.
+fn queue_overrides(xsh: &XsHandle, be: &str, queues: (usize, u16)) -> Result<(usize, u16)> {
+    let num = match xsh.read_str_opt(be, "num-queues")? {
+        Some(val) => val.parse::<u32>()?,
+        None => queues.0 as u32,
+    };
+    if num == 0 {
+        return Err(Error::InvalidQueueNum(num));
+    }
+
+    let size = match xsh.read_str_opt(be, "queue-size")? {
+        Some(val) => val.parse::<u32>()?,
+        None => queues.1 as u32,
+    };
+    if !size.is_power_of_two() || size > VIRTQUEUE_MAX_SIZE {
+        return Err(Error::InvalidQueueSize(size, VIRTQUEUE_MAX_SIZE));
+    }
+
+    Ok((num as usize, size as u16))
+}
+
+fn read_irq(xsh: &XsHandle, be: &str) -> Result<u32> {
+    let irq: u32 = xsh.read_int(be, "irq")?;
+    if !GUEST_IRQS.contains(&irq) {
+        return Err(Error::InvalidIrq(irq));
+    }
+
+    Ok(irq)
+}
+
+// The mapping can be picked per device with the "mapping" node, for example to give only some of
+// the backends access to all of the guest's memory. Returns if foreign mapping is used.
+fn read_mapping(xsh: &XsHandle, be: &str, foreign_mapping: bool) -> Result<bool> {
+    match xsh.read_str_opt(be, "mapping")?.as_deref() {
+        None => Ok(foreign_mapping),
+        Some("foreign") => Ok(true),
+        Some("grant") => Ok(false),
+        Some(val) => Err(Error::InvalidMapping(val.to_string())),
+    }
+}
+
+fn connect_backend(dev: &DeviceInfo, socket: &str) -> Result<Generic> {
+    let name = dev.name;
+    let (num_queues, queue_size) = dev.queues;
+
+    let vu_cfg = VhostUserConfig {
+        socket: socket.to_string(),
+        num_queues,
+        queue_size,
+    };
+
+    println!(
+        "Connecting to {} device backend over {} socket..",
+        name, vu_cfg.socket
+    );
+
+    Ok(Generic::new(
+        vu_cfg,
+        SeccompAction::Allow,
+        EventFd::new(EFD_NONBLOCK)?,
+        VirtioDeviceType::from(name),
+    )?)
+}
+
+// Devices can be added before their backend is started, connects are retried until the socket
+// shows up, with the delay doubled after each retry. Generic::new() waits for a missing socket for
+// much longer on its own, connect() is only called once the socket is there.
+fn connect_retry<T>(
+    socket: &str,
+    retries: u32,
+    delay: Duration,
+    mut connect: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut delay = delay;
+    let mut retry = 0;
+
+    loop {
+        let result = if Path::new(socket).exists() {
+            connect()
+        } else {
+            Err(Error::BackendSocketMissing(socket.to_string()))
+        };
+
+        match result {
+            Err(e) if retry < retries => {
+                retry += 1;
+                println!(
+                    "{}, retrying in {} ms ({}/{})..",
+                    e,
+                    delay.as_millis(),
+                    retry,
+                    retries
+                );
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn connect_backend_retry(dev: &DeviceInfo, socket: &str, args: &DeviceArgs) -> Result<Generic> {
+    let delay = Duration::from_millis(args.connect_delay);
+    connect_retry(socket, args.connect_retries, delay, || {
+        connect_backend(dev, socket)
+    })
+}
+
+// Connects to the first instance of every supported device's backend, without setting up anything
+// with Xen, and reports if it is reachable.
+pub fn check_backends(args: &DeviceArgs) -> bool {
+    check_sockets(args, |dev, socket| connect_backend(dev, socket).map(|_| ()))
+}
+
+fn check_sockets(args: &DeviceArgs, connect: impl Fn(&DeviceInfo, &str) -> Result<()>) -> bool {
+    let mut pass = true;
+
+    for dev in DeviceInfo::builtin() {
+        let name = dev.name;
+        let sockets = backend_sockets(&args.socket_path, name);
+
+        // Connecting to a missing socket is retried for a long time, fail early instead.
+        if sockets.is_empty() {
+            println!(
+                "{}: {}{}.sock*: FAILED (socket not found)",
+                name, args.socket_path, name
+            );
+            pass = false;
+            continue;
+        }
+
+        for socket in sockets {
+            match connect(&dev, &socket) {
+                Ok(()) => println!("{}: {}: OK", name, socket),
+                Err(e) => {
+                    println!("{}: {}: FAILED ({})", name, socket, e);
+                    pass = false;
+                }
+            }
+        }
+    }
+
+    pass
+}
+
+pub struct DeviceSnapshot {
+    pub dev_id: u32,
+    pub compatible: String,
+    pub addr: u64,
+    pub irq: u32,
+    pub mmio: MmioSnapshot,
+}
+
+// Where a device is found, in Xenstore and in the guest, and how its memory is mapped.
+pub struct DeviceSetup {
+    pub dev_id: u32,
+    pub be: String,
+    pub fe: String,
+    pub addr: u64,
+    pub irq: u32,
+    // The guest's event channel port for the interrupt, if it isn't delivered over irqfd.
+    pub port: Option<u32>,
+    pub foreign_mapping: bool,
 }
 
 pub struct XenDevice {
-    pub gdev: Mutex<Generic>,
+    pub gdev: Mutex<Box<dyn Backend>>,
     pub mmio: Mutex<XenMmio>,
     pub xsh: XsHandle,
+    pub be: String,
+    pub fe: String,
+    pub compatible: String,
+    pub name: &'static str,
+    pub socket: String,
     pub dev_id: u32,
     pub addr: u64,
-    pub irq: u8,
+    pub irq: u32,
     pub guest: Arc<XenGuest>,
+    info: DeviceInfo,
     interrupt: Mutex<Option<Arc<XenInterrupt>>>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+    reconnecting: AtomicBool,
+    exit: EventFd,
 }
 
 impl XenDevice {
     pub fn new(dev_id: u32, guest: Arc<XenGuest>) -> Result<Arc<Self>> {
         let mut xsh = XsHandle::new()?;
-        let be = xsh.connect_dom(dev_id, guest.fe_domid)?;
 
-        let dev_dir = format!("{}/{}/{}", BACKEND_PATH, guest.fe_domid, dev_id);
-        let compatible = xsh.read_str(&dev_dir, "type")?;
-        let addr = xsh.read_int(&be, "base")? as u64;
-        let irq = xsh.read_int(&be, "irq")? as u8;
+        // Devices of other types may be served by another frontend, the device is left alone
+        // unless its type is known.
+        let dir = format!(
+            "{}/{}/{}",
+            guest.ctx.args.backend_path, guest.fe_domid, dev_id
+        );
+        let dev_type = xsh.read_str(&dir, "type")?;
+        let (mut info, socket) = guest.ctx.device(&dev_type, guest.fe_domid, dev_id)?;
 
-        let mut devices = DEVICES.lock().unwrap();
-        let dev = devices
-            .get_mut(&compatible)
-            .ok_or(Error::XenDevNotSupported(compatible))?;
+        let timeout = Duration::from_millis(guest.ctx.args.xenbus_timeout);
+        let (be, fe) = xsh.connect_dom(
+            &guest.ctx.args.backend_path,
+            dev_id,
+            guest.fe_domid,
+            timeout,
+        )?;
 
-        let device_type = VirtioDeviceType::from(dev.name);
-        let (num, size) = device_type.queue_num_and_size();
+        let addr: u64 = xsh.read_int(&be, "base")?;
+        let irq = read_irq(&xsh, &be)?;
 
-        let vu_cfg = VhostUserConfig {
-            socket: DEVICE_ARGS.socket_path.to_owned() + dev.name + ".sock" + &dev.index(),
-            num_queues: num,
-            queue_size: size as u16,
+        // Without irqfd, the toolstack allocates an unbound event channel port for the device's
+        // interrupt in the guest.
+        let port = match guest.ctx.args.interrupt {
+            InterruptMode::Irqfd => None,
+            InterruptMode::EventChannel => Some(xsh.read_int(&be, "event-channel")?),
         };
 
-        println!(
-            "Connecting to {} device backend over {} socket..",
-            dev.name, vu_cfg.socket
-        );
+        let foreign_mapping = read_mapping(&xsh, &be, guest.ctx.args.foreign_mapping)?;
 
-        let gdev = Generic::new(
-            vu_cfg,
-            SeccompAction::Allow,
-            EventFd::new(EFD_NONBLOCK).unwrap(),
-            device_type,
-        )
-        .map_err(Error::VhostFrontendError)?;
+        info.queues = queue_overrides(&xsh, &be, info.queues)?;
+        let mut gdev = connect_backend_retry(&info, &socket, &guest.ctx.args)?;
 
-        let mmio = XenMmio::new(&gdev, guest.clone(), addr, DEVICE_ARGS.foreign_mapping)?;
+        // The number of queues set up with the backend must match the one the driver finds in the
+        // config space, connect again with as many queues unless the toolstack picked the number.
+        let num = config_queues(info.name, |offset, data| gdev.read_config(offset, data));
+        if let Some(num) = num {
+            if num != info.queues.0 && xsh.read_str_opt(&be, "num-queues")?.is_none() {
+                info.queues.0 = num;
+                drop(gdev);
+                gdev = connect_backend_retry(&info, &socket, &guest.ctx.args)?;
+            }
+        }
+
+        let setup = DeviceSetup {
+            dev_id,
+            be,
+            fe,
+            addr,
+            irq,
+            port,
+            foreign_mapping,
+        };
+        Self::with_backend(guest, xsh, setup, info, socket, Box::new(gdev))
+    }
+
+    // Serves the device over a backend that is connected already.
+    fn with_backend(
+        guest: Arc<XenGuest>,
+        xsh: XsHandle,
+        setup: DeviceSetup,
+        info: DeviceInfo,
+        socket: String,
+        gdev: Box<dyn Backend>,
+    ) -> Result<Arc<Self>> {
+        let mmio = XenMmio::new(
+            gdev.as_ref(),
+            guest.clone(),
+            setup.addr,
+            info.id,
+            setup.foreign_mapping,
+            guest.ctx.args.max_queue_size,
+            guest.size,
+        )?;
 
         let dev = Arc::new(Self {
             gdev: Mutex::new(gdev),
             mmio: Mutex::new(mmio),
             xsh,
-            dev_id,
-            addr,
-            irq,
+            be: setup.be,
+            fe: setup.fe,
+            compatible: info.compatible.clone(),
+            name: info.name,
+            socket,
+            dev_id: setup.dev_id,
+            addr: setup.addr,
+            irq: setup.irq,
             guest,
+            info,
             interrupt: Mutex::new(None),
+            handle: Mutex::new(None),
+            reconnecting: AtomicBool::new(false),
+            exit: EventFd::new(EFD_NONBLOCK).unwrap(),
         });
 
-        *dev.interrupt.lock().unwrap() = Some(XenInterrupt::new(dev.clone()));
+        let interrupt = XenInterrupt::new(dev.clone(), dev.info.queues.0, setup.port)?;
+        *dev.interrupt.lock().unwrap() = Some(interrupt);
+        dev.clone().setup_events()?;
         Ok(dev)
     }
 
+    // Creates a device of the type over a backend of the tests, as if it was found in Xenstore.
+    #[cfg(any(test, feature = "stubs"))]
+    pub fn with_test_backend(
+        guest: Arc<XenGuest>,
+        xsh: XsHandle,
+        setup: DeviceSetup,
+        dev_type: &str,
+        gdev: Box<dyn Backend>,
+    ) -> Result<Arc<Self>> {
+        let (info, socket) = guest.ctx.device(dev_type, guest.fe_domid, setup.dev_id)?;
+
+        Self::with_backend(guest, xsh, setup, info, socket, gdev)
+    }
+
+    // Reconnects to the backend from a thread of its own, the guest's thread goes on handling the
+    // ioreqs of the other devices meanwhile. The device is removed if that fails.
+    pub fn start_reconnect(self: &Arc<Self>) {
+        // The accesses failing while the reconnect is in progress don't start another one.
+        if self.reconnecting.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let dev = self.clone();
+        let handle = Builder::new()
+            .name(format!(
+                "reconnect {} - {}",
+                self.guest.fe_domid, self.dev_id
+            ))
+            .spawn(move || {
+                if let Err(e) = dev.reconnect() {
+                    println!(
+                        "Device {} / {}: {}, removing it",
+                        dev.guest.fe_domid, dev.dev_id, e
+                    );
+                    dev.guest.remove_device(dev.dev_id);
+                }
+
+                dev.reconnecting.store(false, Ordering::Release);
+            })
+            .unwrap();
+
+        self.guest.push_thread(handle);
+    }
+
+    // Connects to the backend again, after it went away, and brings it to the state the guest has
+    // configured so far. Gives up early if the device is removed meanwhile.
+    fn reconnect(&self) -> Result<()> {
+        let args = &self.guest.ctx.args;
+        let epoll = XenEpoll::new(vec![self.exit.as_raw_fd()])?;
+        let mut delay = args.reconnect_delay;
+
+        for attempt in 1..=args.reconnect_retries {
+            // Exit event received
+            if epoll
+                .wait_timeout(delay.min(i32::MAX as u64) as i32)?
+                .is_some()
+            {
+                break;
+            }
+            delay *= 2;
+
+            println!(
+                "Device {} / {}: Reconnecting to backend, attempt {}",
+                self.guest.fe_domid, self.dev_id, attempt
+            );
+
+            // A single connect per attempt, the attempts are the retries. It returns right away if
+            // the backend's socket isn't there (yet).
+            let connect = connect_retry(&self.socket, 0, Duration::ZERO, || {
+                connect_backend(&self.info, &self.socket)
+            });
+            let gdev = match connect {
+                Ok(gdev) => gdev,
+                Err(e) => {
+                    println!(
+                        "Device {} / {}: Failed to reconnect: {}",
+                        self.guest.fe_domid, self.dev_id, e
+                    );
+                    continue;
+                }
+            };
+
+            *self.gdev.lock().unwrap() = Box::new(gdev);
+            match self.mmio.lock().unwrap().restore(self) {
+                Ok(()) => return Ok(()),
+                Err(e) => println!(
+                    "Device {} / {}: Failed to restore backend state: {}",
+                    self.guest.fe_domid, self.dev_id, e
+                ),
+            }
+        }
+
+        Err(Error::VhostFrontendReconnectFailed)
+    }
+
+    pub fn set_state(&self, state: u32) -> Result<()> {
+        self.xsh.set_backend_state(&self.be, state)
+    }
+
+    // Follows the frontend through the xenbus closing handshake. The toolstack removes the
+    // backend directory once both ends are closed, which tears down the device.
+    fn xs_event(&self) -> Result<()> {
+        self.xsh.read_path()?;
+
+        match self.xsh.read_int::<u32>(&self.fe, "state")? {
+            xenbus_state_XenbusStateClosing => self.set_state(xenbus_state_XenbusStateClosing),
+            xenbus_state_XenbusStateClosed => self.set_state(xenbus_state_XenbusStateClosed),
+            _ => Ok(()),
+        }
+    }
+
+    fn setup_events(self: Arc<Self>) -> Result<()> {
+        let xfd = self.xsh.fileno()?;
+        let efd = self.exit.as_raw_fd();
+        let cfds = self.interrupt().call_fds();
+        let epoll = XenEpoll::new([vec![efd, xfd], cfds.clone()].concat())?;
+        let dev = self.clone();
+
+        *self.handle.lock().unwrap() = Some(
+            Builder::new()
+                .name(format!("device {} - {}", self.guest.fe_domid, self.dev_id))
+                .spawn(move || {
+                    while let Ok(fd) = epoll.wait() {
+                        // Exit event received
+                        if fd == efd {
+                            break;
+                        }
+
+                        if cfds.contains(&fd) {
+                            // A broken eventfd stays readable and would wake the thread forever.
+                            if let Err(e) = dev.interrupt().call_event(fd) {
+                                if e.kind() != io::ErrorKind::WouldBlock {
+                                    println!(
+                                        "Device {} / {}: Not polling call eventfd {} anymore: {}",
+                                        dev.guest.fe_domid, dev.dev_id, fd, e
+                                    );
+                                    epoll.remove(fd).ok();
+                                }
+                            }
+                            dev.check_backlog();
+                        } else {
+                            dev.xs_event().ok();
+                        }
+                    }
+                })
+                .unwrap(),
+        );
+
+        Ok(())
+    }
+
+    // Called as the backend uses buffers, to tell when it caught up again.
+    pub fn check_backlog(&self) {
+        // Called for every notification of the backend, don't take the lock for nothing.
+        if self.guest.ctx.args.backlog_threshold.is_none() {
+            return;
+        }
+
+        self.mmio.lock().unwrap().check_backlog(self);
+    }
+
+    pub fn snapshot(&self) -> DeviceSnapshot {
+        let mut mmio = self.mmio.lock().unwrap().snapshot();
+        for (queue, calls) in mmio.queues.iter_mut().zip(self.interrupt().counts()) {
+            queue.calls = calls;
+        }
+
+        DeviceSnapshot {
+            dev_id: self.dev_id,
+            compatible: self.compatible.clone(),
+            addr: self.addr,
+            irq: self.irq,
+            mmio,
+        }
+    }
+
     pub fn interrupt(&self) -> Arc<XenInterrupt> {
         // We use interrupt.take() here to drop the reference to Arc<XenInterrupt>, as the same
         // isn't required anymore.
         self.interrupt.lock().unwrap().as_ref().unwrap().clone()
     }
 
+    // Xen keeps the ranges of an ioreq server in a rangeset, which merges adjacent ranges by
+    // itself. Registering each device's range on its own, as soon as the device is added, costs a
+    // hypercall per device and keeps the range registered exactly while the device exists.
     pub fn setup_ioreq(&self) -> Result<()> {
         self.guest
             .xdm
@@ -155,17 +759,322 @@ impl XenDevice {
     }
 
     pub fn io_event(&self, ioreq: &mut ioreq) -> Result<()> {
-        self.mmio.lock().unwrap().io_event(ioreq, self)
+        let result = self.mmio.lock().unwrap().io_event(ioreq, self);
+
+        if let Some(trace) = &self.guest.ctx.trace {
+            let offset = ioreq.addr - self.addr;
+            trace.record(self.guest.fe_domid, self.dev_id, offset, ioreq, &result);
+        }
+
+        result
     }
 
     pub fn exit(&self) {
-        if let Some(interrupt) = self.interrupt.lock().unwrap().take() {
+        self.exit.write(1).unwrap();
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            join_thread(handle);
+        }
+
+        let interrupt = self.interrupt.lock().unwrap().take();
+        if let Some(interrupt) = interrupt {
             interrupt.exit();
         }
 
-        self.gdev.lock().unwrap().reset();
-        self.gdev.lock().unwrap().shutdown();
+        let mut gdev = self.gdev.lock().unwrap();
+        gdev.reset();
+        gdev.shutdown();
+        drop(gdev);
+
+        self.mmio.lock().unwrap().unmap_regions(self);
 
         self.destroy_ioreq().ok();
+        self.set_state(xenbus_state_XenbusStateClosed).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stubs::{stub_args, Calls, StubBackend, StubGuest, StubNodes, StubStore};
+    use std::{os::unix::net::UnixListener, time::Instant};
+    use virtio_bindings::virtio_mmio::VIRTIO_MMIO_MAGIC_VALUE;
+
+    // A handle to a store with the nodes in the "be" directory.
+    fn stub_handle(nodes: &[(&str, &str)]) -> XsHandle {
+        let store = StubNodes::default();
+        for (node, val) in nodes {
+            let path = format!("be/{}", node);
+            store.lock().unwrap().insert(path, val.to_string());
+        }
+
+        XsHandle::with_store(Box::new(StubStore::new(store)))
+    }
+
+    fn overrides(nodes: &[(&str, &str)], queues: (usize, u16)) -> Result<(usize, u16)> {
+        queue_overrides(&stub_handle(nodes), "be", queues)
+    }
+
+    #[test]
+    fn test_queue_overrides() {
+        assert_eq!(overrides(&[], (2, 1024)).unwrap(), (2, 1024));
+        assert_eq!(
+            overrides(&[("num-queues", "4")], (2, 1024)).unwrap(),
+            (4, 1024)
+        );
+        assert_eq!(
+            overrides(&[("queue-size", "256")], (2, 1024)).unwrap(),
+            (2, 256)
+        );
+
+        assert!(matches!(
+            overrides(&[("num-queues", "0")], (2, 1024)),
+            Err(Error::InvalidQueueNum(0))
+        ));
+        assert!(matches!(
+            overrides(&[("queue-size", "100")], (2, 1024)),
+            Err(Error::InvalidQueueSize(100, VIRTQUEUE_MAX_SIZE))
+        ));
+    }
+
+    // The default size of the device type isn't a limit, the size of a split virtqueue is.
+    #[test]
+    fn test_queue_size_over_backend_max() {
+        assert_eq!(
+            overrides(&[("queue-size", "4096")], (2, 1024)).unwrap(),
+            (2, 4096)
+        );
+        assert_eq!(
+            overrides(&[("queue-size", "32768")], (2, 1024)).unwrap(),
+            (2, 32768)
+        );
+        assert!(matches!(
+            overrides(&[("queue-size", "65536")], (2, 1024)),
+            Err(Error::InvalidQueueSize(65536, VIRTQUEUE_MAX_SIZE))
+        ));
+    }
+
+    #[test]
+    fn test_read_irq() {
+        let irq = |val: &str| read_irq(&stub_handle(&[("irq", val)]), "be");
+
+        let (first, last) = (*GUEST_IRQS.start(), *GUEST_IRQS.end());
+        assert_eq!(irq(&first.to_string()).unwrap(), first);
+        assert_eq!(irq(&last.to_string()).unwrap(), last);
+        assert!(matches!(irq(&(last + 1).to_string()), Err(Error::InvalidIrq(i)) if i == last + 1));
+        assert!(matches!(irq("spi"), Err(Error::ParseFailure(_))));
+
+        // The SPIs of Arm go up to 1019, past what fits in a byte.
+        #[cfg(target_arch = "aarch64")]
+        {
+            assert_eq!(irq("300").unwrap(), 300);
+            assert_eq!(irq("0x3fb").unwrap(), 1019);
+            assert!(matches!(irq("31"), Err(Error::InvalidIrq(31))));
+        }
+    }
+
+    #[test]
+    fn test_read_mapping() {
+        let mapping = |nodes: &[(&str, &str)], foreign_mapping| {
+            read_mapping(&stub_handle(nodes), "be", foreign_mapping)
+        };
+
+        // The default of the frontend, unless the device picks the other one.
+        for foreign_mapping in [false, true] {
+            assert_eq!(mapping(&[], foreign_mapping).unwrap(), foreign_mapping);
+            assert!(mapping(&[("mapping", "foreign")], foreign_mapping).unwrap());
+            assert!(!mapping(&[("mapping", "grant")], foreign_mapping).unwrap());
+        }
+
+        assert!(matches!(
+            mapping(&[("mapping", "Grant")], false),
+            Err(Error::InvalidMapping(val)) if val == "Grant"
+        ));
+    }
+
+    fn temp_socket(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "xen-vhost-frontend-{}-{}.sock",
+            std::process::id(),
+            name
+        ));
+        fs::remove_file(&path).ok();
+        path.to_str().unwrap().to_string()
+    }
+
+    // The backend is only connected to once its socket shows up.
+    #[test]
+    fn test_connect_retry_late_socket() {
+        let socket = temp_socket("late");
+        let path = socket.clone();
+        let listener = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            UnixListener::bind(path).unwrap()
+        });
+
+        let mut connects = 0;
+        let result = connect_retry(&socket, 10, Duration::from_millis(10), || {
+            connects += 1;
+            Ok(())
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(connects, 1);
+        drop(listener.join().unwrap());
+        fs::remove_file(&socket).unwrap();
+    }
+
+    #[test]
+    fn test_connect_retry_exhausted() {
+        let socket = temp_socket("missing");
+        let mut connects = 0;
+        let result = connect_retry(&socket, 2, Duration::from_millis(1), || {
+            connects += 1;
+            Ok(())
+        });
+        assert!(matches!(result, Err(Error::BackendSocketMissing(path)) if path == socket));
+        assert_eq!(connects, 0);
+
+        // The error of the last attempt is returned once the socket is there.
+        let _listener = UnixListener::bind(&socket).unwrap();
+        let mut connects = 0;
+        let result: Result<()> = connect_retry(&socket, 2, Duration::from_millis(1), || {
+            connects += 1;
+            Err(Error::InvalidQueueNum(connects))
+        });
+        assert!(matches!(result, Err(Error::InvalidQueueNum(3))));
+        fs::remove_file(&socket).unwrap();
+    }
+
+    // A backend that doesn't come back fails each reconnect attempt right away, the device is
+    // removed once they run out.
+    #[test]
+    fn test_reconnect_failed() {
+        let mut args = stub_args(&["--reconnect-retries", "3", "--reconnect-delay", "10"]);
+        args.socket_path = temp_socket("reconnect");
+        let stub = StubGuest::new(args);
+        let backend = StubBackend::new(vec![1024], 0, Arc::new(Calls::default()));
+        let dev = stub.add_device(0, "i2c", backend, true).unwrap();
+
+        let start = Instant::now();
+        dev.start_reconnect();
+        while !stub.guest.is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Every supported device needs a backend socket, each of which is connected to.
+    #[test]
+    fn test_check_backends() {
+        let dir =
+            std::env::temp_dir().join(format!("xen-vhost-frontend-{}-check", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let mut args = stub_args(&[]);
+        args.socket_path = format!("{}/", dir.to_str().unwrap());
+
+        let sockets: Vec<UnixListener> = DeviceInfo::builtin()
+            .iter()
+            .map(|dev| UnixListener::bind(socket_name(&args.socket_path, dev.name, 1, 0)).unwrap())
+            .collect();
+        let connects = Mutex::new(Vec::new());
+        assert!(check_sockets(&args, |dev, socket| {
+            connects.lock().unwrap().push(socket.to_string());
+            assert!(socket.starts_with(&format!("{}{}.sock", args.socket_path, dev.name)));
+            Ok(())
+        }));
+        assert_eq!(connects.lock().unwrap().len(), sockets.len());
+
+        // An unreachable backend fails the check, as does a missing socket.
+        let i2c = socket_name(&args.socket_path, "i2c", 1, 0);
+        assert!(!check_sockets(&args, |_, socket| {
+            if socket == i2c {
+                Err(io::Error::from(io::ErrorKind::ConnectionRefused).into())
+            } else {
+                Ok(())
+            }
+        }));
+
+        fs::remove_file(&i2c).unwrap();
+        assert!(!check_sockets(&args, |_, _| Ok(())));
+
+        drop(sockets);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Devices of types served by another frontend fail to be added, without affecting the other
+    // devices of the guest.
+    #[test]
+    fn test_unknown_device_skipped() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let backend = || StubBackend::new(vec![1024], 0, Arc::new(Calls::default()));
+        stub.add_device(0, "i2c", backend(), true).unwrap();
+
+        assert!(matches!(
+            stub.add_device(1, "virtio,device999", backend(), true),
+            Err(Error::XenDevNotSupported(t)) if t == "virtio,device999"
+        ));
+        assert!(matches!(
+            stub.add_device(2, "frobnicator", backend(), true),
+            Err(Error::XenDevUnknownName(t)) if t == "frobnicator"
+        ));
+
+        let ids: Vec<u32> = stub
+            .guest
+            .snapshot()
+            .devices
+            .iter()
+            .map(|dev| dev.dev_id)
+            .collect();
+        assert_eq!(ids, [0]);
+
+        let dev = stub.add_device(3, "i2c", backend(), true).unwrap();
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_MAGIC_VALUE as u64), 0x74726976);
+    }
+
+    // Toolstacks write either the compatible or the name of the device type.
+    #[test]
+    fn test_device_lookup() {
+        let ctx = DeviceContext::new(stub_args(&[])).unwrap();
+
+        for dev_type in ["virtio,device22", "i2c"] {
+            let (info, _) = ctx.device(dev_type, 1, 0).unwrap();
+            assert_eq!((info.name, info.id), ("i2c", 22));
+            assert_eq!(info.compatible, "virtio,device22");
+        }
+
+        assert!(matches!(
+            ctx.device("virtio,device99", 1, 0),
+            Err(Error::XenDevNotSupported(t)) if t == "virtio,device99"
+        ));
+        // Names aren't compatibles, nor the other way around.
+        assert!(matches!(
+            ctx.device("device22", 1, 0),
+            Err(Error::XenDevUnknownName(t)) if t == "device22"
+        ));
+        assert!(matches!(
+            ctx.device("virtio,devicei2c", 1, 0),
+            Err(Error::XenDevNotSupported(_))
+        ));
+
+        // Registered device types are found both ways too.
+        ctx.register_device("rng", 4, (1, 256));
+        assert_eq!(ctx.device("rng", 1, 0).unwrap().0.id, 4);
+        assert_eq!(ctx.device("virtio,device4", 1, 0).unwrap().0.name, "rng");
+    }
+
+    // Each guest's device has a backend socket of its own, named the same every time.
+    #[test]
+    fn test_socket_name() {
+        let ctx = DeviceContext::new(stub_args(&[])).unwrap();
+        let socket = |fe_domid, dev_id| ctx.device("i2c", fe_domid, dev_id).unwrap().1;
+
+        assert_eq!(socket(1, 0), "/tmp/i2c.sock1-0");
+        assert_eq!(socket(2, 0), "/tmp/i2c.sock2-0");
+        assert_eq!(socket(1, 1), "/tmp/i2c.sock1-1");
+        assert_eq!(socket(1, 0), "/tmp/i2c.sock1-0");
+
+        let (_, socket) = ctx.device("virtio,device29", 12, 3).unwrap();
+        assert_eq!(socket, "/tmp/gpio.sock12-3");
     }
 }
@@ -11,28 +11,48 @@ pub struct XenEpoll(Epoll);
 
 impl XenEpoll {
     pub fn new(fds: Vec<i32>) -> Result<Self> {
-        let epoll = Epoll::new().map_err(Error::EpollCreateFd)?;
+        let epoll = Self(Epoll::new().map_err(Error::EpollCreateFd)?);
 
         for fd in fds {
-            epoll
-                .ctl(
-                    ControlOperation::Add,
-                    fd,
-                    EpollEvent::new(EventSet::IN, fd as u64),
-                )
-                .map_err(Error::RegisterExitEvent)?;
+            epoll.add(fd)?;
         }
 
-        Ok(Self(epoll))
+        Ok(epoll)
+    }
+
+    pub fn add(&self, fd: i32) -> Result<()> {
+        self.0
+            .ctl(
+                ControlOperation::Add,
+                fd,
+                EpollEvent::new(EventSet::IN, fd as u64),
+            )
+            .map_err(Error::RegisterExitEvent)
+    }
+
+    pub fn remove(&self, fd: i32) -> Result<()> {
+        self.0
+            .ctl(ControlOperation::Delete, fd, EpollEvent::default())
+            .map_err(Error::UnregisterEvent)
     }
 
     pub fn wait(&self) -> Result<i32> {
+        loop {
+            if let Some(fd) = self.wait_timeout(-1)? {
+                return Ok(fd);
+            }
+        }
+    }
+
+    // Returns None if no event occurred within the timeout, in milliseconds.
+    pub fn wait_timeout(&self, timeout: i32) -> Result<Option<i32>> {
         let mut events = vec![EpollEvent::new(EventSet::empty(), 0); 1];
 
         loop {
-            match self.0.wait(-1, &mut events[..]) {
+            match self.0.wait(timeout, &mut events[..]) {
+                Ok(0) => return Ok(None),
                 Ok(_) => {
-                    return Ok(events[0].fd());
+                    return Ok(Some(events[0].fd()));
                 }
 
                 Err(e) => {
@@ -51,3 +71,66 @@ impl XenEpoll {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        os::unix::io::AsRawFd,
+        time::{Duration, Instant},
+    };
+    use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+    #[test]
+    fn test_add() {
+        let first = EventFd::new(EFD_NONBLOCK).unwrap();
+        let second = EventFd::new(EFD_NONBLOCK).unwrap();
+        let epoll = XenEpoll::new(vec![first.as_raw_fd()]).unwrap();
+
+        epoll.add(second.as_raw_fd()).unwrap();
+        second.write(1).unwrap();
+        assert_eq!(epoll.wait().unwrap(), second.as_raw_fd());
+
+        // Adding the same fd twice is refused by epoll.
+        assert!(matches!(
+            epoll.add(second.as_raw_fd()),
+            Err(Error::RegisterExitEvent(_))
+        ));
+    }
+
+    #[test]
+    fn test_remove() {
+        let first = EventFd::new(EFD_NONBLOCK).unwrap();
+        let second = EventFd::new(EFD_NONBLOCK).unwrap();
+        let epoll = XenEpoll::new(vec![first.as_raw_fd(), second.as_raw_fd()]).unwrap();
+
+        epoll.remove(second.as_raw_fd()).unwrap();
+        second.write(1).unwrap();
+        assert_eq!(epoll.wait_timeout(10).unwrap(), None);
+
+        // The other fds still wake it up.
+        first.write(1).unwrap();
+        assert_eq!(epoll.wait_timeout(10).unwrap(), Some(first.as_raw_fd()));
+
+        assert!(matches!(
+            epoll.remove(second.as_raw_fd()),
+            Err(Error::UnregisterEvent(_))
+        ));
+    }
+
+    // Times out with no event, after about as long as asked for.
+    #[test]
+    fn test_wait_timeout() {
+        let efd = EventFd::new(EFD_NONBLOCK).unwrap();
+        let epoll = XenEpoll::new(vec![efd.as_raw_fd()]).unwrap();
+
+        let start = Instant::now();
+        assert_eq!(epoll.wait_timeout(50).unwrap(), None);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        assert_eq!(epoll.wait_timeout(0).unwrap(), None);
+
+        efd.write(1).unwrap();
+        assert_eq!(epoll.wait_timeout(50).unwrap(), Some(efd.as_raw_fd()));
+    }
+}
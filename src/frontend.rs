@@ -5,10 +5,17 @@
 
 use std::{
     sync::{Arc, Mutex},
-    thread::{self, JoinHandle},
+    thread::{self, Builder, JoinHandle},
 };
 
-use super::{device::XenDevice, guest::XenGuest, Result};
+use super::{
+    control::setup_control_socket,
+    device::{DeviceArgs, DeviceContext, XenDevice},
+    guest::{join_thread, GuestSnapshot, XenGuest},
+    status::setup_status_dump,
+    xs::{XsEvent, XsHandle, RELEASE_DOMAIN_PATH},
+    Error, Result,
+};
 
 #[derive(Default)]
 struct FrontendGuests(Vec<Arc<XenGuest>>);
@@ -21,11 +28,16 @@ impl FrontendGuests {
             .cloned()
     }
 
-    fn add_guest(&mut self, fe_domid: u16) -> Result<Arc<XenGuest>> {
-        let guest = XenGuest::new(fe_domid)?;
-        self.0.push(guest.clone());
+    // Adds a guest set up without holding the guests lock. Another device of the guest may have
+    // added it meanwhile, the new one is dropped then.
+    fn add_guest(&mut self, guest: Arc<XenGuest>) -> Arc<XenGuest> {
+        if let Some(found) = self.find_guest(guest.fe_domid) {
+            guest.exit();
+            return found;
+        }
 
-        Ok(guest)
+        self.0.push(guest.clone());
+        guest
     }
 
     fn remove_guest(&mut self, fe_domid: u16) {
@@ -34,64 +46,416 @@ impl FrontendGuests {
             .exit()
     }
 
-    fn add_device(&mut self, fe_domid: u16, dev_id: u32) -> Result<Arc<XenDevice>> {
-        let guest = match self.find_guest(fe_domid) {
-            Some(guest) => guest,
-            None => self.add_guest(fe_domid)?,
-        };
+    // Don't keep a guest around for its only device that failed.
+    fn remove_if_empty(&mut self, guest: &Arc<XenGuest>) {
+        if guest.is_empty() && self.0.iter().any(|g| Arc::ptr_eq(g, guest)) {
+            self.remove_guest(guest.fe_domid);
+        }
+    }
+
+    // Adds a device, set up without holding the guests lock, to its guest. Anything may have
+    // happened to the guest or the device in the meantime.
+    fn add_device(&mut self, dev: Arc<XenDevice>) -> Result<()> {
+        let guest = dev.guest.clone();
+
+        // The guest was removed, along with its last device or as it went away.
+        if !self.0.iter().any(|g| Arc::ptr_eq(g, &guest)) {
+            dev.exit();
+            return Err(Error::GuestRemoved(guest.fe_domid));
+        }
+
+        // The device was removed before it could be added, its removal event found nothing to
+        // remove. Removals are handled with the guests lock held, checking here is enough.
+        if dev.xsh.read_str_opt(&dev.be, "state")?.is_none() {
+            dev.exit();
+            self.remove_if_empty(&guest);
+            return Err(Error::DeviceRemoved(guest.fe_domid, dev.dev_id));
+        }
 
-        guest.add_device(dev_id)
+        let result = guest.add_device(dev);
+        if result.is_err() {
+            self.remove_if_empty(&guest);
+        }
+
+        result
     }
 
     fn remove_device(&mut self, fe_domid: u16, dev_id: u32) {
-        let guest = self.find_guest(fe_domid).unwrap();
+        // The guest may already be gone, if it was removed as part of a domain release.
+        let guest = match self.find_guest(fe_domid) {
+            Some(guest) => guest,
+            None => return,
+        };
+
         guest.remove_device(dev_id);
 
         if guest.is_empty() {
             self.remove_guest(fe_domid);
         }
     }
+
+    fn snapshot(&self) -> Vec<GuestSnapshot> {
+        self.0.iter().map(|guest| guest.snapshot()).collect()
+    }
+
+    fn remove_dead_guests(&mut self) {
+        let dead: Vec<u16> = self
+            .0
+            .iter()
+            .filter(|guest| !guest.is_alive())
+            .map(|guest| guest.fe_domid)
+            .collect();
+
+        for fe_domid in dead {
+            println!("Guest {} is gone, removing it", fe_domid);
+            self.remove_guest(fe_domid);
+        }
+    }
 }
 
+/// Serves the virtio devices of all the guests that have their backends in this domain.
 pub struct XenFrontend {
+    ctx: Arc<DeviceContext>,
     guests: Mutex<FrontendGuests>,
     threads: Mutex<Vec<JoinHandle<()>>>,
 }
 
 impl XenFrontend {
-    pub fn new() -> Result<Arc<Self>> {
+    pub fn new(args: DeviceArgs) -> Result<Arc<Self>> {
+        if args.backend_path.is_empty() || args.backend_path.ends_with('/') {
+            return Err(Error::InvalidBackendPath(args.backend_path));
+        }
+
         Ok(Arc::new(Self {
+            ctx: Arc::new(DeviceContext::new(args)?),
             guests: Mutex::new(FrontendGuests::default()),
             threads: Mutex::new(Vec::new()),
         }))
     }
 
+    /// Adds support for a device type, on top of the ones in src/supported_devices.rs, with
+    /// `queues` as the number of virtqueues and their maximum size. Devices of the type are
+    /// matched by the "virtio,device<id>" compatible, and their backends are looked up as
+    /// "<name>.sock<domid>-<dev_id>". Meant to be called before `run()`.
+    pub fn register_device(&self, name: &'static str, id: u32, queues: (usize, u16)) {
+        self.ctx.register_device(name, id, queues);
+    }
+
+    /// Watches Xenstore for devices being added or removed and serves them, never returns unless
+    /// Xenstore fails. The state of the frontend is printed as JSON on SIGUSR1, which is blocked
+    /// for the calling thread and the threads it starts. Control commands are accepted on the
+    /// control socket, if one is passed.
+    pub fn run(self: Arc<Self>) -> Result<()> {
+        setup_status_dump(self.clone())?;
+        if let Some(path) = &self.ctx.args.control_socket {
+            setup_control_socket(self.clone(), path)?;
+        }
+
+        let mut xsh = XsHandle::new_with_epoll()?;
+        let backend_path = &self.ctx.args.backend_path;
+        xsh.create_watch(backend_path.clone(), backend_path.clone())?;
+        xsh.create_watch(
+            RELEASE_DOMAIN_PATH.to_string(),
+            RELEASE_DOMAIN_PATH.to_string(),
+        )?;
+
+        loop {
+            let (fe_domid, dev_id, new) = match xsh.wait_for_event(backend_path)? {
+                XsEvent::Device(fe_domid, dev_id, new) => (fe_domid, dev_id, new),
+                XsEvent::ReleaseDomain => {
+                    // Guests that were destroyed or rebooted (which gets them a new domid) won't
+                    // remove their devices themselves, reclaim their resources here.
+                    self.remove_dead_guests();
+                    continue;
+                }
+            };
+
+            // Handle events in individual threads, in order to support multiple
+            // devices / guests.
+            let f = self.clone();
+            self.push(
+                Builder::new()
+                    .name(format!("frontend {} - {}", fe_domid, dev_id))
+                    .spawn(move || {
+                        if new {
+                            // Failing devices, like the ones of types served by another frontend,
+                            // are skipped without affecting the rest.
+                            if let Err(e) = f.add_device(fe_domid, dev_id) {
+                                println!("Skipping device {} / {}: {}", fe_domid, dev_id, e);
+                            }
+                        } else {
+                            f.remove_device(fe_domid, dev_id);
+                        }
+                    })
+                    .unwrap(),
+            );
+        }
+    }
+
     pub fn add_device(&self, fe_domid: u16, dev_id: u32) -> Result<()> {
         // TODO: We need some sign that all devid subdirs are already written to
         // Xenstore, so it's time to parse them. This delay although works, doesn't
         // guarantee that.
         thread::sleep(std::time::Duration::from_millis(400));
 
-        let dev = self.guests.lock().unwrap().add_device(fe_domid, dev_id)?;
+        let guest = self.get_guest(fe_domid, |fe_domid| {
+            XenGuest::new(fe_domid, self.ctx.clone())
+        })?;
 
-        // Device is ready to accept ioreq() updates now, lets enable that.
-        dev.setup_ioreq()?;
-        Ok(())
+        // Setting up the device waits for the guest and the backend, which may take long. The
+        // guests lock isn't held meanwhile, so the watch loop and the other devices can go on.
+        let dev = match XenDevice::new(dev_id, guest.clone()) {
+            Ok(dev) => dev,
+            Err(e) => {
+                self.guests.lock().unwrap().remove_if_empty(&guest);
+                return Err(e);
+            }
+        };
+
+        self.guests.lock().unwrap().add_device(dev)
+    }
+
+    // Setting up a guest waits for the size of its domain to settle, which takes a while. The
+    // guests lock isn't held meanwhile, like for the devices.
+    fn get_guest(
+        &self,
+        fe_domid: u16,
+        new_guest: impl FnOnce(u16) -> Result<Arc<XenGuest>>,
+    ) -> Result<Arc<XenGuest>> {
+        if let Some(guest) = self.guests.lock().unwrap().find_guest(fe_domid) {
+            return Ok(guest);
+        }
+
+        let guest = new_guest(fe_domid)?;
+        Ok(self.guests.lock().unwrap().add_guest(guest))
+    }
+
+    // Serves a guest that was set up already, like the ones over the stubs.
+    #[cfg(any(test, feature = "stubs"))]
+    pub fn add_guest(&self, guest: Arc<XenGuest>) -> Arc<XenGuest> {
+        self.guests.lock().unwrap().add_guest(guest)
     }
 
     pub fn remove_device(&self, fe_domid: u16, dev_id: u32) {
         self.guests.lock().unwrap().remove_device(fe_domid, dev_id);
     }
 
+    // Returns the guests and devices currently being served.
+    pub fn list(&self) -> Vec<GuestSnapshot> {
+        self.guests.lock().unwrap().snapshot()
+    }
+
+    pub fn remove_dead_guests(&self) {
+        self.guests.lock().unwrap().remove_dead_guests();
+    }
+
     pub fn push(&self, handle: JoinHandle<()>) {
-        self.threads.lock().unwrap().push(handle)
+        let mut threads = self.threads.lock().unwrap();
+
+        // Reap the threads of earlier events that are done already, there is one for each device
+        // added or removed and the list would keep growing otherwise.
+        let (done, mut running): (Vec<_>, Vec<_>) =
+            threads.drain(..).partition(|handle| handle.is_finished());
+        running.push(handle);
+        *threads = running;
+        drop(threads);
+
+        for handle in done {
+            join_thread(handle);
+        }
     }
 }
 
 impl Drop for XenFrontend {
     fn drop(&mut self) {
         while let Some(handle) = self.threads.lock().unwrap().pop() {
-            handle.join().unwrap();
+            join_thread(handle);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::VIRTIO_MMIO_IO_SIZE;
+    use crate::stubs::{stub_args, Calls, StubBackend, StubGuest, STUB_ADDR, STUB_DOMID, STUB_IRQ};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use virtio_bindings::{
+        virtio_config::VIRTIO_CONFIG_S_ACKNOWLEDGE,
+        virtio_mmio::{VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_STATUS},
+    };
+    use xen_bindings::bindings::IOREQ_READ;
+
+    fn guest_domids(frontend: &XenFrontend) -> Vec<u16> {
+        frontend.list().iter().map(|guest| guest.fe_domid).collect()
+    }
+
+    // The guests lock isn't held while the guest is set up.
+    #[test]
+    fn test_get_guest() {
+        let frontend = XenFrontend::new(stub_args(&[])).unwrap();
+        let stub = StubGuest::new(stub_args(&[]));
+
+        let guest = frontend
+            .get_guest(STUB_DOMID, |_| {
+                assert!(frontend.guests.try_lock().is_ok());
+                Ok(stub.guest.clone())
+            })
+            .unwrap();
+        assert!(Arc::ptr_eq(&guest, &stub.guest));
+        assert_eq!(guest_domids(&frontend), [STUB_DOMID]);
+
+        // Found, not set up again.
+        let found = frontend
+            .get_guest(STUB_DOMID, |_| panic!("guest set up again"))
+            .unwrap();
+        assert!(Arc::ptr_eq(&found, &stub.guest));
+    }
+
+    // The domain went away before the guest could be set up.
+    #[test]
+    fn test_get_guest_vanished() {
+        let frontend = XenFrontend::new(stub_args(&[])).unwrap();
+
+        let result =
+            frontend.get_guest(5, |fe_domid| Err(Error::InvalidDomainInfo(0, fe_domid, 0)));
+        assert!(matches!(result, Err(Error::InvalidDomainInfo(0, 5, 0))));
+        assert!(guest_domids(&frontend).is_empty());
+    }
+
+    // Another device of the guest added it first, its guest is used instead.
+    #[test]
+    fn test_get_guest_raced() {
+        let frontend = XenFrontend::new(stub_args(&[])).unwrap();
+        let first = StubGuest::new(stub_args(&[]));
+        let second = StubGuest::new(stub_args(&[]));
+
+        let guest = frontend
+            .get_guest(STUB_DOMID, |_| {
+                frontend
+                    .guests
+                    .lock()
+                    .unwrap()
+                    .add_guest(first.guest.clone());
+                Ok(second.guest.clone())
+            })
+            .unwrap();
+
+        assert!(Arc::ptr_eq(&guest, &first.guest));
+        assert_eq!(guest_domids(&frontend), [STUB_DOMID]);
+    }
+
+    // The threads of the events handled already don't pile up.
+    #[test]
+    fn test_push_reaps_threads() {
+        let frontend = XenFrontend::new(stub_args(&[])).unwrap();
+
+        for _ in 0..100 {
+            let handle = thread::spawn(|| {});
+            while !handle.is_finished() {
+                thread::yield_now();
+            }
+
+            frontend.push(handle);
+            assert_eq!(frontend.threads.lock().unwrap().len(), 1);
+        }
+    }
+
+    // The guests being served, with their devices as the guest set them up.
+    #[test]
+    fn test_list() {
+        let frontend = XenFrontend::new(stub_args(&[])).unwrap();
+        let stub = StubGuest::new(stub_args(&[]));
+        let backend = || StubBackend::new(vec![256, 256], 0, Arc::new(Calls::default()));
+        assert!(frontend.list().is_empty());
+
+        stub.add_device(0, "i2c", backend(), true).unwrap();
+        let gpio = stub.add_device(1, "gpio", backend(), true).unwrap();
+        stub.write(
+            &gpio,
+            VIRTIO_MMIO_STATUS as u64,
+            VIRTIO_CONFIG_S_ACKNOWLEDGE,
+        );
+        frontend
+            .guests
+            .lock()
+            .unwrap()
+            .add_guest(stub.guest.clone());
+
+        let guests = frontend.list();
+        assert_eq!(guests.len(), 1);
+        assert_eq!(guests[0].fe_domid, STUB_DOMID);
+
+        let devices: Vec<_> = guests[0]
+            .devices
+            .iter()
+            .map(|dev| {
+                (
+                    dev.dev_id,
+                    dev.compatible.as_str(),
+                    dev.addr,
+                    dev.irq,
+                    dev.mmio.status,
+                    dev.mmio.activated,
+                )
+            })
+            .collect();
+        assert_eq!(
+            devices,
+            [
+                (0, "virtio,device22", STUB_ADDR, STUB_IRQ, 0, false),
+                (
+                    1,
+                    "virtio,device29",
+                    STUB_ADDR + VIRTIO_MMIO_IO_SIZE,
+                    STUB_IRQ + 1,
+                    VIRTIO_CONFIG_S_ACKNOWLEDGE,
+                    false
+                ),
+            ]
+        );
+    }
+
+    // Devices added while the guest is busy with the ioreqs of another one are served as soon as
+    // they are added.
+    #[test]
+    fn test_add_device_under_load() {
+        let frontend = XenFrontend::new(stub_args(&[])).unwrap();
+        let stub = StubGuest::new(stub_args(&[]));
+        let backend = || StubBackend::new(vec![1024], 0, Arc::new(Calls::default()));
+        let magic = |vcpu, dev: &XenDevice| {
+            stub.access_from(vcpu, dev, VIRTIO_MMIO_MAGIC_VALUE as u64, 4, IOREQ_READ, 0)
+                .data
+        };
+
+        let first = stub.add_device(0, "i2c", backend(), true).unwrap();
+        frontend
+            .guests
+            .lock()
+            .unwrap()
+            .add_guest(stub.guest.clone());
+        let stop = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                while !stop.load(Ordering::Relaxed) {
+                    assert_eq!(magic(1, &first), 0x74726976);
+                }
+            });
+
+            for dev_id in 1..16 {
+                let dev = stub.new_device(dev_id, "i2c", backend(), true).unwrap();
+                frontend
+                    .guests
+                    .lock()
+                    .unwrap()
+                    .add_device(dev.clone())
+                    .unwrap();
+                assert_eq!(magic(0, &dev), 0x74726976);
+            }
+            stop.store(true, Ordering::Relaxed);
+        });
+
+        assert_eq!(frontend.list()[0].devices.len(), 16);
+    }
+}
@@ -4,22 +4,149 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::{
+    convert::TryFrom,
+    io,
+    mem::{size_of, zeroed},
     os::unix::io::AsRawFd,
-    sync::{atomic::fence, atomic::Ordering, Arc, Mutex},
-    thread::{Builder, JoinHandle},
+    sync::{Arc, Mutex},
+    thread::{self, Builder, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
-use xen_bindings::bindings::{
-    ioreq, IOREQ_TYPE_COPY, IOREQ_TYPE_INVALIDATE, STATE_IOREQ_INPROCESS, STATE_IOREQ_READY,
-    STATE_IORESP_READY,
-};
+use xen_bindings::bindings::{ioreq, XC_PAGE_SIZE};
+
+use xen_ioctls::xc_domain_info;
 
 use super::{
-    device::XenDevice, epoll::XenEpoll, xdm::XenDeviceModel, xec::XenEventChannel,
-    xfm::XenForeignMemory, Result,
+    device::{DeviceContext, DeviceSnapshot, XenDevice, VIRTIO_MMIO_IO_SIZE},
+    epoll::XenEpoll,
+    ioreq::{handle_ioreq, IoreqWatchdog, XenIoreqSource},
+    layout::GuestRamLayout,
+    xdm::{DeviceModel, XenDeviceModel},
+    xec::{EventChannel, XenEventChannel},
+    xfm::{ForeignMemory, XenForeignMemory},
+    Error, Result,
 };
 
+const DOM_SIZE_RETRIES: u32 = 20;
+const DOM_SIZE_DELAY_MS: u64 = 50;
+const THREAD_EXIT_TIMEOUT_MS: u64 = 5000;
+
+// Waits for a thread that was asked to exit. A thread stuck in a backend call is left behind after
+// a while, instead of blocking the teardown forever.
+pub fn join_thread(handle: JoinHandle<()>) {
+    join_thread_timeout(handle, Duration::from_millis(THREAD_EXIT_TIMEOUT_MS));
+}
+
+fn join_thread_timeout(handle: JoinHandle<()>, timeout: Duration) {
+    let name = handle.thread().name().unwrap_or("unnamed").to_string();
+    let deadline = Instant::now() + timeout;
+
+    while !handle.is_finished() {
+        if Instant::now() >= deadline {
+            println!("Thread {} didn't exit in time, leaving it behind", name);
+            return;
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    if handle.join().is_err() {
+        println!("Thread {} panicked", name);
+    }
+}
+
+fn get_dom_pages(domid: u16) -> Result<u64> {
+    let info = xc_domain_info(domid, 1);
+
+    if info.len() != 1 {
+        Err(Error::InvalidDomainInfo(info.len(), domid, 0))
+    } else if info[0].domid != domid {
+        Err(Error::InvalidDomainInfo(
+            info.len(),
+            domid,
+            info[0].domid as usize,
+        ))
+    } else {
+        Ok(info[0].nr_pages as u64)
+    }
+}
+
+// Returns the size of the domain's RAM in bytes, from its number of pages and the pages that
+// aren't part of its RAM.
+fn dom_ram_size(domid: u16, pages: u64, extra_pages: u64) -> Result<usize> {
+    pages
+        .checked_sub(extra_pages)
+        .filter(|pages| *pages != 0)
+        .and_then(|pages| pages.checked_mul(XC_PAGE_SIZE as u64))
+        .and_then(|size| usize::try_from(size).ok())
+        .ok_or(Error::InvalidDomainPages(domid, pages))
+}
+
+// The number of pages may not be final yet for a domain that is still being built, wait for it to
+// settle.
+fn get_dom_size(
+    domid: u16,
+    extra_pages: u64,
+    get_pages: impl Fn(u16) -> Result<u64>,
+) -> Result<usize> {
+    let mut last = None;
+
+    for _ in 0..DOM_SIZE_RETRIES {
+        let pages = get_pages(domid)?;
+        if pages != 0 && last == Some(pages) {
+            return dom_ram_size(domid, pages, extra_pages);
+        }
+
+        last = Some(pages);
+        thread::sleep(Duration::from_millis(DOM_SIZE_DELAY_MS));
+    }
+
+    Err(Error::DomainSizeUnstable(domid))
+}
+
+// Returns the cpus to pin the guest's thread to, from "<domid>:<cpu>[,<cpu>..]" entries.
+fn guest_cpus(entries: &[String], domid: u16) -> Result<Option<Vec<usize>>> {
+    for entry in entries {
+        let (id, cpus) = entry
+            .split_once(':')
+            .ok_or_else(|| Error::InvalidGuestCpus(entry.clone()))?;
+
+        if id.parse::<u16>()? != domid {
+            continue;
+        }
+
+        let cpus = cpus
+            .split(',')
+            .map(str::parse::<usize>)
+            .collect::<std::result::Result<Vec<usize>, _>>()?;
+
+        return Ok(Some(cpus));
+    }
+
+    Ok(None)
+}
+
+// Pins the calling thread to the cpus.
+fn set_affinity(cpus: &[usize]) -> io::Result<()> {
+    // SAFETY: cpu_set_t is a plain bitmap, all zeroes is an empty set.
+    let mut set: libc::cpu_set_t = unsafe { zeroed() };
+
+    for cpu in cpus {
+        // SAFETY: Safe as set is a valid cpu_set_t, out of range cpus are ignored.
+        unsafe { libc::CPU_SET(*cpu, &mut set) };
+    }
+
+    // SAFETY: Safe as set is a valid cpu_set_t of the passed size.
+    let ret = unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
 #[derive(Default)]
 struct GuestDevices(Vec<Arc<XenDevice>>);
 
@@ -28,34 +155,65 @@ impl GuestDevices {
         self.0.push(dev);
     }
 
-    fn remove(&mut self, dev_id: u32) -> Arc<XenDevice> {
-        self.0
-            .remove(self.0.iter().position(|dev| dev.dev_id == dev_id).unwrap())
+    fn remove(&mut self, dev_id: u32) -> Option<Arc<XenDevice>> {
+        let index = self.0.iter().position(|dev| dev.dev_id == dev_id)?;
+        Some(self.0.remove(index))
+    }
+
+    fn drain(&mut self) -> Vec<Arc<XenDevice>> {
+        self.0.drain(..).collect()
     }
 
-    fn io_event(&self, ioreq: &mut ioreq) -> Result<()> {
-        for dev in &self.0 {
-            if ioreq.addr >= dev.addr && ioreq.addr < dev.addr + 0x200 {
-                dev.io_event(ioreq)?;
-                return Ok(());
+    // Returns the device if it hit a fatal error while handling the ioreq.
+    fn io_event(&self, ioreq: &mut ioreq) -> Option<Arc<XenDevice>> {
+        let dev = self
+            .0
+            .iter()
+            .find(|dev| ioreq.addr >= dev.addr && ioreq.addr < dev.addr + VIRTIO_MMIO_IO_SIZE)?;
+
+        match dev.io_event(ioreq) {
+            Ok(()) => None,
+            Err(e) => {
+                println!(
+                    "Device {} / {}: Failed to handle ioreq at {:#x}: {}",
+                    dev.guest.fe_domid, dev.dev_id, ioreq.addr, e
+                );
+
+                if e.is_fatal() {
+                    Some(dev.clone())
+                } else {
+                    None
+                }
             }
         }
-
-        Ok(())
     }
 
     fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
+
+    fn snapshot(&self) -> Vec<DeviceSnapshot> {
+        self.0.iter().map(|dev| dev.snapshot()).collect()
+    }
+}
+
+pub struct GuestSnapshot {
+    pub fe_domid: u16,
+    pub devices: Vec<DeviceSnapshot>,
+    pub ioreq_stalls: u64,
 }
 
 pub struct XenGuest {
-    pub xdm: Mutex<XenDeviceModel>,
-    pub xec: Mutex<XenEventChannel>,
-    pub xfm: Mutex<XenForeignMemory>,
+    pub xdm: Mutex<Box<dyn DeviceModel>>,
+    pub xec: Mutex<Box<dyn EventChannel>>,
+    pub xfm: Mutex<Box<dyn ForeignMemory>>,
     pub fe_domid: u16,
+    pub size: usize,
+    pub layout: GuestRamLayout,
+    pub ctx: Arc<DeviceContext>,
     devices: Mutex<GuestDevices>,
-    handle: Mutex<Option<JoinHandle<()>>>,
+    watchdog: Option<IoreqWatchdog>,
+    handles: Mutex<Vec<JoinHandle<()>>>,
     exit: EventFd,
 }
 
@@ -65,7 +223,9 @@ unsafe impl Send for XenGuest {}
 unsafe impl Sync for XenGuest {}
 
 impl XenGuest {
-    pub fn new(fe_domid: u16) -> Result<Arc<Self>> {
+    pub fn new(fe_domid: u16, ctx: Arc<DeviceContext>) -> Result<Arc<Self>> {
+        let size = get_dom_size(fe_domid, ctx.args.dom_extra_pages, get_dom_pages)?;
+
         let mut xdm = XenDeviceModel::new(fe_domid)?;
         xdm.create_ioreq_server()?;
 
@@ -76,13 +236,45 @@ impl XenGuest {
         let mut xec = XenEventChannel::new()?;
         xec.bind(&xfm, fe_domid, xdm.vcpus())?;
 
+        Self::with_handles(
+            fe_domid,
+            ctx,
+            size,
+            Box::new(xdm),
+            Box::new(xec),
+            Box::new(xfm),
+        )
+    }
+
+    // Sets up the guest over handles that are ready to use already, the ioreq server created and
+    // its event channels bound.
+    pub fn with_handles(
+        fe_domid: u16,
+        ctx: Arc<DeviceContext>,
+        size: usize,
+        xdm: Box<dyn DeviceModel>,
+        xec: Box<dyn EventChannel>,
+        xfm: Box<dyn ForeignMemory>,
+    ) -> Result<Arc<Self>> {
+        let watchdog = ctx
+            .args
+            .ioreq_timeout
+            .map(|timeout| IoreqWatchdog::new(Duration::from_millis(timeout)));
+
         let guest = Arc::new(Self {
             xdm: Mutex::new(xdm),
             xec: Mutex::new(xec),
             xfm: Mutex::new(xfm),
             fe_domid,
+            size,
+            layout: match &ctx.ram_banks {
+                Some(banks) => GuestRamLayout::with_banks(size, banks),
+                None => GuestRamLayout::new(size),
+            },
+            ctx,
             devices: Mutex::new(GuestDevices::default()),
-            handle: Mutex::new(None),
+            watchdog,
+            handles: Mutex::new(Vec::new()),
             exit: EventFd::new(EFD_NONBLOCK).unwrap(),
         });
 
@@ -90,58 +282,61 @@ impl XenGuest {
         Ok(guest)
     }
 
-    pub fn add_device(self: Arc<Self>, dev_id: u32) -> Result<Arc<XenDevice>> {
-        let dev = XenDevice::new(dev_id, self.clone())?;
+    // Starts serving a device created for this guest.
+    pub fn add_device(&self, dev: Arc<XenDevice>) -> Result<()> {
+        let dev_id = dev.dev_id;
+
+        // The guest's thread may be handling ioreqs of other devices already. The device must be
+        // visible to it before Xen starts sending ioreqs for its range here, and the range is
+        // registered while the caller still holds the frontend's guests lock, so a removal of the
+        // device can't slip in between.
         self.devices.lock().unwrap().push(dev.clone());
+        if let Err(e) = dev.setup_ioreq() {
+            self.remove_device(dev_id);
+            return Err(e);
+        }
 
         println!("Created device {} / {}", self.fe_domid, dev_id);
-        Ok(dev)
+        Ok(())
     }
 
     pub fn remove_device(&self, dev_id: u32) {
+        // Bind the result first, so the devices lock isn't held while the device exits.
         let dev = self.devices.lock().unwrap().remove(dev_id);
 
-        println!("Removed device {} / {}", self.fe_domid, dev_id);
-        dev.exit();
+        if let Some(dev) = dev {
+            println!("Removed device {} / {}", self.fe_domid, dev_id);
+            dev.exit();
+        }
     }
 
     fn io_event(&self) -> Result<()> {
-        let mut xec = self.xec.lock().unwrap();
-        let xfm = self.xfm.lock().unwrap();
-
-        let (port, cpu) = xec.pending()?;
-        xec.unmask(port)?;
-
-        let ioreq = xfm.ioreq(cpu)?;
-        if ioreq.state() != STATE_IOREQ_READY as u8 {
-            return Ok(());
+        // Reconnecting takes time and isn't done here, the ioreqs of the other devices of the
+        // guest would have to wait for it.
+        if let Some(dev) = self.handle_ioreq()? {
+            dev.start_reconnect();
         }
 
-        // Memory barrier
-        fence(Ordering::SeqCst);
+        Ok(())
+    }
 
-        ioreq.set_state(STATE_IOREQ_INPROCESS as u8);
+    fn handle_ioreq(&self) -> Result<Option<Arc<XenDevice>>> {
+        let mut source = XenIoreqSource::new(&self.xec, &self.xfm);
 
-        match ioreq.type_ as u32 {
-            IOREQ_TYPE_COPY => {
-                self.devices.lock().unwrap().io_event(ioreq)?;
+        // Device errors are reported by GuestDevices::io_event().
+        handle_ioreq(&mut source, |ioreq| {
+            if let Some(watchdog) = &self.watchdog {
+                watchdog.start(ioreq.addr);
             }
 
-            IOREQ_TYPE_INVALIDATE => println!("Invalidate Ioreq type is Not implemented"),
-            t => println!("Ioreq type unknown: {}", t),
-        }
-
-        // Memory barrier
-        fence(Ordering::SeqCst);
-
-        ioreq.set_state(STATE_IORESP_READY as u8);
-
-        // Memory barrier
-        fence(Ordering::SeqCst);
+            let dev = self.devices.lock().unwrap().io_event(ioreq);
 
-        xec.notify(port)?;
+            if let Some(watchdog) = &self.watchdog {
+                watchdog.stop(self.fe_domid);
+            }
 
-        Ok(())
+            dev
+        })
     }
 
     fn setup_events(self: Arc<Self>) -> Result<()> {
@@ -149,34 +344,330 @@ impl XenGuest {
         let efd = self.exit.as_raw_fd();
         let epoll = XenEpoll::new(vec![efd, xfd])?;
         let guest = self.clone();
+        let cpus = guest_cpus(&self.ctx.args.guest_cpus, self.fe_domid)?;
 
-        *self.handle.lock().unwrap() = Some(
+        self.handles.lock().unwrap().push(
             Builder::new()
                 .name(format!("guest {}", self.fe_domid))
                 .spawn(move || {
+                    if let Some(cpus) = cpus {
+                        if let Err(e) = set_affinity(&cpus) {
+                            println!(
+                                "Guest {}: Failed to set cpu affinity: {}",
+                                guest.fe_domid, e
+                            );
+                        }
+                    }
+
                     while let Ok(fd) = epoll.wait() {
                         // Exit event received
                         if fd == efd {
                             break;
                         }
 
-                        guest.io_event().ok();
+                        if let Err(e) = guest.io_event() {
+                            println!("Guest {}: Failed to handle io event: {}", guest.fe_domid, e);
+                        }
                     }
                 })
                 .unwrap(),
         );
 
+        if self.watchdog.is_some() {
+            self.setup_watchdog()?;
+        }
+
         Ok(())
     }
 
+    // The guest's thread is the one stuck on a stalled ioreq, the watchdog runs on its own thread.
+    fn setup_watchdog(self: Arc<Self>) -> Result<()> {
+        let efd = self.exit.as_raw_fd();
+        let epoll = XenEpoll::new(vec![efd])?;
+        let guest = self.clone();
+
+        self.handles.lock().unwrap().push(
+            Builder::new()
+                .name(format!("watchdog {}", self.fe_domid))
+                .spawn(move || {
+                    let watchdog = guest.watchdog.as_ref().unwrap();
+                    let period = watchdog.period().as_millis() as i32;
+
+                    // Exit event received, or epoll failed.
+                    while let Ok(None) = epoll.wait_timeout(period) {
+                        watchdog.check(guest.fe_domid);
+                    }
+                })
+                .unwrap(),
+        );
+
+        Ok(())
+    }
+
+    // Keeps a thread of the guest to wait for on exit, the ones that are done already are dropped.
+    pub fn push_thread(&self, handle: JoinHandle<()>) {
+        let mut handles = self.handles.lock().unwrap();
+
+        handles.retain(|handle| !handle.is_finished());
+        handles.push(handle);
+    }
+
     pub fn is_empty(&self) -> bool {
         self.devices.lock().unwrap().is_empty()
     }
 
+    pub fn snapshot(&self) -> GuestSnapshot {
+        GuestSnapshot {
+            fe_domid: self.fe_domid,
+            devices: self.devices.lock().unwrap().snapshot(),
+            ioreq_stalls: self
+                .watchdog
+                .as_ref()
+                .map_or(0, |watchdog| watchdog.stalls()),
+        }
+    }
+
+    // Xen never reuses a domid for a rebooted guest, so it is enough to check if the domain
+    // still exists.
+    pub fn is_alive(&self) -> bool {
+        let info = xc_domain_info(self.fe_domid, 1);
+        info.len() == 1 && info[0].domid == self.fe_domid
+    }
+
     pub fn exit(&self) {
+        // Remove any devices left behind by a guest that went away without removing them.
+        let devices = self.devices.lock().unwrap().drain();
+        for dev in devices {
+            println!("Removed device {} / {}", self.fe_domid, dev.dev_id);
+            dev.exit();
+        }
+
         self.exit.write(1).unwrap();
-        if let Some(handle) = self.handle.lock().unwrap().take() {
-            handle.join().unwrap();
+        let handles: Vec<_> = self.handles.lock().unwrap().drain(..).collect();
+        for handle in handles {
+            join_thread(handle);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DOM_EXTRA_PAGES;
+    use crate::stubs::{
+        stub_args, Calls, StubBackend, StubDeviceModel, StubEventChannel, StubForeignMemory,
+        StubGuest, StubXen, STUB_DOMID, STUB_GUEST_SIZE,
+    };
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    };
+    use vhost::vhost_user::message::VHOST_USER_CONFIG_OFFSET;
+    use xen_bindings::bindings::IOREQ_READ;
+
+    // The cpus the calling thread may run on.
+    fn affinity() -> Vec<usize> {
+        // SAFETY: cpu_set_t is a plain bitmap, all zeroes is an empty set.
+        let mut set: libc::cpu_set_t = unsafe { zeroed() };
+
+        // SAFETY: Safe as set is a valid cpu_set_t of the passed size.
+        let ret = unsafe { libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &mut set) };
+        assert_eq!(ret, 0);
+
+        (0..libc::CPU_SETSIZE as usize)
+            // SAFETY: Safe as set is a valid cpu_set_t and the cpu is within it.
+            .filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, &set) })
+            .collect()
+    }
+
+    #[test]
+    fn test_ram_banks() {
+        let banks = "0x80000000:0x800000,0x100000000:0x10000000";
+        let stub = StubGuest::new(stub_args(&["--ram-banks", banks]));
+
+        assert_eq!(
+            stub.guest.layout.chunks(1 << 30),
+            [(0x80000000, 0x800000), (0x100000000, 0x800000)]
+        );
+    }
+
+    // A domain that is still being built reports a different size every time.
+    #[test]
+    fn test_dom_size_unstable() {
+        let xen = StubXen::new();
+        let pages = |domid| xen.dom_pages(domid);
+
+        xen.set_dom_pages(&(1..=DOM_SIZE_RETRIES as u64).collect::<Vec<_>>());
+        assert!(matches!(
+            get_dom_size(STUB_DOMID, 0, pages),
+            Err(Error::DomainSizeUnstable(STUB_DOMID))
+        ));
+
+        // No pages yet doesn't count as settled.
+        xen.set_dom_pages(&[0, 0, 0x800, 0x1000]);
+        assert_eq!(
+            get_dom_size(STUB_DOMID, 0, pages).unwrap(),
+            0x1000 * XC_PAGE_SIZE as usize
+        );
+
+        assert!(matches!(
+            get_dom_size(STUB_DOMID + 1, 0, pages),
+            Err(Error::InvalidDomainInfo(..))
+        ));
+    }
+
+    #[test]
+    fn test_guest_cpus() {
+        let entries = ["2:3".to_string(), "1:0,1".to_string()];
+
+        assert_eq!(guest_cpus(&entries, 1).unwrap(), Some(vec![0, 1]));
+        assert_eq!(guest_cpus(&entries, 3).unwrap(), None);
+        assert!(matches!(
+            guest_cpus(&["1".to_string()], 1),
+            Err(Error::InvalidGuestCpus(_))
+        ));
+        assert!(guest_cpus(&["1:a".to_string()], 1).is_err());
+    }
+
+    #[test]
+    fn test_set_affinity() {
+        // Any cpu this thread can run on, the test may be limited to some of them.
+        let cpu = affinity()[0];
+
+        let cpus = thread::spawn(move || {
+            set_affinity(&[cpu]).unwrap();
+            affinity()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(cpus, [cpu]);
+
+        // The guest's entry is checked before its thread starts.
+        let xen = StubXen::new();
+        let ctx = DeviceContext::new(stub_args(&["--guest-cpus", "1"])).unwrap();
+        let guest = XenGuest::with_handles(
+            STUB_DOMID,
+            Arc::new(ctx),
+            STUB_GUEST_SIZE,
+            Box::new(StubDeviceModel::new(xen.clone())),
+            Box::new(StubEventChannel::new(xen.clone())),
+            Box::new(StubForeignMemory::new(xen)),
+        );
+        assert!(matches!(guest, Err(Error::InvalidGuestCpus(_))));
+
+        let guest = StubGuest::new(stub_args(&[
+            "--guest-cpus",
+            &format!("{}:{}", STUB_DOMID, cpu),
+        ]));
+        assert!(guest.guest.is_empty());
+    }
+
+    #[test]
+    fn test_join_thread_timeout() {
+        let timeout = Duration::from_millis(50);
+        let (tx, rx) = mpsc::channel::<()>();
+        let done = Arc::new(AtomicBool::new(false));
+        let thread_done = done.clone();
+
+        // Stuck like a thread waiting on a backend that doesn't reply.
+        let handle = Builder::new()
+            .name("stuck".to_string())
+            .spawn(move || {
+                let _ = rx.recv();
+                thread_done.store(true, Ordering::SeqCst);
+            })
+            .unwrap();
+
+        let start = Instant::now();
+        join_thread_timeout(handle, timeout);
+        assert!(start.elapsed() >= timeout);
+        assert!(!done.load(Ordering::SeqCst));
+        drop(tx);
+
+        // Threads that exit in time are waited for, even if they panicked.
+        let done = Arc::new(AtomicBool::new(false));
+        let thread_done = done.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            thread_done.store(true, Ordering::SeqCst);
+        });
+        join_thread_timeout(handle, Duration::from_secs(5));
+        assert!(done.load(Ordering::SeqCst));
+
+        join_thread_timeout(thread::spawn(|| panic!("stub")), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_ioreq_watchdog() {
+        let stub = StubGuest::new(stub_args(&["--ioreq-timeout", "20"]));
+        let backend =
+            StubBackend::new(vec![64], 0, Arc::new(Calls::default())).with_config(vec![1; 4]);
+        let config = backend.config();
+        let dev = stub.add_device(0, "i2c", backend, true).unwrap();
+        let offset = VHOST_USER_CONFIG_OFFSET as u64;
+
+        stub.access(&dev, offset, 4, IOREQ_READ, 0);
+        assert_eq!(stub.guest.snapshot().ioreq_stalls, 0);
+
+        // Config reads wait for the backend, which doesn't reply for a while.
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let _config = config.lock().unwrap();
+            tx.send(()).unwrap();
+            thread::sleep(Duration::from_millis(200));
+        });
+        rx.recv().unwrap();
+
+        let req = stub.access(&dev, offset, 4, IOREQ_READ, 0);
+        assert_eq!(req.data, 0x01010101);
+        handle.join().unwrap();
+
+        // Counted once, however long it stalled.
+        assert_eq!(stub.guest.snapshot().ioreq_stalls, 1);
+        stub.access(&dev, offset, 4, IOREQ_READ, 0);
+        assert_eq!(stub.guest.snapshot().ioreq_stalls, 1);
+    }
+
+    // A domain with no more pages than the ones that aren't RAM.
+    #[test]
+    fn test_dom_size_underflow() {
+        let xen = StubXen::new();
+        let pages = |domid| xen.dom_pages(domid);
+
+        xen.set_dom_pages(&[0x100]);
+        assert!(matches!(
+            get_dom_size(STUB_DOMID, 0x101, pages),
+            Err(Error::InvalidDomainPages(STUB_DOMID, 0x100))
+        ));
+        assert!(matches!(
+            get_dom_size(STUB_DOMID, 0x100, pages),
+            Err(Error::InvalidDomainPages(STUB_DOMID, 0x100))
+        ));
+
+        assert!(matches!(
+            dom_ram_size(STUB_DOMID, u64::MAX, 0),
+            Err(Error::InvalidDomainPages(STUB_DOMID, u64::MAX))
+        ));
+    }
+
+    // The pages that aren't RAM are left out of the guest's size, as set by the user.
+    #[test]
+    fn test_dom_extra_pages() {
+        let xen = StubXen::new();
+        let pages = |domid| xen.dom_pages(domid);
+        let page = XC_PAGE_SIZE as usize;
+
+        let args = stub_args(&[]);
+        xen.set_dom_pages(&[0x1000 + DOM_EXTRA_PAGES]);
+        assert_eq!(
+            get_dom_size(STUB_DOMID, args.dom_extra_pages, pages).unwrap(),
+            0x1000 * page
+        );
+
+        let args = stub_args(&["--dom-extra-pages", "32"]);
+        assert_eq!(
+            get_dom_size(STUB_DOMID, args.dom_extra_pages, pages).unwrap(),
+            (0x1000 + DOM_EXTRA_PAGES as usize - 32) * page
+        );
+    }
+}
@@ -3,58 +3,375 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{io::Result as IoResult, sync::Arc};
+use std::{
+    io::{self, ErrorKind, Result as IoResult},
+    os::unix::io::{AsRawFd, RawFd},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
 
 use vhost_user_frontend::{VirtioInterrupt, VirtioInterruptType};
-use vmm_sys_util::eventfd::EventFd;
+use virtio_bindings::virtio_mmio::{VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
-use super::device::XenDevice;
+use super::{device::XenDevice, xec::EventChannel, Result};
+
+// How the guest's interrupt is raised, see DeviceArgs::interrupt.
+enum Notifier {
+    // Registered with Xen, raises the guest's interrupt when written.
+    Irqfd(EventFd),
+    // The event channel and its local port bound to the guest's port.
+    EventChannel(Mutex<Box<dyn EventChannel>>, u32),
+}
 
 pub struct XenInterrupt {
     dev: Arc<XenDevice>,
-    // Single EventFd is enough for any number of queues as there is a single underlying interrupt
-    // to guest anyway.
-    call: EventFd,
+    // An EventFd per queue, the backend signals used buffers on them, which is forwarded to the
+    // guest once the interrupt status is updated. The guest has a single interrupt for all the
+    // queues anyway, separate EventFds only tell which queue the backend signaled.
+    calls: Vec<EventFd>,
+    // Number of used buffer notifications per queue, for diagnostics.
+    counts: Vec<AtomicU64>,
+    notifier: Notifier,
+    // Interrupt status register, kept here as the backend raises interrupts without taking any of
+    // the device locks.
+    status: AtomicU32,
+    // Config generation register, changes with every config change reported by the backend.
+    generation: AtomicU32,
 }
 
+// SAFETY: Safe as the event channel is protected with Mutex.
+unsafe impl Send for XenInterrupt {}
+// SAFETY: Safe as the event channel is protected with Mutex.
+unsafe impl Sync for XenInterrupt {}
+
 impl XenInterrupt {
-    pub fn new(dev: Arc<XenDevice>) -> Arc<Self> {
-        let call = EventFd::new(0).unwrap();
+    // The interrupt is delivered over the guest's event channel port if there is one, over an
+    // irqfd for the device's irq otherwise.
+    pub fn new(dev: Arc<XenDevice>, num_queues: usize, port: Option<u32>) -> Result<Arc<Self>> {
+        let notifier = match port {
+            Some(port) => {
+                let mut xec = dev.guest.xec.lock().unwrap().open()?;
+                let port = xec.bind_port(dev.guest.fe_domid, port)?;
+
+                Notifier::EventChannel(Mutex::new(xec), port)
+            }
+            None => {
+                let irqfd = EventFd::new(0).unwrap();
+
+                dev.guest.xdm.lock().unwrap().set_irqfd(
+                    irqfd.try_clone().unwrap(),
+                    dev.irq,
+                    true,
+                )?;
 
-        let xen_int = Arc::new(XenInterrupt {
+                Notifier::Irqfd(irqfd)
+            }
+        };
+
+        Ok(Arc::new(XenInterrupt {
             dev,
-            call: call.try_clone().unwrap(),
-        });
-
-        xen_int
-            .dev
-            .guest
-            .xdm
-            .lock()
-            .unwrap()
-            .set_irqfd(call, xen_int.dev.irq as u32, true)
-            .unwrap();
+            calls: (0..num_queues)
+                .map(|_| EventFd::new(EFD_NONBLOCK).unwrap())
+                .collect(),
+            counts: (0..num_queues).map(|_| AtomicU64::new(0)).collect(),
+            notifier,
+            status: AtomicU32::new(0),
+            generation: AtomicU32::new(0),
+        }))
+    }
+
+    fn raise(&self, bit: u32) -> IoResult<()> {
+        // The status must be updated before the guest gets interrupted and reads it.
+        self.status.fetch_or(bit, Ordering::AcqRel);
+
+        match &self.notifier {
+            Notifier::Irqfd(irqfd) => irqfd.write(1),
+            Notifier::EventChannel(xec, port) => xec
+                .lock()
+                .unwrap()
+                .notify(*port)
+                .map_err(|e| io::Error::new(ErrorKind::Other, e)),
+        }
+    }
+
+    fn raise_queue(&self, index: usize) -> IoResult<()> {
+        if let Some(count) = self.counts.get(index) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.raise(VIRTIO_MMIO_INT_VRING)
+    }
+
+    pub fn call_fds(&self) -> Vec<RawFd> {
+        self.calls.iter().map(|call| call.as_raw_fd()).collect()
+    }
+
+    // Called when the backend signals used buffers on one of the call eventfds.
+    pub fn call_event(&self, fd: RawFd) -> IoResult<()> {
+        let index = match self.calls.iter().position(|call| call.as_raw_fd() == fd) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
 
-        xen_int
+        // Any number of signals raise a single interrupt.
+        self.calls[index].read()?;
+        self.raise_queue(index)
+    }
+
+    // Number of used buffer notifications of each queue.
+    pub fn counts(&self) -> Vec<u64> {
+        self.counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    pub fn status(&self) -> u32 {
+        self.status.load(Ordering::Acquire)
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    // Called when the config space may have changed, the driver reads the fields it was in the
+    // middle of reading again when it sees a new generation.
+    pub fn config_changed(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn ack(&self, bits: u32) {
+        self.status.fetch_and(!bits, Ordering::AcqRel);
+    }
+
+    pub fn reset(&self) {
+        self.status.store(0, Ordering::Release);
     }
 
     pub fn exit(&self) {
-        self.dev
-            .guest
-            .xdm
-            .lock()
-            .unwrap()
-            .set_irqfd(self.call.try_clone().unwrap(), self.dev.irq as u32, false)
-            .unwrap();
+        // The event channel port is unbound when the event channel is dropped.
+        if let Notifier::Irqfd(irqfd) = &self.notifier {
+            // This fails if the guest is already gone, nothing more to clean up in that case.
+            self.dev
+                .guest
+                .xdm
+                .lock()
+                .unwrap()
+                .set_irqfd(irqfd.try_clone().unwrap(), self.dev.irq, false)
+                .ok();
+        }
     }
 }
 
 impl VirtioInterrupt for XenInterrupt {
-    fn trigger(&self, _int_type: VirtioInterruptType) -> IoResult<()> {
-        Ok(())
+    fn trigger(&self, int_type: VirtioInterruptType) -> IoResult<()> {
+        match int_type {
+            VirtioInterruptType::Config => {
+                // The guest's config accesses are done with gdev held, taking it here makes sure
+                // the generation doesn't change in the middle of one of them.
+                let gdev = self.dev.gdev.lock().unwrap();
+                self.config_changed();
+                drop(gdev);
+
+                self.raise(VIRTIO_MMIO_INT_CONFIG)
+            }
+            VirtioInterruptType::Queue(index) => self.raise_queue(index as usize),
+        }
+    }
+
+    // Config changes are only reported through trigger(), there is no eventfd for them.
+    fn notifier(&self, int_type: VirtioInterruptType) -> Option<EventFd> {
+        match int_type {
+            VirtioInterruptType::Config => None,
+            VirtioInterruptType::Queue(index) => self
+                .calls
+                .get(index as usize)
+                .map(|call| call.try_clone().unwrap()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stubs::{
+        stub_args, Calls, StubBackend, StubGuest, STUB_DOMID, STUB_GUEST_PORT, STUB_IRQ,
+    };
+    use crate::Error;
+
+    // Adds a device with the arguments, returns its interrupt and the calls made to set it up.
+    fn stub_interrupt(args: &[&str]) -> (StubGuest, Arc<XenInterrupt>, Vec<String>) {
+        let stub = StubGuest::new(stub_args(args));
+        let backend = StubBackend::new(vec![1024], 0, Arc::new(Calls::default()));
+
+        stub.xen.calls.take();
+        let dev = stub.add_device(0, "i2c", backend, true).unwrap();
+        let calls = stub
+            .xen
+            .calls
+            .take()
+            .into_iter()
+            .filter(|call| call.starts_with("set_irqfd") || call.starts_with("bind_port"))
+            .collect();
+
+        (stub, dev.interrupt(), calls)
+    }
+
+    // The interrupt is raised over an irqfd registered for the device's irq by default.
+    #[test]
+    fn test_irqfd() {
+        let (stub, interrupt, calls) = stub_interrupt(&[]);
+        assert_eq!(calls, [format!("set_irqfd {} true", STUB_IRQ)]);
+
+        interrupt.trigger(VirtioInterruptType::Queue(0)).unwrap();
+        match &interrupt.notifier {
+            Notifier::Irqfd(irqfd) => assert_eq!(irqfd.read().unwrap(), 1),
+            Notifier::EventChannel(..) => panic!("event channel notifier with irqfd"),
+        }
+        assert!(stub.xen.calls.take().is_empty());
+
+        interrupt.exit();
+        assert_eq!(
+            stub.xen.calls.take(),
+            [format!("set_irqfd {} false", STUB_IRQ)]
+        );
+    }
+
+    // Or by notifying a port bound to the one the guest allocated for it.
+    #[test]
+    fn test_event_channel() {
+        let (stub, interrupt, calls) = stub_interrupt(&["--interrupt", "event-channel"]);
+
+        let port = match &interrupt.notifier {
+            Notifier::EventChannel(_, port) => *port,
+            Notifier::Irqfd(_) => panic!("irqfd notifier with event channel"),
+        };
+        assert_eq!(
+            calls,
+            [format!(
+                "bind_port {} {} {}",
+                STUB_DOMID, STUB_GUEST_PORT, port
+            )]
+        );
+
+        interrupt.trigger(VirtioInterruptType::Queue(0)).unwrap();
+        interrupt.trigger(VirtioInterruptType::Config).unwrap();
+        assert_eq!(
+            stub.xen.calls.take(),
+            [format!("notify {}", port), format!("notify {}", port)]
+        );
+
+        // There is no irqfd to clear.
+        interrupt.exit();
+        assert!(stub.xen.calls.take().is_empty());
+    }
+
+    fn irqfd_count(interrupt: &XenInterrupt) -> u64 {
+        match &interrupt.notifier {
+            Notifier::Irqfd(irqfd) => irqfd.read().unwrap(),
+            Notifier::EventChannel(..) => panic!("event channel notifier with irqfd"),
+        }
+    }
+
+    // Each interrupt type sets its own bit of the status register, which the guest acks.
+    #[test]
+    fn test_status_bits() {
+        let (_stub, interrupt, _) = stub_interrupt(&[]);
+        assert_eq!(interrupt.status(), 0);
+
+        interrupt.trigger(VirtioInterruptType::Queue(0)).unwrap();
+        assert_eq!(interrupt.status(), VIRTIO_MMIO_INT_VRING);
+        assert_eq!(irqfd_count(&interrupt), 1);
+
+        interrupt.trigger(VirtioInterruptType::Config).unwrap();
+        assert_eq!(
+            interrupt.status(),
+            VIRTIO_MMIO_INT_VRING | VIRTIO_MMIO_INT_CONFIG
+        );
+        assert_eq!(irqfd_count(&interrupt), 1);
+
+        interrupt.ack(VIRTIO_MMIO_INT_VRING);
+        assert_eq!(interrupt.status(), VIRTIO_MMIO_INT_CONFIG);
+        interrupt.ack(VIRTIO_MMIO_INT_CONFIG);
+        assert_eq!(interrupt.status(), 0);
+    }
+
+    // The backend signals used buffers on the call eventfds, the guest is only interrupted once
+    // the status tells it why.
+    #[test]
+    fn test_call_event() {
+        let (_stub, interrupt, _) = stub_interrupt(&[]);
+        let call = interrupt.notifier(VirtioInterruptType::Queue(0)).unwrap();
+
+        // Not a call eventfd of the device.
+        interrupt.call_event(-1).unwrap();
+        assert_eq!(interrupt.status(), 0);
+
+        call.write(1).unwrap();
+        interrupt.call_event(call.as_raw_fd()).unwrap();
+        assert_eq!(irqfd_count(&interrupt), 1);
+        assert_eq!(interrupt.status(), VIRTIO_MMIO_INT_VRING);
+
+        assert!(interrupt.notifier(VirtioInterruptType::Config).is_none());
     }
 
-    fn notifier(&self, _int_type: VirtioInterruptType) -> Option<EventFd> {
-        Some(self.call.try_clone().unwrap())
+    // Used buffer notifications are counted per queue, all of them raise the same status bit.
+    #[test]
+    fn test_queue_counts() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let backend = StubBackend::new(vec![64, 64], 0, Arc::new(Calls::default()));
+        let interrupt = stub
+            .add_device(0, "input", backend, true)
+            .unwrap()
+            .interrupt();
+        let calls: Vec<EventFd> = (0..2)
+            .map(|index| {
+                interrupt
+                    .notifier(VirtioInterruptType::Queue(index))
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(interrupt.counts(), [0, 0]);
+
+        // Any number of signals before the event is handled count once.
+        calls[1].write(3).unwrap();
+        interrupt.call_event(calls[1].as_raw_fd()).unwrap();
+        assert_eq!(interrupt.counts(), [0, 1]);
+
+        calls[0].write(1).unwrap();
+        interrupt.call_event(calls[0].as_raw_fd()).unwrap();
+        interrupt.trigger(VirtioInterruptType::Queue(1)).unwrap();
+        assert_eq!(interrupt.counts(), [1, 2]);
+        assert_eq!(interrupt.status(), VIRTIO_MMIO_INT_VRING);
+
+        // Config changes aren't counted.
+        interrupt.trigger(VirtioInterruptType::Config).unwrap();
+        assert_eq!(interrupt.counts(), [1, 2]);
+    }
+
+    // Xen refusing the irqfd fails adding the device, and clearing it only gets logged.
+    #[test]
+    fn test_irqfd_failed() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let backend = StubBackend::new(vec![1024], 0, Arc::new(Calls::default()));
+
+        stub.xen.fail_irqfd.store(true, Ordering::Release);
+        assert!(matches!(
+            stub.add_device(0, "i2c", backend, true),
+            Err(Error::IoError(e)) if e.raw_os_error() == Some(libc::EINVAL)
+        ));
+        assert!(stub.guest.is_empty());
+
+        stub.xen.fail_irqfd.store(false, Ordering::Release);
+        let (stub, interrupt, _) = stub_interrupt(&[]);
+        stub.xen.fail_irqfd.store(true, Ordering::Release);
+        interrupt.exit();
+        assert_eq!(
+            stub.xen.calls.take(),
+            [format!("set_irqfd {} false", STUB_IRQ)]
+        );
     }
 }
@@ -0,0 +1,281 @@
+// Copyright 2022-2023 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use std::{
+    sync::{
+        atomic::{fence, AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use xen_bindings::bindings::{
+    ioreq, IOREQ_TYPE_COPY, IOREQ_TYPE_INVALIDATE, STATE_IOREQ_INPROCESS, STATE_IOREQ_READY,
+    STATE_IORESP_READY,
+};
+
+use super::{xec::EventChannel, xfm::ForeignMemory, Result};
+
+// Where the ioreqs come from and how the vcpu is told that one is completed. Keeps the ioreq state
+// handling below independent of the Xen event channel and foreign memory.
+pub trait IoreqSource {
+    // Returns the ioreq of the vcpu that raised the event, along with the port to complete it on.
+    fn pending(&mut self) -> Result<(u32, &mut ioreq)>;
+    fn complete(&mut self, port: u32) -> Result<()>;
+}
+
+pub struct XenIoreqSource<'a> {
+    xec: MutexGuard<'a, Box<dyn EventChannel>>,
+    xfm: MutexGuard<'a, Box<dyn ForeignMemory>>,
+}
+
+impl<'a> XenIoreqSource<'a> {
+    // Lock order: xec and then xfm, see working.md.
+    pub fn new(
+        xec: &'a Mutex<Box<dyn EventChannel>>,
+        xfm: &'a Mutex<Box<dyn ForeignMemory>>,
+    ) -> Self {
+        let xec = xec.lock().unwrap();
+        let xfm = xfm.lock().unwrap();
+
+        Self { xec, xfm }
+    }
+}
+
+impl IoreqSource for XenIoreqSource<'_> {
+    fn pending(&mut self) -> Result<(u32, &mut ioreq)> {
+        let (port, cpu) = self.xec.pending()?;
+        self.xec.unmask(port)?;
+
+        Ok((port, self.xfm.ioreq(cpu)?))
+    }
+
+    fn complete(&mut self, port: u32) -> Result<()> {
+        self.xec.notify(port)
+    }
+}
+
+struct InProcess {
+    addr: u64,
+    start: Instant,
+    reported: bool,
+}
+
+// Tracks the ioreq being handled, so a handler stuck in the backend is reported while the vcpu is
+// still waiting for the response.
+pub struct IoreqWatchdog {
+    timeout: Duration,
+    current: Mutex<Option<InProcess>>,
+    stalls: AtomicU64,
+}
+
+impl IoreqWatchdog {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            current: Mutex::new(None),
+            stalls: AtomicU64::new(0),
+        }
+    }
+
+    // How often check() needs to be called to notice a stall in time.
+    pub fn period(&self) -> Duration {
+        (self.timeout / 2).max(Duration::from_millis(1))
+    }
+
+    pub fn start(&self, addr: u64) {
+        *self.current.lock().unwrap() = Some(InProcess {
+            addr,
+            start: Instant::now(),
+            reported: false,
+        });
+    }
+
+    pub fn stop(&self, domid: u16) {
+        if let Some(req) = self.current.lock().unwrap().take() {
+            if req.reported {
+                println!(
+                    "Guest {}: Stalled ioreq at {:#x} completed after {:?}",
+                    domid,
+                    req.addr,
+                    req.start.elapsed()
+                );
+            }
+        }
+    }
+
+    // Warns once for an ioreq that is in process for longer than the timeout.
+    pub fn check(&self, domid: u16) {
+        if let Some(req) = self.current.lock().unwrap().as_mut() {
+            if !req.reported && req.start.elapsed() >= self.timeout {
+                req.reported = true;
+                self.stalls.fetch_add(1, Ordering::Relaxed);
+                println!(
+                    "Guest {}: Ioreq at {:#x} in process for over {:?}, the vcpu is stalled",
+                    domid, req.addr, self.timeout
+                );
+            }
+        }
+    }
+
+    // Number of ioreqs that were reported as stalled.
+    pub fn stalls(&self) -> u64 {
+        self.stalls.load(Ordering::Relaxed)
+    }
+}
+
+// Takes the pending ioreq from the source, passes it to `copy` if it is an MMIO access and
+// completes it. Returns what `copy` returned, if it was called.
+pub fn handle_ioreq<S, T, F>(source: &mut S, copy: F) -> Result<Option<T>>
+where
+    S: IoreqSource,
+    F: FnOnce(&mut ioreq) -> Option<T>,
+{
+    // The port is unmasked right away, and must stay so, as Xen only notifies it again for the next
+    // ioreq once it is unmasked. An ioreq made ready after this raises a new event.
+    let (port, ioreq) = source.pending()?;
+
+    if ioreq.state() != STATE_IOREQ_READY as u8 {
+        // Memory barrier
+        fence(Ordering::SeqCst);
+
+        // Xen sets the state before notifying the port, an ioreq that still isn't ready is a
+        // spurious event and is left to Xen.
+        if ioreq.state() != STATE_IOREQ_READY as u8 {
+            println!(
+                "Spurious ioreq event on port {}, state {}",
+                port,
+                ioreq.state()
+            );
+            return Ok(None);
+        }
+    }
+
+    // Memory barrier
+    fence(Ordering::SeqCst);
+
+    ioreq.set_state(STATE_IOREQ_INPROCESS as u8);
+
+    // The ioreq must be completed irrespective of what happened to it, to not leave the vcpu
+    // waiting forever.
+    let result = match ioreq.type_ as u32 {
+        IOREQ_TYPE_COPY => copy(ioreq),
+
+        IOREQ_TYPE_INVALIDATE => {
+            println!("Invalidate Ioreq type is Not implemented");
+            None
+        }
+        t => {
+            println!("Ioreq type unknown: {}", t);
+            None
+        }
+    };
+
+    // Memory barrier
+    fence(Ordering::SeqCst);
+
+    ioreq.set_state(STATE_IORESP_READY as u8);
+
+    // Memory barrier
+    fence(Ordering::SeqCst);
+
+    source.complete(port)?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::zeroed;
+    use xen_bindings::bindings::STATE_IOREQ_NONE;
+
+    const PORT: u32 = 7;
+
+    // The ioreq of a single vcpu, raised on PORT.
+    struct MockSource {
+        ioreq: ioreq,
+        completed: Vec<u32>,
+    }
+
+    impl MockSource {
+        fn new(type_: u32, state: u32) -> Self {
+            // SAFETY: ioreq is plain data, all zeroes is a valid value.
+            let mut ioreq: ioreq = unsafe { zeroed() };
+            ioreq.type_ = type_ as u8;
+            ioreq.set_state(state as u8);
+
+            Self {
+                ioreq,
+                completed: Vec::new(),
+            }
+        }
+    }
+
+    impl IoreqSource for MockSource {
+        fn pending(&mut self) -> Result<(u32, &mut ioreq)> {
+            Ok((PORT, &mut self.ioreq))
+        }
+
+        fn complete(&mut self, port: u32) -> Result<()> {
+            self.completed.push(port);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_copy() {
+        let mut source = MockSource::new(IOREQ_TYPE_COPY, STATE_IOREQ_READY);
+
+        let result = handle_ioreq(&mut source, |ioreq| {
+            assert_eq!(ioreq.state(), STATE_IOREQ_INPROCESS as u8);
+            ioreq.data = 0x1234;
+            Some(42)
+        });
+
+        assert_eq!(result.unwrap(), Some(42));
+        assert_eq!(source.ioreq.data, 0x1234);
+        assert_eq!(source.ioreq.state(), STATE_IORESP_READY as u8);
+        assert_eq!(source.completed, [PORT]);
+    }
+
+    // Ioreqs that aren't MMIO accesses are completed without being handled.
+    #[test]
+    fn test_other_types() {
+        for type_ in [IOREQ_TYPE_INVALIDATE, 0xff] {
+            let mut source = MockSource::new(type_, STATE_IOREQ_READY);
+            let mut copied = false;
+
+            let result = handle_ioreq(&mut source, |_| {
+                copied = true;
+                Some(())
+            });
+
+            assert_eq!(result.unwrap(), None);
+            assert!(!copied);
+            assert_eq!(source.ioreq.state(), STATE_IORESP_READY as u8);
+            assert_eq!(source.completed, [PORT]);
+        }
+    }
+
+    // Spurious events leave the ioreq to Xen, untouched and not completed.
+    #[test]
+    fn test_not_ready() {
+        for state in [STATE_IOREQ_NONE, STATE_IOREQ_INPROCESS, STATE_IORESP_READY] {
+            let mut source = MockSource::new(IOREQ_TYPE_COPY, state);
+            let mut copied = false;
+
+            let result = handle_ioreq(&mut source, |_| {
+                copied = true;
+                Some(())
+            });
+
+            assert_eq!(result.unwrap(), None);
+            assert!(!copied);
+            assert_eq!(source.ioreq.state(), state as u8);
+            assert!(source.completed.is_empty());
+        }
+    }
+}
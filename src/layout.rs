@@ -0,0 +1,162 @@
+// Copyright 2022-2023 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// This file describes where the guest RAM lives in the guest physical address space. Xen places
+// the RAM of ARM guests in fixed banks (see xen/include/public/arch-arm.h), filled in order, and
+// doesn't expose the layout to device models otherwise. The toolstack lays out the RAM of x86 HVM
+// guests the same way, around the legacy VGA hole and the MMIO hole below 4GB (see
+// tools/libs/light/libxl_dom.c and tools/firmware/hvmloader/config.h), with the default hole size.
+//
+// The layout can be given on the command line instead, for guests laid out differently.
+
+use std::convert::TryFrom;
+
+use super::{Error, Result};
+
+#[cfg(not(any(target_arch = "aarch64", target_arch = "x86_64")))]
+compile_error!("The guest RAM layout is only known for aarch64 and x86_64");
+
+// (Base, Size) of the RAM banks.
+#[cfg(target_arch = "aarch64")]
+const GUEST_RAM_BANKS: [(u64, usize); 2] = [
+    (0x40000000, 0xc0000000),     // 3GB of low RAM @ 1GB
+    (0x0200000000, 0xfe00000000), // 1016GB of RAM @ 8GB
+];
+
+#[cfg(target_arch = "x86_64")]
+const GUEST_RAM_BANKS: [(u64, usize); 3] = [
+    (0x0, 0xa0000),               // 640KB of RAM below the VGA hole
+    (0xc0000, 0xeff40000),        // RAM up to the MMIO hole @ 3.75GB
+    (0x0100000000, 0xff00000000), // 1020GB of RAM @ 4GB
+];
+
+pub struct GuestRamLayout {
+    banks: Vec<(u64, usize)>,
+}
+
+// Parses a number, in hex with the 0x prefix or in decimal.
+fn parse_num(val: &str) -> Option<u64> {
+    match val.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => val.parse().ok(),
+    }
+}
+
+// Parses the RAM banks from "<base>:<size>[,<base>:<size>..]", in increasing order of address and
+// not overlapping.
+pub fn parse_banks(banks: &str) -> Result<Vec<(u64, usize)>> {
+    let invalid = || Error::InvalidRamBanks(banks.to_string());
+    let mut parsed: Vec<(u64, usize)> = Vec::new();
+    let mut end = 0;
+
+    for bank in banks.split(',') {
+        let (base, size) = bank.split_once(':').ok_or_else(invalid)?;
+        let base = parse_num(base).ok_or_else(invalid)?;
+        let size = parse_num(size)
+            .filter(|size| *size != 0)
+            .ok_or_else(invalid)?;
+
+        if base < end {
+            return Err(invalid());
+        }
+
+        end = base.checked_add(size).ok_or_else(invalid)?;
+        parsed.push((base, usize::try_from(size).map_err(|_| invalid())?));
+    }
+
+    Ok(parsed)
+}
+
+impl GuestRamLayout {
+    pub fn new(size: usize) -> Self {
+        Self::with_banks(size, &GUEST_RAM_BANKS)
+    }
+
+    // Lays out the RAM over the banks, filled in order.
+    pub fn with_banks(mut size: usize, ram_banks: &[(u64, usize)]) -> Self {
+        let mut banks = Vec::new();
+
+        for &(base, bank_size) in ram_banks {
+            if size == 0 {
+                break;
+            }
+
+            let len = size.min(bank_size);
+            banks.push((base, len));
+            size -= len;
+        }
+
+        Self { banks }
+    }
+
+    // Returns if the range lies within a single RAM bank.
+    pub fn contains(&self, addr: u64, size: usize) -> bool {
+        self.banks.iter().any(|&(base, len)| {
+            addr >= base
+                && (addr - base)
+                    .checked_add(size as u64)
+                    .map_or(false, |end| end <= len as u64)
+        })
+    }
+
+    // Splits the banks in chunks of at most `chunk_size` bytes.
+    pub fn chunks(&self, chunk_size: usize) -> Vec<(u64, usize)> {
+        self.banks
+            .iter()
+            .flat_map(|&(base, size)| {
+                (0..size)
+                    .step_by(chunk_size)
+                    .map(move |offset| (base + offset as u64, chunk_size.min(size - offset)))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_banks() {
+        assert_eq!(
+            parse_banks("0x40000000:0x40000000").unwrap(),
+            [(0x40000000, 0x40000000)]
+        );
+        assert_eq!(
+            parse_banks("0:655360,0x100000:1048576").unwrap(),
+            [(0, 0xa0000), (0x100000, 0x100000)]
+        );
+
+        for banks in [
+            "",
+            "0x1000",
+            "0x1000:0",
+            "0x1000:zz",
+            "0x2000:0x1000,0x1000:0x1000",
+            "0x1000:0x2000,0x2000:0x1000",
+            "0xffffffffffffffff:0x2",
+        ] {
+            assert!(
+                matches!(parse_banks(banks), Err(Error::InvalidRamBanks(_))),
+                "{}",
+                banks
+            );
+        }
+    }
+
+    #[test]
+    fn test_with_banks() {
+        let banks = [(0x1000, 0x1000), (0x10000, 0x4000)];
+        let layout = GuestRamLayout::with_banks(0x3000, &banks);
+
+        assert_eq!(
+            layout.chunks(0x1000),
+            [(0x1000, 0x1000), (0x10000, 0x1000), (0x11000, 0x1000)]
+        );
+        assert!(layout.contains(0x10000, 0x2000));
+        assert!(!layout.contains(0x10000, 0x2001));
+        assert!(!layout.contains(0x1800, 0x1000));
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright 2022-2023 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Xen vhost frontend, connects virtio-mmio devices of Xen guests to vhost-user backends.
+//!
+//! The frontend can be embedded in a bigger program, which is what the xen-vhost-frontend binary
+//! does as well:
+//!
+//! ```no_run
+//! use clap::Parser;
+//! use xen_vhost_frontend::{DeviceArgs, XenFrontend};
+//!
+//! let args = DeviceArgs::parse_from(["xen-vhost-frontend", "--socket-path", "/root/"]);
+//! let frontend = XenFrontend::new(args).unwrap();
+//!
+//! frontend.run().unwrap();
+//! ```
+
+mod backend;
+mod control;
+mod device;
+mod epoll;
+mod frontend;
+mod guest;
+mod interrupt;
+mod ioreq;
+mod layout;
+mod mmio;
+mod status;
+#[cfg(any(test, feature = "stubs"))]
+pub mod stubs;
+mod supported_devices;
+mod trace;
+mod xdm;
+mod xec;
+mod xfm;
+mod xs;
+
+use std::{io, num::ParseIntError, str};
+
+pub use device::{check_backends, DeviceArgs, DeviceSnapshot, InterruptMode, XenDevice};
+pub use frontend::XenFrontend;
+pub use guest::{GuestSnapshot, XenGuest};
+pub use mmio::{MmioSnapshot, QueueStats};
+use xs::XsHandle;
+
+pub const BACKEND_PATH: &str = "backend/virtio";
+
+/// Result for xen-vhost-frontend operations
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Error codes for xen-vhost-frontend operations
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Invalid Domain info, len {0:?}, domid expected {1:?} info length {2:?}")]
+    InvalidDomainInfo(usize, u16, usize),
+    #[error("Domain {0:} memory size didn't settle")]
+    DomainSizeUnstable(u16),
+    #[error("Domain {0:} has too few pages for its RAM: {1:}")]
+    InvalidDomainPages(u16, u64),
+    #[error("Invalid MMIO {0:} Address {1:?}")]
+    InvalidMmioAddr(&'static str, u64),
+    #[error("MMIO Legacy not supported by Guest")]
+    MmioLegacyNotSupported,
+    #[error("Grant mapping needs the guest to use IOMMU, check its DT iommu nodes and Kconfig")]
+    GrantRequiresIommu,
+    #[error("Invalid feature select {0:}")]
+    InvalidFeatureSel(u32),
+    #[error("Invalid queue size {0:}, must be a power of two up to {1:}")]
+    InvalidQueueSize(u32, u32),
+    #[error("Invalid number of queues {0:}")]
+    InvalidQueueNum(u32),
+    #[error("Invalid queue index {0:}")]
+    InvalidQueueIndex(u32),
+    #[error("Invalid MMIO direction {0:}")]
+    InvalidMmioDir(u8),
+    #[error("Invalid MMIO access size {0:}")]
+    InvalidMmioSize(u32),
+    #[error("Config space at offset {0:#x} is read-only")]
+    ConfigReadOnly(u64),
+    #[error("Invalid irq {0:}")]
+    InvalidIrq(u32),
+    #[error("Device not supported: {0:}")]
+    XenDevNotSupported(String),
+    #[error("Unknown device name: {0:}")]
+    XenDevUnknownName(String),
+    #[error("Event on unknown port {0:}")]
+    UnknownEventPort(u32),
+    #[error("Xen foreign memory failure")]
+    XenForeignMemoryFailure,
+    #[error("I/O error: {0:?}")]
+    IoError(#[from] io::Error),
+    #[error("Failed to open Xen device node: {0:?}")]
+    XenDeviceNodeOpen(io::Error),
+    #[error("Address {0:#x} isn't a grant address, check the guest's DT iommu nodes and Kconfig")]
+    InvalidGrantAddr(u64),
+    #[error("Failed to open MMIO trace file: {0:?}")]
+    MmioTrace(io::Error),
+    #[error("Failed to set up control socket: {0:?}")]
+    ControlSocket(io::Error),
+    #[error("Invalid backend path {0:?}, must be non-empty and not end with /")]
+    InvalidBackendPath(String),
+    #[error("Invalid mapping {0:}, must be foreign or grant")]
+    InvalidMapping(String),
+    #[error("Virtqueue {0:} at {1:#x} isn't within guest memory")]
+    InvalidRingAddr(&'static str, u64),
+    #[error("Failed to map memory region at {0:#x}")]
+    RegionMmap(u64),
+    #[error("Guest memory regions are missing, overlap or are out of order")]
+    InvalidMemoryRegions,
+    #[error("Vhost user frontend error")]
+    VhostFrontendError(#[from] vhost_user_frontend::Error),
+    #[error("Vhost user frontend activate error")]
+    VhostFrontendActivateError(#[from] vhost_user_frontend::ActivateError),
+    #[error("Failed to reconnect to vhost user backend")]
+    VhostFrontendReconnectFailed,
+    #[error("Vhost user backend socket {0:} not found")]
+    BackendSocketMissing(String),
+    #[error("Virtqueue error: {0:?}")]
+    VirtQueueError(virtio_queue::Error),
+    #[error("Invalid String: {0:?}")]
+    InvalidString(#[from] str::Utf8Error),
+    #[error("Failed while parsing to integer: {0:?}")]
+    ParseFailure(#[from] ParseIntError),
+    #[error(
+        "Invalid RAM banks: {0:}, must be <base>:<size>[,<base>:<size>..] in increasing order"
+    )]
+    InvalidRamBanks(String),
+    #[error("Invalid guest cpus: {0:}")]
+    InvalidGuestCpus(String),
+    #[error("Failed to set up signalfd: {0:?}")]
+    SignalFd(io::Error),
+    #[error("Failed to create epoll context: {0:?}")]
+    EpollCreateFd(io::Error),
+    #[error("Failed to open XS file")]
+    FileOpenFailed,
+    #[error("Failed to add event to epoll: {0:?}")]
+    RegisterExitEvent(io::Error),
+    #[error("Failed to remove event from epoll: {0:?}")]
+    UnregisterEvent(io::Error),
+    #[error("Failed while waiting on epoll: {0:?}")]
+    EpollWait(io::Error),
+    #[error("Failed to read Xenstore node {0:}: {1:?}")]
+    XsRead(String, io::Error),
+    #[error("Failed to write Xenstore node {0:}: {1:?}")]
+    XsWrite(String, io::Error),
+    #[error("Failed to watch Xenstore path {0:}: {1:?}")]
+    XsWatch(String, io::Error),
+    #[error("Device {0:} is served by another frontend already, pid {1:}")]
+    DeviceAlreadyOwned(String, String),
+    #[error("Guest {0:} was removed while adding its device")]
+    GuestRemoved(u16),
+    #[error("Device {0:} / {1:} was removed while being added")]
+    DeviceRemoved(u16, u32),
+    #[error("Xen Bus Invalid State")]
+    XBInvalidState,
+    #[error("Timed out waiting for Xen Bus state, last state {0:}")]
+    XBStateTimeout(u32),
+    #[error("Failed to kick backend: {0:?}")]
+    EventFdWriteFailed(io::Error),
+    #[error("Some backends aren't reachable")]
+    BackendCheckFailed,
+}
+
+impl Error {
+    // Errors after which a device can't make any progress, like losing its backend. A failed
+    // activation isn't one of them, the driver is asked to reset the device and try again.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Error::VhostFrontendError(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(val: &str) -> Result<u32> {
+        Ok(val.parse::<u32>()?)
+    }
+
+    fn utf8(bytes: &[u8]) -> Result<&str> {
+        Ok(str::from_utf8(bytes)?)
+    }
+
+    fn io(kind: io::ErrorKind) -> Result<()> {
+        let result: io::Result<()> = Err(kind.into());
+        Ok(result?)
+    }
+
+    #[test]
+    fn test_from_io_error() {
+        let e = io(io::ErrorKind::NotFound).unwrap_err();
+
+        assert!(matches!(&e, Error::IoError(e) if e.kind() == io::ErrorKind::NotFound));
+        assert!(e.to_string().starts_with("I/O error: "));
+    }
+
+    #[test]
+    fn test_from_parse_error() {
+        assert_eq!(parse("42").unwrap(), 42);
+        assert!(matches!(parse("0x2a"), Err(Error::ParseFailure(_))));
+        assert!(matches!(parse(""), Err(Error::ParseFailure(_))));
+    }
+
+    #[test]
+    fn test_from_utf8_error() {
+        assert_eq!(utf8(b"virtio").unwrap(), "virtio");
+        assert!(matches!(utf8(&[0xff]), Err(Error::InvalidString(_))));
+    }
+}
@@ -3,54 +3,254 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::fs::OpenOptions;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::fs::File;
+use std::mem::size_of;
+use std::sync::{atomic::Ordering, Arc};
+use std::thread;
+use std::time::Instant;
 
 use vhost::vhost_user::message::{VhostUserProtocolFeatures, VHOST_USER_CONFIG_OFFSET};
-use vhost_user_frontend::{Generic, VirtioDevice};
 use vhost_user_frontend::{GuestMemoryMmap, GuestRegionMmap};
-use virtio_bindings::virtio_config::{VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1};
+use vhost_user_frontend::{VirtioInterrupt, VirtioInterruptType};
+use virtio_bindings::virtio_config::{
+    VIRTIO_CONFIG_S_DRIVER_OK, VIRTIO_CONFIG_S_NEEDS_RESET, VIRTIO_F_IOMMU_PLATFORM,
+    VIRTIO_F_VERSION_1,
+};
 use virtio_bindings::virtio_mmio::{
     VIRTIO_MMIO_CONFIG_GENERATION, VIRTIO_MMIO_DEVICE_FEATURES, VIRTIO_MMIO_DEVICE_FEATURES_SEL,
     VIRTIO_MMIO_DEVICE_ID, VIRTIO_MMIO_DRIVER_FEATURES, VIRTIO_MMIO_DRIVER_FEATURES_SEL,
-    VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INTERRUPT_STATUS, VIRTIO_MMIO_INT_VRING,
-    VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_QUEUE_AVAIL_HIGH, VIRTIO_MMIO_QUEUE_AVAIL_LOW,
-    VIRTIO_MMIO_QUEUE_DESC_HIGH, VIRTIO_MMIO_QUEUE_DESC_LOW, VIRTIO_MMIO_QUEUE_NOTIFY,
-    VIRTIO_MMIO_QUEUE_NUM, VIRTIO_MMIO_QUEUE_NUM_MAX, VIRTIO_MMIO_QUEUE_READY,
-    VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_QUEUE_USED_HIGH, VIRTIO_MMIO_QUEUE_USED_LOW,
-    VIRTIO_MMIO_STATUS, VIRTIO_MMIO_VENDOR_ID, VIRTIO_MMIO_VERSION,
+    VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INTERRUPT_STATUS, VIRTIO_MMIO_MAGIC_VALUE,
+    VIRTIO_MMIO_QUEUE_AVAIL_HIGH, VIRTIO_MMIO_QUEUE_AVAIL_LOW, VIRTIO_MMIO_QUEUE_DESC_HIGH,
+    VIRTIO_MMIO_QUEUE_DESC_LOW, VIRTIO_MMIO_QUEUE_NOTIFY, VIRTIO_MMIO_QUEUE_NUM,
+    VIRTIO_MMIO_QUEUE_NUM_MAX, VIRTIO_MMIO_QUEUE_READY, VIRTIO_MMIO_QUEUE_SEL,
+    VIRTIO_MMIO_QUEUE_USED_HIGH, VIRTIO_MMIO_QUEUE_USED_LOW, VIRTIO_MMIO_STATUS,
+    VIRTIO_MMIO_VENDOR_ID, VIRTIO_MMIO_VERSION,
 };
-use virtio_bindings::virtio_ring::{__virtio16, vring_avail, vring_used, vring_used_elem};
-use virtio_queue::{Descriptor, Queue, QueueT};
-use vm_memory::ByteValued;
-use vm_memory::{
-    guest_memory::FileOffset, GuestAddress, GuestMemoryAtomic, GuestMemoryRegion, MmapRange,
-    MmapRegion, MmapXenFlags,
+use virtio_bindings::virtio_ring::{
+    __virtio16, vring_avail, vring_used, vring_used_elem, VIRTIO_RING_F_EVENT_IDX,
 };
+use virtio_queue::{Descriptor, Queue, QueueT};
+use vm_memory::{GuestAddress, GuestMemoryAtomic, GuestMemoryRegion, MmapXenFlags};
 
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
-use super::{device::XenDevice, guest::XenGuest, Error, Result};
-use xen_bindings::bindings::{ioreq, IOREQ_READ, IOREQ_WRITE, XC_PAGE_SHIFT, XC_PAGE_SIZE};
-use xen_ioctls::xc_domain_info;
+use super::{
+    backend::Backend, device::XenDevice, guest::XenGuest, supported_devices::writable_config,
+    xfm::MemoryMapper, Error, Result,
+};
+use xen_bindings::bindings::{
+    ioreq, xenbus_state_XenbusStateConnected, IOREQ_READ, IOREQ_WRITE, XC_PAGE_SIZE,
+};
+
+// Added in VIRTIO 1.2, not part of virtio-bindings yet.
+const VIRTIO_MMIO_QUEUE_RESET: u32 = 0x0c0;
+
+// Added in VIRTIO 1.1, not part of virtio-bindings yet.
+const VIRTIO_F_NOTIFICATION_DATA: u32 = 38;
+
+// Shared memory region registers.
+const VIRTIO_MMIO_SHM_SEL: u32 = 0x0ac;
+const VIRTIO_MMIO_SHM_LEN_LOW: u32 = 0x0b0;
+const VIRTIO_MMIO_SHM_LEN_HIGH: u32 = 0x0b4;
+const VIRTIO_MMIO_SHM_BASE_LOW: u32 = 0x0b8;
+const VIRTIO_MMIO_SHM_BASE_HIGH: u32 = 0x0bc;
+const FOREIGN_CHUNK_SIZE: usize = 1 << 30; // Foreign memory is mapped in chunks of 1GB
+
+// Protocol features the frontend can work with, Generic::negotiate_features() acks the ones the
+// backend supports out of these. SLAVE_REQ and INFLIGHT_SHMFD are left out, as nothing here keeps
+// inflight buffers across reconnects, and Generic doesn't set up the channel for the backend's
+// requests. Config changes reported over it would reach the guest through XenInterrupt::trigger().
+const FRONTEND_PROTOCOL_FEATURES: VhostUserProtocolFeatures =
+    VhostUserProtocolFeatures::from_bits_truncate(
+        VhostUserProtocolFeatures::MQ.bits()
+            | VhostUserProtocolFeatures::CONFIG.bits()
+            | VhostUserProtocolFeatures::REPLY_ACK.bits()
+            | VhostUserProtocolFeatures::XEN_MMAP.bits(),
+    );
+
+// A guest access to the device's MMIO region.
+#[derive(Debug, PartialEq)]
+enum MmioAccess {
+    // Virtio register, at the offset from the base address, and the access size.
+    RegRead(u64, usize),
+    RegWrite(u64, usize),
+    // Device config space, at the offset from the start of config space, and the access size.
+    ConfigRead(u64, usize),
+    ConfigWrite(u64, usize),
+}
+
+fn size_mask(size: usize) -> u32 {
+    (u64::MAX >> (64 - size * 8)) as u32
+}
+
+// Decodes an access with the offset from the base address, size and direction of the ioreq. All
+// the values come from the guest, nothing is assumed about them.
+fn decode_mmio(offset: u64, size: u32, dir: u8) -> Result<MmioAccess> {
+    let write = match dir as u32 {
+        IOREQ_READ => false,
+        IOREQ_WRITE => true,
+        _ => return Err(Error::InvalidMmioDir(dir)),
+    };
+
+    if offset < VHOST_USER_CONFIG_OFFSET as u64 {
+        // Registers are 32 bits wide, narrower accesses must stay within a single register.
+        if !matches!(size, 1 | 2 | 4) || (offset & 3) + size as u64 > 4 {
+            return Err(Error::InvalidMmioSize(size));
+        }
 
-const GUEST_RAM0_BASE: u64 = 0x40000000; // 3GB of low RAM @ 1GB
-const XEN_GRANT_ADDR_OFF: u64 = 1 << 63;
+        return Ok(if write {
+            MmioAccess::RegWrite(offset, size as usize)
+        } else {
+            MmioAccess::RegRead(offset, size as usize)
+        });
+    }
 
-fn get_dom_size(domid: u16) -> Result<usize> {
-    let info = xc_domain_info(domid, 1);
+    // Config space is accessed byte by byte, any width the ioreq data can hold is fine.
+    let size = match size {
+        1 | 2 | 4 | 8 => size as usize,
+        _ => return Err(Error::InvalidMmioSize(size)),
+    };
 
-    if info.len() != 1 {
-        Err(Error::InvalidDomainInfo(info.len(), domid, 0))
-    } else if info[0].domid != domid {
-        Err(Error::InvalidDomainInfo(
-            info.len(),
-            domid,
-            info[0].domid as usize,
-        ))
+    let offset = offset - VHOST_USER_CONFIG_OFFSET as u64;
+    Ok(if write {
+        MmioAccess::ConfigWrite(offset, size)
     } else {
-        Ok((info[0].nr_pages as usize - 4) << XC_PAGE_SHIFT)
+        MmioAccess::ConfigRead(offset, size)
+    })
+}
+
+// Maps the (address, size) chunks with `map`, from one thread per cpu at most, each of them taking
+// a run of consecutive chunks. Returns the mappings in the order of the chunks.
+fn map_chunks<T, F>(chunks: &[(u64, usize)], map: F) -> Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(u64, usize) -> Result<T> + Sync,
+{
+    if chunks.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let workers = thread::available_parallelism().map_or(1, |n| n.get());
+    let per_worker = (chunks.len() + workers - 1) / workers;
+    let map = &map;
+
+    thread::scope(|s| {
+        let handles: Vec<_> = chunks
+            .chunks(per_worker)
+            .map(|run| {
+                s.spawn(move || {
+                    run.iter()
+                        .map(|&(addr, size)| map(addr, size))
+                        .collect::<Result<Vec<T>>>()
+                })
+            })
+            .collect();
+
+        let mut mappings = Vec::with_capacity(chunks.len());
+        for handle in handles {
+            mappings.extend(handle.join().unwrap()?);
+        }
+
+        Ok(mappings)
+    })
+}
+
+// Rounds the address up to the next page boundary.
+fn page_align(addr: u64) -> u64 {
+    let mask = XC_PAGE_SIZE as u64 - 1;
+    (addr + mask) & !mask
+}
+
+// Where the grant regions fail to cover the guest's memory, see grant_coverage_gaps().
+#[derive(Debug, PartialEq)]
+enum CoverageGap {
+    // A region starting at the address, below the end of the previous ones.
+    Overlap(u64, u64),
+    // A range left unmapped between two regions.
+    Hole(u64, u64),
+    // The regions end at the address, short of or past the guest's memory.
+    End(u64),
+}
+
+// Walks the regions, sorted by start address, covering start..end. Holes up to the end of a
+// page are fine, the next region starts at the following page then.
+fn grant_coverage_gaps(regions: &[(u64, usize)], start: u64, end: u64) -> Vec<CoverageGap> {
+    let mut gaps = Vec::new();
+    let mut covered = start;
+
+    for &(addr, len) in regions {
+        if addr < covered {
+            gaps.push(CoverageGap::Overlap(addr, covered));
+        } else if addr > page_align(covered) {
+            gaps.push(CoverageGap::Hole(covered, addr));
+        }
+
+        covered = covered.max(addr.saturating_add(len as u64));
+    }
+
+    if page_align(covered) != end {
+        gaps.push(CoverageGap::End(covered));
+    }
+
+    gaps
+}
+
+// The ring sizes below rely on the headers of the avail and used rings being just flags and idx,
+// with the flexible ring[] arrays not adding to the size.
+const _: () = assert!(size_of::<vring_avail>() == 4 && size_of::<vring_used>() == 4);
+
+// Sizes of the split virtqueue parts, as laid out in section 2.7 of the VIRTIO 1.2 spec.
+fn desc_table_size(vq_size: usize) -> usize {
+    vq_size * size_of::<Descriptor>()
+}
+
+// flags, idx, ring[vq_size] and used_event.
+fn avail_ring_size(vq_size: usize, event_idx: bool) -> usize {
+    let mut size = size_of::<vring_avail>() + vq_size * size_of::<__virtio16>();
+
+    // used_event is placed right after the ring.
+    if event_idx {
+        size += size_of::<__virtio16>();
+    }
+
+    size
+}
+
+// flags, idx, ring[vq_size] and avail_event.
+fn used_ring_size(vq_size: usize, event_idx: bool) -> usize {
+    let mut size = size_of::<vring_used>() + vq_size * size_of::<vring_used_elem>();
+
+    // avail_event is placed right after the ring.
+    if event_idx {
+        size += size_of::<__virtio16>();
     }
+
+    size
+}
+
+// Statistics of a virtqueue. Kicks normally go straight from the guest to the backend through the
+// ioeventfd, only the ones that reach the frontend can be counted. The backlog of the backend is
+// read from the rings instead, as the buffers made available by the guest and not used yet.
+#[derive(Clone)]
+pub struct QueueStats {
+    pub ready: bool,
+    pub size: u32,
+    pub pending: Option<u16>,
+    pub kicks: u64,
+    pub last_kick: Option<Instant>,
+    // Used buffer notifications from the backend.
+    pub calls: u64,
+}
+
+// Guest visible state of the device, and the guest memory mapped for it as (address, size).
+pub struct MmioSnapshot {
+    pub status: u32,
+    pub driver_features: u64,
+    pub queue_sel: u32,
+    pub activated: bool,
+    pub queues: Vec<QueueStats>,
+    pub regions: Vec<(u64, u64)>,
 }
 
 struct VirtQueue {
@@ -63,64 +263,161 @@ struct VirtQueue {
     avail_hi: u32,
     used_lo: u32,
     used_hi: u32,
+    queue_reset: u32,
+    kicks: u64,
+    last_kick: Option<Instant>,
+    // Backlog over the threshold when last checked.
+    backlogged: bool,
 
     // Guest to device
     kick: EventFd,
 }
 
+impl VirtQueue {
+    // The guest may enable a queue it hasn't set up, or only partly.
+    fn queue(&self) -> Result<Queue> {
+        let desc = ((self.desc_hi as u64) << 32) | self.desc_lo as u64;
+        let avail = ((self.avail_hi as u64) << 32) | self.avail_lo as u64;
+        let used = ((self.used_hi as u64) << 32) | self.used_lo as u64;
+
+        for (name, addr) in [
+            ("descriptor table", desc),
+            ("available ring", avail),
+            ("used ring", used),
+        ] {
+            if addr == 0 {
+                return Err(Error::InvalidRingAddr(name, addr));
+            }
+        }
+
+        let mut queue = Queue::new(self.size as u16)
+            .map_err(|_| Error::InvalidQueueSize(self.size, self.size_max))?;
+        queue.set_desc_table_address(Some((desc & 0xFFFFFFFF) as u32), Some((desc >> 32) as u32));
+        queue.set_avail_ring_address(
+            Some((avail & 0xFFFFFFFF) as u32),
+            Some((avail >> 32) as u32),
+        );
+        queue.set_used_ring_address(Some((used & 0xFFFFFFFF) as u32), Some((used >> 32) as u32));
+        queue.set_next_avail(0);
+        Ok(queue)
+    }
+
+    fn pending(&self, mem: &GuestMemoryMmap) -> Result<u16> {
+        let queue = self.queue()?;
+        let avail = queue
+            .avail_idx(mem, Ordering::Acquire)
+            .map_err(Error::VirtQueueError)?;
+        let used = queue
+            .used_idx(mem, Ordering::Acquire)
+            .map_err(Error::VirtQueueError)?;
+
+        Ok(avail.0.wrapping_sub(used.0))
+    }
+
+    fn stats(&self, mem: Option<&GuestMemoryMmap>) -> QueueStats {
+        QueueStats {
+            ready: self.ready == 1,
+            size: self.size,
+            pending: match mem {
+                Some(mem) if self.ready == 1 => self.pending(mem).ok(),
+                _ => None,
+            },
+            kicks: self.kicks,
+            last_kick: self.last_kick,
+            calls: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.ready = 0;
+        self.size = 0;
+        self.desc_lo = 0;
+        self.desc_hi = 0;
+        self.avail_lo = 0;
+        self.avail_hi = 0;
+        self.used_lo = 0;
+        self.used_hi = 0;
+        self.backlogged = false;
+    }
+}
+
 pub struct XenMmio {
     addr: u64,
     magic: [u8; 4],
     version: u8,
     vendor_id: u32,
+    device_id: u32,
     status: u32,
     queue_sel: u32,
+    // Features offered to the guest, read from the backend when it connects.
+    device_features: u64,
     device_features_sel: u32,
     driver_features: u64,
     driver_features_sel: u32,
-    interrupt_state: u32,
-    queues_count: usize,
+    notification_data: bool,
+    activated: bool,
     queues: Vec<(usize, Queue, EventFd)>,
     vq: Vec<VirtQueue>,
-    regions: Vec<GuestRegionMmap>,
+    // Authoritative list of the mapped regions, the guest memory handed over to the backend only
+    // holds references to these.
+    regions: Vec<Arc<GuestRegionMmap>>,
+    // The memory handed over to the backend, while it is activated.
+    memory: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
+    files: HashMap<String, Arc<File>>,
+    mapper: Arc<dyn MemoryMapper>,
     foreign_mapping: bool,
+    grant_addr_off: u64,
+    allow_legacy: bool,
+    backlog_threshold: Option<u16>,
     guest_size: usize,
     guest: Arc<XenGuest>,
 }
 
 impl XenMmio {
     pub fn new(
-        gdev: &Generic,
+        gdev: &dyn Backend,
         guest: Arc<XenGuest>,
         addr: u64,
+        device_id: u32,
         foreign_mapping: bool,
+        max_queue_size: Option<u32>,
+        guest_size: usize,
     ) -> Result<Self> {
         let sizes = gdev.queue_max_sizes();
-        let guest_size = get_dom_size(guest.fe_domid)?;
 
         let mut mmio = Self {
             addr,
             magic: [b'v', b'i', b'r', b't'],
             version: 2,
             vendor_id: 0x4d564b4c,
+            device_id,
             status: 0,
             queue_sel: 0,
+            device_features: 0,
             device_features_sel: 0,
             driver_features: 0,
             driver_features_sel: 0,
-            interrupt_state: 0,
-            queues_count: sizes.len(),
+            notification_data: false,
+            activated: false,
             queues: Vec::with_capacity(sizes.len()),
             vq: Vec::new(),
             regions: Vec::new(),
+            memory: None,
+            files: HashMap::new(),
+            mapper: guest.xfm.lock().unwrap().mapper(),
             foreign_mapping,
+            grant_addr_off: guest.ctx.args.grant_addr_offset,
+            allow_legacy: guest.ctx.args.allow_legacy,
+            backlog_threshold: guest.ctx.args.backlog_threshold,
             guest_size,
             guest: guest.clone(),
         };
+        mmio.device_features = mmio.offered_features(gdev);
 
+        // Lock order: xec, xfm and then xdm, see working.md.
+        let xec = guest.xec.lock().unwrap();
         let xfm = guest.xfm.lock().unwrap();
         let ioreq = xfm.ioreq(0).unwrap();
-        let xec = guest.xec.lock().unwrap();
 
         for (index, size) in sizes.iter().enumerate() {
             let kick = EventFd::new(EFD_NONBLOCK).unwrap();
@@ -135,13 +432,17 @@ impl XenMmio {
             mmio.vq.push(VirtQueue {
                 ready: 0,
                 size: 0,
-                size_max: *size as u32,
+                size_max: max_queue_size.map_or(*size as u32, |max| max.min(*size as u32)),
                 desc_lo: 0,
                 desc_hi: 0,
                 avail_lo: 0,
                 avail_hi: 0,
                 used_lo: 0,
                 used_hi: 0,
+                queue_reset: 0,
+                kicks: 0,
+                last_kick: None,
+                backlogged: false,
                 kick,
             });
         }
@@ -155,125 +456,361 @@ impl XenMmio {
         Ok(mmio)
     }
 
-    fn config_read(&self, ioreq: &mut ioreq, gdev: &Generic, offset: u64) -> Result<()> {
-        let mut data: u64 = 0;
-        gdev.read_config(offset, &mut data.as_mut_slice()[0..ioreq.size as usize]);
-        ioreq.data = data;
+    // Config space is forwarded byte by byte, exactly [offset, offset + size) is accessed
+    // irrespective of the alignment. Virtio config space is little-endian.
+    fn config_read(&self, ioreq: &mut ioreq, gdev: &dyn Backend, offset: u64, size: usize) {
+        let mut data = [0u8; 8];
 
-        Ok(())
+        gdev.read_config(offset, &mut data[..size]);
+        ioreq.data = u64::from_le_bytes(data);
     }
 
-    fn config_write(&self, ioreq: &mut ioreq, gdev: &mut Generic, offset: u64) -> Result<()> {
-        gdev.write_config(offset, &ioreq.data.to_ne_bytes()[0..ioreq.size as usize]);
+    fn config_write(&self, ioreq: &ioreq, dev: &XenDevice, offset: u64, size: usize) -> Result<()> {
+        // Don't let the guest change the fields the backend owns.
+        match writable_config(dev.name) {
+            Some(range) if range.start <= offset && offset + size as u64 <= range.end => (),
+            _ => return Err(Error::ConfigReadOnly(offset)),
+        }
+
+        dev.gdev
+            .lock()
+            .unwrap()
+            .write_config(offset, &ioreq.data.to_le_bytes()[..size]);
+
+        // Other fields can depend on the written ones, like the virtio-input union selected by
+        // select and subsel.
+        dev.interrupt().config_changed();
         Ok(())
     }
 
-    fn io_read(&self, ioreq: &mut ioreq, dev: &XenDevice, offset: u64) -> Result<()> {
-        let vq = &self.vq[self.queue_sel as usize];
-        let gdev = dev.gdev.lock().unwrap();
+    // Access to an offset that isn't a virtio register, which usually means that the device's base
+    // address in the guest DT is wrong.
+    fn invalid_addr(&self, dir: &'static str, dev: &XenDevice, offset: u64) -> Error {
+        println!(
+            "Invalid MMIO {} for device {} / {}: offset {:#x}, base {:#x}",
+            dir, dev.guest.fe_domid, dev.dev_id, offset, self.addr
+        );
 
-        ioreq.data = match offset as u32 {
+        Error::InvalidMmioAddr(dir, offset)
+    }
+
+    // Returns the value of the register at offset, None if it can't be read.
+    fn reg_read(&self, dev: &XenDevice, offset: u64) -> Result<Option<u32>> {
+        // The driver finds out how many queues there are by selecting them in turn, until one
+        // reads QueueNumMax as 0. All the registers of a queue that doesn't exist read as 0.
+        let vq = self.vq.get(self.queue_sel as usize);
+        let queue_reg = |reg: fn(&VirtQueue) -> u32| vq.map_or(0, reg);
+
+        let val = match offset as u32 {
             VIRTIO_MMIO_MAGIC_VALUE => u32::from_le_bytes(self.magic),
             VIRTIO_MMIO_VERSION => self.version as u32,
-            VIRTIO_MMIO_DEVICE_ID => gdev.device_type(),
+            VIRTIO_MMIO_DEVICE_ID => self.device_id,
             VIRTIO_MMIO_VENDOR_ID => self.vendor_id,
             VIRTIO_MMIO_STATUS => self.status,
-            VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt_state | VIRTIO_MMIO_INT_VRING,
-            VIRTIO_MMIO_QUEUE_NUM_MAX => vq.size_max,
+            VIRTIO_MMIO_INTERRUPT_STATUS => dev.interrupt().status(),
+            VIRTIO_MMIO_QUEUE_NUM_MAX => queue_reg(|vq| vq.size_max),
             VIRTIO_MMIO_DEVICE_FEATURES => {
                 if self.device_features_sel > 1 {
                     return Err(Error::InvalidFeatureSel(self.device_features_sel));
                 }
 
-                let mut features = gdev.device_features();
-                features |= 1 << VIRTIO_F_VERSION_1;
-                features |= 1 << VIRTIO_F_IOMMU_PLATFORM;
-                (features >> (32 * self.device_features_sel)) as u32
-            }
-            VIRTIO_MMIO_QUEUE_READY => vq.ready,
-            VIRTIO_MMIO_QUEUE_DESC_LOW => vq.desc_lo,
-            VIRTIO_MMIO_QUEUE_DESC_HIGH => vq.desc_hi,
-            VIRTIO_MMIO_QUEUE_USED_LOW => vq.used_lo,
-            VIRTIO_MMIO_QUEUE_USED_HIGH => vq.used_hi,
-            VIRTIO_MMIO_QUEUE_AVAIL_LOW => vq.avail_lo,
-            VIRTIO_MMIO_QUEUE_AVAIL_HIGH => vq.avail_hi,
-            VIRTIO_MMIO_CONFIG_GENERATION => {
-                // TODO
-                0
+                (self.device_features >> (32 * self.device_features_sel)) as u32
             }
+            VIRTIO_MMIO_QUEUE_READY => queue_reg(|vq| vq.ready),
+            VIRTIO_MMIO_QUEUE_RESET => queue_reg(|vq| vq.queue_reset),
+            VIRTIO_MMIO_QUEUE_DESC_LOW => queue_reg(|vq| vq.desc_lo),
+            VIRTIO_MMIO_QUEUE_DESC_HIGH => queue_reg(|vq| vq.desc_hi),
+            VIRTIO_MMIO_QUEUE_USED_LOW => queue_reg(|vq| vq.used_lo),
+            VIRTIO_MMIO_QUEUE_USED_HIGH => queue_reg(|vq| vq.used_hi),
+            VIRTIO_MMIO_QUEUE_AVAIL_LOW => queue_reg(|vq| vq.avail_lo),
+            VIRTIO_MMIO_QUEUE_AVAIL_HIGH => queue_reg(|vq| vq.avail_hi),
+            // Shared memory regions, like the virtio-fs DAX window, can't be set up with the
+            // backend yet. All ones in length and base tell the guest that the selected region
+            // doesn't exist.
+            VIRTIO_MMIO_SHM_LEN_LOW
+            | VIRTIO_MMIO_SHM_LEN_HIGH
+            | VIRTIO_MMIO_SHM_BASE_LOW
+            | VIRTIO_MMIO_SHM_BASE_HIGH => u32::MAX,
+            VIRTIO_MMIO_CONFIG_GENERATION => dev.interrupt().generation(),
+
+            _ => return Ok(None),
+        };
 
-            _ => return Err(Error::InvalidMmioAddr("read", offset)),
-        } as u64;
+        Ok(Some(val))
+    }
 
-        Ok(())
+    // The registers are read without taking the gdev lock, the backend's features are read once,
+    // when it connects.
+    fn offered_features(&self, gdev: &dyn Backend) -> u64 {
+        let mut features = gdev.device_features();
+        if !self.allow_legacy {
+            features |= 1 << VIRTIO_F_VERSION_1;
+        }
+
+        // Grant mappings rely on the guest's Xen grant DMA ops, which it only uses for devices
+        // that need the platform's IOMMU. Foreign mappings work with guest physical addresses.
+        if !self.foreign_mapping {
+            features |= 1 << VIRTIO_F_IOMMU_PLATFORM;
+        }
+        features
     }
 
-    fn io_write(&mut self, ioreq: &ioreq, dev: &XenDevice, offset: u64) -> Result<()> {
-        let vq = &mut self.vq[self.queue_sel as usize];
+    fn io_read(&self, dev: &XenDevice, offset: u64) -> Result<u32> {
+        self.reg_read(dev, offset)?
+            .ok_or_else(|| self.invalid_addr("read", dev, offset))
+    }
+
+    fn negotiate_features(&mut self, dev: &XenDevice) -> Result<()> {
+        self.notification_data = (self.driver_features & (1 << VIRTIO_F_NOTIFICATION_DATA)) != 0;
 
+        dev.gdev
+            .lock()
+            .unwrap()
+            .negotiate_features(self.driver_features, FRONTEND_PROTOCOL_FEATURES)
+    }
+
+    fn io_write(&mut self, data: u32, dev: &XenDevice, offset: u64) -> Result<()> {
         match offset as u32 {
-            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.device_features_sel = ioreq.data as u32,
-            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.driver_features_sel = ioreq.data as u32,
-            VIRTIO_MMIO_QUEUE_SEL => self.queue_sel = ioreq.data as u32,
-            VIRTIO_MMIO_STATUS => self.status = ioreq.data as u32,
-            VIRTIO_MMIO_QUEUE_NUM => vq.size = ioreq.data as u32,
-            VIRTIO_MMIO_QUEUE_DESC_LOW => vq.desc_lo = ioreq.data as u32,
-            VIRTIO_MMIO_QUEUE_DESC_HIGH => vq.desc_hi = ioreq.data as u32,
-            VIRTIO_MMIO_QUEUE_USED_LOW => vq.used_lo = ioreq.data as u32,
-            VIRTIO_MMIO_QUEUE_USED_HIGH => vq.used_hi = ioreq.data as u32,
-            VIRTIO_MMIO_QUEUE_AVAIL_LOW => vq.avail_lo = ioreq.data as u32,
-            VIRTIO_MMIO_QUEUE_AVAIL_HIGH => vq.avail_hi = ioreq.data as u32,
-            VIRTIO_MMIO_INTERRUPT_ACK => {
-                self.interrupt_state &= !(ioreq.data as u32);
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.device_features_sel = data,
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.driver_features_sel = data,
+            // Any queue can be selected, including the ones that don't exist, see reg_read().
+            VIRTIO_MMIO_QUEUE_SEL => self.queue_sel = data,
+            VIRTIO_MMIO_QUEUE_NUM
+            | VIRTIO_MMIO_QUEUE_DESC_LOW
+            | VIRTIO_MMIO_QUEUE_DESC_HIGH
+            | VIRTIO_MMIO_QUEUE_USED_LOW
+            | VIRTIO_MMIO_QUEUE_USED_HIGH
+            | VIRTIO_MMIO_QUEUE_AVAIL_LOW
+            | VIRTIO_MMIO_QUEUE_AVAIL_HIGH
+            | VIRTIO_MMIO_QUEUE_READY
+            | VIRTIO_MMIO_QUEUE_RESET => self.queue_write(data, dev, offset as u32)?,
+            VIRTIO_MMIO_SHM_SEL => {
+                // None of the shared memory regions exist, nothing to select.
+            }
+            VIRTIO_MMIO_STATUS => {
+                if data == 0 {
+                    self.reset(dev);
+                } else if data & VIRTIO_CONFIG_S_DRIVER_OK != 0 && !self.activated {
+                    // Drivers only set up the virtqueues they use, like the gpio event queue,
+                    // which is only used with VIRTIO_GPIO_F_IRQ. Activate the device with the
+                    // ones set up so far.
+                    if let Err(e) = self.activate_device(dev, dev.guest.fe_domid) {
+                        self.abort_activation(dev, data);
+                        return Err(e);
+                    }
+                }
+
+                self.status = data;
             }
+            VIRTIO_MMIO_INTERRUPT_ACK => dev.interrupt().ack(data),
             VIRTIO_MMIO_DRIVER_FEATURES => {
-                self.driver_features |=
-                    ((ioreq.data as u32) as u64) << (32 * self.driver_features_sel);
+                self.driver_features |= (data as u64) << (32 * self.driver_features_sel);
 
                 if self.driver_features_sel == 1 {
-                    if (self.driver_features & (1 << VIRTIO_F_VERSION_1)) == 0 {
+                    if !self.allow_legacy && (self.driver_features & (1 << VIRTIO_F_VERSION_1)) == 0
+                    {
                         return Err(Error::MmioLegacyNotSupported);
                     }
+
+                    // Without it the guest shares guest physical addresses instead of grants,
+                    // which can't be mapped.
+                    if !self.foreign_mapping
+                        && (self.driver_features & (1 << VIRTIO_F_IOMMU_PLATFORM)) == 0
+                    {
+                        return Err(Error::GrantRequiresIommu);
+                    }
                 } else {
                     // Guest sends feature sel 1 first, followed by 0. Once that is done, lets
                     // negotiate features.
-                    dev.gdev
-                        .lock()
-                        .unwrap()
-                        .negotiate_features(
-                            self.driver_features,
-                            VhostUserProtocolFeatures::XEN_MMAP,
-                        )
-                        .map_err(Error::VhostFrontendError)?;
+                    self.negotiate_features(dev)?;
                 }
             }
+            VIRTIO_MMIO_QUEUE_NOTIFY => {
+                // This is normally handled in the Linux kernel, via the ioeventfd registered for
+                // each virtqueue, which kicks the backend directly. The ioeventfd only matches the
+                // plain queue index though, with VIRTIO_F_NOTIFICATION_DATA the write lands here
+                // instead and the backend is kicked from here.
+                //
+                // Notification suppression (VIRTIO_F_EVENT_IDX or VRING_USED_F_NO_NOTIFY) is
+                // done by the guest driver before it writes to this register, so a kick that
+                // reaches us is always meant to be delivered.
+                self.kick(data)?;
+                self.check_backlog(dev);
+            }
+
+            _ => return Err(self.invalid_addr("write", dev, offset)),
+        }
+
+        Ok(())
+    }
+
+    // Writes to the registers of the selected queue, the ones of a queue that doesn't exist are
+    // ignored.
+    fn queue_write(&mut self, data: u32, dev: &XenDevice, offset: u32) -> Result<()> {
+        let vq = match self.vq.get_mut(self.queue_sel as usize) {
+            Some(vq) => vq,
+            None => return Ok(()),
+        };
+
+        match offset {
+            VIRTIO_MMIO_QUEUE_NUM => {
+                let size = data;
+
+                // The size is used to map the virtqueues, don't trust the guest with it.
+                if size > vq.size_max || !size.is_power_of_two() {
+                    return Err(Error::InvalidQueueSize(size, vq.size_max));
+                }
+
+                vq.size = size;
+            }
+            VIRTIO_MMIO_QUEUE_DESC_LOW => vq.desc_lo = data,
+            VIRTIO_MMIO_QUEUE_DESC_HIGH => vq.desc_hi = data,
+            VIRTIO_MMIO_QUEUE_USED_LOW => vq.used_lo = data,
+            VIRTIO_MMIO_QUEUE_USED_HIGH => vq.used_hi = data,
+            VIRTIO_MMIO_QUEUE_AVAIL_LOW => vq.avail_lo = data,
+            VIRTIO_MMIO_QUEUE_AVAIL_HIGH => vq.avail_hi = data,
             VIRTIO_MMIO_QUEUE_READY => {
-                if ioreq.data == 1 {
+                if data == 1 && vq.queue_reset == 1 && self.activated {
+                    self.enable_reset_vq(dev)?;
+                } else if data == 1 {
                     self.init_vq(dev.guest.fe_domid)?;
-
-                    // Wait for all virtqueues to get initialized.
-                    if self.queues.len() == self.queues_count {
-                        self.activate_device(dev, dev.guest.fe_domid)?;
-                    }
                 } else {
                     self.destroy_vq();
                 }
             }
-            VIRTIO_MMIO_QUEUE_NOTIFY => {
-                // This is handled in the Linux kernel now. Nothing to do here.
+            VIRTIO_MMIO_QUEUE_RESET => {
+                if data == 1 {
+                    self.reset_vq(dev);
+                }
             }
-
-            _ => return Err(Error::InvalidMmioAddr("write", offset)),
+            _ => unreachable!(),
         }
 
         Ok(())
     }
 
+    fn kick(&mut self, data: u32) -> Result<()> {
+        // With VIRTIO_F_NOTIFICATION_DATA, the queue index is in the low 16 bits and the rest
+        // carries the next available offset and wrap counter, which the backend doesn't need.
+        let index = if self.notification_data {
+            data & 0xffff
+        } else {
+            data
+        };
+
+        let vq = self
+            .vq
+            .get_mut(index as usize)
+            .ok_or(Error::InvalidQueueIndex(index))?;
+
+        vq.kicks += 1;
+        vq.last_kick = Some(Instant::now());
+        vq.kick.write(1).map_err(Error::EventFdWriteFailed)
+    }
+
+    // Warns when the backlog of a queue goes over the threshold, as the backend isn't keeping up
+    // with the guest, and once it is back under it. The backlog can only be checked when the
+    // frontend is involved, on the kicks that reach it and the backend's used buffer
+    // notifications.
+    pub fn check_backlog(&mut self, dev: &XenDevice) {
+        let (threshold, mem) = match (self.backlog_threshold, &self.memory) {
+            (Some(threshold), Some(mem)) => (threshold, mem.memory()),
+            _ => return,
+        };
+
+        for (index, vq) in self.vq.iter_mut().enumerate() {
+            if vq.ready == 0 {
+                continue;
+            }
+
+            let pending = match vq.pending(&mem) {
+                Ok(pending) => pending,
+                Err(_) => continue,
+            };
+
+            let backlogged = pending > threshold;
+            if backlogged == vq.backlogged {
+                continue;
+            }
+
+            vq.backlogged = backlogged;
+            if backlogged {
+                println!(
+                    "Device {} / {}: Queue {} has {} buffers waiting for the backend, over {}",
+                    dev.guest.fe_domid, dev.dev_id, index, pending, threshold
+                );
+            } else {
+                println!(
+                    "Device {} / {}: Queue {} backlog is back under {}",
+                    dev.guest.fe_domid, dev.dev_id, index, threshold
+                );
+            }
+        }
+    }
+
+    // Brings the device back to its initial state, the guest requests this by writing 0 to the
+    // status register.
+    fn reset(&mut self, dev: &XenDevice) {
+        if self.activated {
+            dev.gdev.lock().unwrap().reset();
+            self.activated = false;
+            self.memory = None;
+        }
+
+        self.destroy_vq();
+        for vq in self.vq.iter_mut() {
+            vq.reset();
+        }
+
+        // Foreign memory is mapped once for the lifetime of the device, grant mappings are
+        // created again when the queues are set up.
+        if !self.foreign_mapping {
+            self.regions.clear();
+        }
+
+        self.status = 0;
+        self.queue_sel = 0;
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        dev.interrupt().reset();
+        self.notification_data = false;
+    }
+
+    // Unmaps all the grant and foreign memory regions, must be called only after the backend is
+    // stopped, so that nobody refers to the regions anymore.
+    pub fn unmap_regions(&mut self, dev: &XenDevice) {
+        // The memory handed over to the backend references all of them.
+        self.memory = None;
+
+        for region in self.regions.drain(..) {
+            if Arc::strong_count(&region) > 1 {
+                println!(
+                    "Device {} / {}: Region at {:#x} is still in use",
+                    dev.guest.fe_domid,
+                    dev.dev_id,
+                    region.start_addr().0
+                );
+            }
+        }
+    }
+
     fn sort_regions(&mut self) {
         self.regions
             .sort_by(|a, b| a.start_addr().partial_cmp(&b.start_addr()).unwrap());
     }
 
+    // All regions share a single open file per device node.
+    fn open_dev(&mut self, path: &str) -> Result<Arc<File>> {
+        if let Some(file) = self.files.get(path) {
+            return Ok(file.clone());
+        }
+
+        let file = Arc::new(self.mapper.open_dev(path)?);
+        self.files.insert(path.to_string(), file.clone());
+        Ok(file)
+    }
+
     fn map_region(
         &mut self,
         addr: GuestAddress,
@@ -282,36 +819,42 @@ impl XenMmio {
         flags: u32,
         data: u32,
     ) -> Result<()> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(path)
-            .unwrap();
-
-        let range = MmapRange::new(size, Some(FileOffset::new(file, 0)), addr, flags, data);
-        let region = GuestRegionMmap::new(MmapRegion::from_range(range).unwrap(), addr).unwrap();
-
-        self.regions.push(region);
+        let file = self.open_dev(path)?;
+        let region = self.mapper.mmap_region(addr, size, file, flags, data)?;
+        self.regions.push(Arc::new(region));
 
         Ok(())
     }
 
+    // Mapping a big guest takes a lot of time, map it in chunks from separate threads.
+    //
+    // There is no need to prefault the mapped pages. vm-memory maps the guest with privcmd's
+    // IOCTL_PRIVCMD_MMAPBATCH_V2, which populates the page table entries at map time, the backend
+    // doesn't take any faults on its first accesses.
     fn map_foreign_region(&mut self, domid: u16) -> Result<()> {
-        self.map_region(
-            GuestAddress(GUEST_RAM0_BASE),
-            self.guest_size,
-            "/dev/xen/privcmd",
-            MmapXenFlags::FOREIGN.bits(),
-            domid as u32,
-        )
+        let chunks = self.guest.layout.chunks(FOREIGN_CHUNK_SIZE);
+        let file = self.open_dev("/dev/xen/privcmd")?;
+        let mapper = self.mapper.clone();
+
+        let regions = map_chunks(&chunks, |addr, size| {
+            let flags = MmapXenFlags::FOREIGN.bits();
+            mapper.mmap_region(GuestAddress(addr), size, file.clone(), flags, domid as u32)
+        })?;
+
+        self.regions.extend(regions.into_iter().map(Arc::new));
+
+        // Sort the already added regions by start address.
+        self.sort_regions();
+
+        Ok(())
     }
 
     // Maps entire guest address space in one region.
     //
-    // The address received here is special as the kernel ORs the address with 0x8000000000000000
-    // to mark it for grant mapping. If the memory mapping fails for a device here and address
-    // doesn't have the top bit set, then either the guest kernel's DT doesn't have the required
-    // iommu nodes or it is missing some Kconfig options.
+    // The address received here is special as the kernel adds an offset (0x8000000000000000 by
+    // default) to the address to mark it for grant mapping. If the address doesn't have the offset
+    // added, then either the guest kernel's DT doesn't have the required iommu nodes or it is
+    // missing some Kconfig options.
     //
     // Hint: XEN_GRANT_DMA_ADDR_OFF in drivers/xen/grant-dma-ops.c.
     fn map_grant_region(&mut self, addr: u64, size: usize, domid: u16, flags: u32) -> Result<()> {
@@ -319,6 +862,10 @@ impl XenMmio {
             return Ok(());
         }
 
+        if addr < self.grant_addr_off {
+            return Err(Error::InvalidGrantAddr(addr));
+        }
+
         self.map_region(
             GuestAddress(addr),
             size,
@@ -328,48 +875,89 @@ impl XenMmio {
         )
     }
 
-    // Maps virtqueues in advance.
-    fn map_grant_queue_regions(&mut self, queue: &Queue, vq_size: usize, domid: u16) -> Result<()> {
-        let mut size = vq_size * std::mem::size_of::<Descriptor>();
-        self.map_grant_region(queue.desc_table(), size, domid, 0)?;
+    // Returns the parts of the virtqueue as (name, address, size).
+    fn queue_rings(&self, queue: &Queue, vq_size: usize) -> [(&'static str, u64, usize); 3] {
+        let event_idx = (self.driver_features & (1 << VIRTIO_RING_F_EVENT_IDX)) != 0;
+
+        [
+            (
+                "descriptor table",
+                queue.desc_table(),
+                desc_table_size(vq_size),
+            ),
+            (
+                "available ring",
+                queue.avail_ring(),
+                avail_ring_size(vq_size, event_idx),
+            ),
+            (
+                "used ring",
+                queue.used_ring(),
+                used_ring_size(vq_size, event_idx),
+            ),
+        ]
+    }
 
-        size = vq_size * std::mem::size_of::<__virtio16>();
-        size += std::mem::size_of::<vring_avail>();
-        // Extra 2 bytes for vring_used_elem at the end of avail ring
-        size += std::mem::size_of::<__virtio16>();
-        self.map_grant_region(queue.avail_ring(), size, domid, 0)?;
+    // The backend accesses the rings at the addresses written by the guest, make sure they point
+    // to the guest's RAM and nothing else. With grant mappings, that is the range of grant
+    // addresses mapped for the guest.
+    fn check_queue_rings(&self, queue: &Queue, vq_size: usize) -> Result<()> {
+        for (name, addr, size) in self.queue_rings(queue, vq_size) {
+            let valid = if self.foreign_mapping {
+                self.guest.layout.contains(addr, size)
+            } else {
+                addr >= self.grant_addr_off
+                    && (addr - self.grant_addr_off)
+                        .checked_add(size as u64)
+                        .map_or(false, |end| end <= self.guest_size as u64)
+            };
+
+            if !valid {
+                return Err(Error::InvalidRingAddr(name, addr));
+            }
+        }
 
-        size = vq_size * std::mem::size_of::<vring_used_elem>();
-        size += std::mem::size_of::<vring_used>();
-        // Extra 2 bytes for vring_used_elem at the end of used ring
-        size += std::mem::size_of::<__virtio16>();
-        self.map_grant_region(queue.used_ring(), size, domid, 0)?;
+        Ok(())
+    }
+
+    // Maps virtqueues in advance.
+    fn map_grant_queue_regions(&mut self, queue: &Queue, vq_size: usize, domid: u16) -> Result<()> {
+        for (_, addr, size) in self.queue_rings(queue, vq_size) {
+            self.map_grant_region(addr, size, domid, 0)?;
+        }
 
         Ok(())
     }
 
-    // Maps non-virtqueues memory with no advance map flag.
+    // Maps non-virtqueues memory with no advance map flag. This covers all of the guest's memory
+    // that isn't a ring, and so the buffers and the indirect descriptor tables
+    // (VIRTIO_RING_F_INDIRECT_DESC) too, which the driver places anywhere in it.
     fn map_grant_remaining_regions(&mut self, domid: u16) -> Result<()> {
         // Sort the already added regions by start address.
         self.sort_regions();
 
-        let mut regions: Vec<GuestRegionMmap> = self.regions.drain(..).collect();
-        let mut offset = XEN_GRANT_ADDR_OFF;
+        let mut regions: Vec<Arc<GuestRegionMmap>> = self.regions.drain(..).collect();
+        let mut offset = self.grant_addr_off;
 
         for region in &regions {
-            let size = (region.start_addr().0 - offset) as usize;
-            self.map_grant_region(offset, size, domid, MmapXenFlags::NO_ADVANCE_MAP.bits())?;
-            offset = region.start_addr().0 + region.len() + XC_PAGE_SIZE as u64 - 1;
-            offset = (offset >> XC_PAGE_SHIFT) << XC_PAGE_SHIFT;
+            let start = region.start_addr().0;
+
+            // Rings sharing a page with the previous one start below the offset, there is nothing
+            // to map in between then.
+            if start > offset {
+                let size = (start - offset) as usize;
+                self.map_grant_region(offset, size, domid, MmapXenFlags::NO_ADVANCE_MAP.bits())?;
+            }
+
+            offset = offset.max(page_align(start + region.len()));
         }
 
         // Regions are mapped from address 0 until end of all virtqueues, lets map the rest now.
-        self.map_grant_region(
-            offset,
-            self.guest_size - (offset - XEN_GRANT_ADDR_OFF) as usize,
-            domid,
-            MmapXenFlags::NO_ADVANCE_MAP.bits(),
-        )?;
+        // Nothing is left if the last ring ends in the last page of the guest's memory.
+        let size = self
+            .guest_size
+            .saturating_sub((offset - self.grant_addr_off) as usize);
+        self.map_grant_region(offset, size, domid, MmapXenFlags::NO_ADVANCE_MAP.bits())?;
         self.regions.append(&mut regions);
 
         // Sort the already added regions by start address.
@@ -378,89 +966,276 @@ impl XenMmio {
         Ok(())
     }
 
+    // The grant mappings must cover all of the guest's memory, with holes only left at the end of
+    // the pages holding the rings. Logs where that isn't the case, the backend faults on accessing
+    // the memory otherwise.
+    fn check_grant_regions(&self, dev: &XenDevice) {
+        let regions: Vec<(u64, usize)> = self
+            .regions
+            .iter()
+            .map(|region| (region.start_addr().0, region.len() as usize))
+            .collect();
+        let guest_end = self.grant_addr_off + self.guest_size as u64;
+
+        for gap in grant_coverage_gaps(&regions, self.grant_addr_off, guest_end) {
+            match gap {
+                CoverageGap::Overlap(start, end) => println!(
+                    "Device {} / {}: Grant region at {:#x} overlaps the one ending at {:#x}",
+                    dev.guest.fe_domid, dev.dev_id, start, end
+                ),
+                CoverageGap::Hole(start, end) => println!(
+                    "Device {} / {}: Grant regions leave a hole at {:#x}..{:#x}",
+                    dev.guest.fe_domid, dev.dev_id, start, end
+                ),
+                CoverageGap::End(end) => println!(
+                    "Device {} / {}: Grant regions end at {:#x} instead of {:#x}",
+                    dev.guest.fe_domid, dev.dev_id, end, guest_end
+                ),
+            }
+        }
+    }
+
+    // Returns the selected queue, as set up by the guest, once its rings are checked.
+    fn checked_queue(&self) -> Result<Queue> {
+        let vq = &self.vq[self.queue_sel as usize];
+        let queue = vq.queue()?;
+
+        self.check_queue_rings(&queue, vq.size as usize)?;
+        Ok(queue)
+    }
+
     fn init_vq(&mut self, domid: u16) -> Result<()> {
+        let queue = self.checked_queue()?;
+
+        if !self.foreign_mapping {
+            let vq_size = self.vq[self.queue_sel as usize].size;
+            self.map_grant_queue_regions(&queue, vq_size as usize, domid)?;
+        }
+
+        // The queue is only ready once its rings are mapped.
         let vq = &mut self.vq[self.queue_sel as usize];
         let kick = vq.kick.try_clone().unwrap();
-        let vq_size = vq.size;
+        vq.ready = 1;
+        vq.queue_reset = 0;
+
+        self.queues.push((self.queue_sel as usize, queue, kick));
+
+        Ok(())
+    }
 
-        let desc = ((vq.desc_hi as u64) << 32) | vq.desc_lo as u64;
-        let avail = ((vq.avail_hi as u64) << 32) | vq.avail_lo as u64;
-        let used = ((vq.used_hi as u64) << 32) | vq.used_lo as u64;
+    // Resets the selected queue only. vhost-user can't reset a single queue, so the backend is
+    // stopped instead, along with all the other queues, and activated again with all of them once
+    // this queue is enabled again.
+    fn reset_vq(&mut self, dev: &XenDevice) {
+        let index = self.queue_sel as usize;
 
-        if desc == 0 || avail == 0 || used == 0 {
-            panic!();
+        if self.activated {
+            dev.gdev.lock().unwrap().reset();
         }
 
-        let mut queue = Queue::new(vq_size as u16).unwrap();
-        queue.set_desc_table_address(Some((desc & 0xFFFFFFFF) as u32), Some((desc >> 32) as u32));
-        queue.set_avail_ring_address(
-            Some((avail & 0xFFFFFFFF) as u32),
-            Some((avail >> 32) as u32),
-        );
-        queue.set_used_ring_address(Some((used & 0xFFFFFFFF) as u32), Some((used >> 32) as u32));
-        queue.set_next_avail(0);
+        self.queues.retain(|(i, _, _)| *i != index);
 
-        vq.ready = 1;
+        // The rings of each queue are grant mapped on their own and the rest of the memory around
+        // the rings of all of them. Keep the rings of the other queues only, the rest is mapped
+        // again around the new rings once this queue is enabled.
+        if !self.foreign_mapping {
+            let rings: Vec<(u64, usize)> = self
+                .vq
+                .iter()
+                .enumerate()
+                .filter(|(i, vq)| *i != index && vq.ready == 1)
+                .filter_map(|(_, vq)| vq.queue().ok().map(|queue| (queue, vq.size as usize)))
+                .flat_map(|(queue, size)| self.queue_rings(&queue, size))
+                .map(|(_, addr, size)| (addr, size))
+                .collect();
+
+            self.regions
+                .retain(|region| rings.contains(&(region.start_addr().0, region.len() as usize)));
+            self.memory = None;
+        }
+
+        let vq = &mut self.vq[index];
+        vq.reset();
+        vq.queue_reset = 1;
+    }
+
+    // Enables the selected queue again after it was reset, which restarts the backend.
+    fn enable_reset_vq(&mut self, dev: &XenDevice) -> Result<()> {
+        let queue = self.checked_queue()?;
 
         if !self.foreign_mapping {
+            let domid = dev.guest.fe_domid;
+            let vq_size = self.vq[self.queue_sel as usize].size;
+
             self.map_grant_queue_regions(&queue, vq_size as usize, domid)?;
+            self.map_grant_remaining_regions(domid)?;
+            self.check_grant_regions(dev);
         }
 
-        self.queues.push((self.queue_sel as usize, queue, kick));
+        let vq = &mut self.vq[self.queue_sel as usize];
+        vq.ready = 1;
+        vq.queue_reset = 0;
 
-        Ok(())
+        self.reactivate(dev)
     }
 
     fn destroy_vq(&mut self) {
         self.queues.drain(..);
     }
 
-    fn mem(&mut self) -> GuestMemoryAtomic<GuestMemoryMmap> {
-        GuestMemoryAtomic::new(
-            GuestMemoryMmap::from_regions(self.regions.drain(..).collect()).unwrap(),
-        )
+    fn mem(&self) -> Result<GuestMemoryAtomic<GuestMemoryMmap>> {
+        let mem = GuestMemoryMmap::from_arc_regions(self.regions.clone())
+            .map_err(|_| Error::InvalidMemoryRegions)?;
+
+        Ok(GuestMemoryAtomic::new(mem))
     }
 
     fn activate_device(&mut self, dev: &XenDevice, domid: u16) -> Result<()> {
         // Map rest of the memory, now that all the queues are mapped.
         if !self.foreign_mapping {
             self.map_grant_remaining_regions(domid)?;
+            self.check_grant_regions(dev);
+        }
+
+        // Lock order: interrupt and then gdev, see working.md.
+        let interrupt = dev.interrupt();
+        let mem = self.mem()?;
+        dev.gdev.lock().unwrap().activate(
+            mem.clone(),
+            interrupt,
+            self.queues.drain(..).collect(),
+        )?;
+
+        self.memory = Some(mem);
+        self.activated = true;
+        dev.set_state(xenbus_state_XenbusStateConnected)
+    }
+
+    // Drops the queues and grant mappings set up for an activation that failed halfway and asks
+    // the driver to reset the device, which sets it all up again from scratch.
+    fn abort_activation(&mut self, dev: &XenDevice, status: u32) {
+        // Only setting the xenbus state may have failed, the backend is using the memory then.
+        if self.activated {
+            dev.gdev.lock().unwrap().reset();
+            self.activated = false;
+            self.memory = None;
+        }
+
+        self.destroy_vq();
+        for vq in self.vq.iter_mut() {
+            vq.ready = 0;
+        }
+
+        if !self.foreign_mapping {
+            self.regions.clear();
+        }
+
+        self.status = status | VIRTIO_CONFIG_S_NEEDS_RESET;
+        dev.interrupt().trigger(VirtioInterruptType::Config).ok();
+    }
+
+    pub fn snapshot(&self) -> MmioSnapshot {
+        // The rings can only be read once all the memory is mapped.
+        let mem = self.memory.as_ref().map(|mem| mem.memory());
+        let mem = mem.as_deref();
+
+        MmioSnapshot {
+            status: self.status,
+            driver_features: self.driver_features,
+            queue_sel: self.queue_sel,
+            activated: self.activated,
+            queues: self.vq.iter().map(|vq| vq.stats(mem)).collect(),
+            regions: self
+                .regions
+                .iter()
+                .map(|region| (region.start_addr().0, region.len()))
+                .collect(),
+        }
+    }
+
+    // Replays the configuration done by the guest so far to a newly connected backend.
+    pub fn restore(&mut self, dev: &XenDevice) -> Result<()> {
+        self.device_features = self.offered_features(&dev.gdev.lock().unwrap());
+
+        if self.driver_features != 0 {
+            self.negotiate_features(dev)?;
+        }
+
+        if !self.activated {
+            return Ok(());
+        }
+
+        self.reactivate(dev)
+    }
+
+    // Activates the backend again with all the enabled queues, after it was reset or replaced.
+    fn reactivate(&mut self, dev: &XenDevice) -> Result<()> {
+        let mem = self.mem()?;
+
+        let mut queues = Vec::new();
+        for (index, vq) in self.vq.iter().enumerate() {
+            if vq.ready == 0 {
+                continue;
+            }
+
+            // The backend doesn't know which buffers were already processed anymore, resume from
+            // the last one marked as used.
+            let mut queue = vq.queue()?;
+            let used = queue
+                .used_idx(&*mem.memory(), Ordering::Acquire)
+                .map_err(Error::VirtQueueError)?;
+            queue.set_next_avail(used.0);
+            queue.set_next_used(used.0);
+
+            queues.push((index, queue, vq.kick.try_clone().unwrap()));
         }
 
+        // Lock order: interrupt and then gdev, see working.md.
+        let interrupt = dev.interrupt();
         dev.gdev
             .lock()
             .unwrap()
-            .activate(self.mem(), dev.interrupt(), self.queues.drain(..).collect())
-            .map_err(Error::VhostFrontendActivateError)
+            .activate(mem.clone(), interrupt, queues)?;
+
+        self.memory = Some(mem);
+        Ok(())
     }
 
     pub fn io_event(&mut self, ioreq: &mut ioreq, dev: &XenDevice) -> Result<()> {
-        let mut offset = ioreq.addr - self.addr;
-
-        if offset >= VHOST_USER_CONFIG_OFFSET as u64 {
-            offset -= VHOST_USER_CONFIG_OFFSET as u64;
-            let gdev = &mut dev.gdev.lock().unwrap();
-
-            match ioreq.dir() as u32 {
-                IOREQ_READ => self.config_read(ioreq, gdev, offset),
-                IOREQ_WRITE => self.config_write(ioreq, gdev, offset),
-                _ => Err(Error::InvalidMmioDir(ioreq.dir())),
+        match decode_mmio(ioreq.addr - self.addr, ioreq.size, ioreq.dir())? {
+            MmioAccess::RegRead(offset, size) => {
+                let val = self.io_read(dev, offset & !3)?;
+                ioreq.data = ((val >> ((offset & 3) * 8)) & size_mask(size)) as u64;
+                Ok(())
             }
-        } else {
-            match ioreq.dir() as u32 {
-                IOREQ_READ => self.io_read(ioreq, dev, offset),
-                IOREQ_WRITE => self.io_write(ioreq, dev, offset),
-                _ => Err(Error::InvalidMmioDir(ioreq.dir())),
+            MmioAccess::RegWrite(offset, 4) => self.io_write(ioreq.data as u32, dev, offset),
+            MmioAccess::RegWrite(offset, size) => {
+                // Only the bytes written change, the rest keep the register's current value, or
+                // are zero for registers that can't be read back.
+                let reg = offset & !3;
+                let shift = (offset & 3) * 8;
+                let mask = size_mask(size) << shift;
+                let val = self.reg_read(dev, reg)?.unwrap_or(0);
+
+                let data = ((ioreq.data as u32) << shift) & mask;
+
+                self.io_write((val & !mask) | data, dev, reg)
             }
+            MmioAccess::ConfigRead(offset, size) => {
+                self.config_read(ioreq, &dev.gdev.lock().unwrap(), offset, size);
+                Ok(())
+            }
+            MmioAccess::ConfigWrite(offset, size) => self.config_write(ioreq, dev, offset, size),
         }
     }
 }
 
 impl Drop for XenMmio {
     fn drop(&mut self) {
+        // Lock order: xec, xfm and then xdm, see working.md.
+        let xec = self.guest.xec.lock().unwrap();
         let xfm = self.guest.xfm.lock().unwrap();
         let ioreq = xfm.ioreq(0).unwrap();
-        let xec = self.guest.xec.lock().unwrap();
 
         for (index, vq) in self.vq.iter().enumerate() {
             let kick = vq.kick.try_clone().unwrap();
@@ -470,7 +1245,1220 @@ impl Drop for XenMmio {
                 .lock()
                 .unwrap()
                 .set_ioeventfd(&kick, ioreq, xec.ports(), self.addr, index as u32, false)
-                .unwrap();
+                .ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::GuestRamLayout;
+    use crate::stubs::{stub_args, Calls, StubBackend, StubGuest, STUB_ADDR, STUB_GUEST_SIZE};
+    use proptest::prelude::*;
+    use std::hint;
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        mpsc,
+    };
+    use std::time::{Duration, Instant};
+    use virtio_bindings::virtio_config::{
+        VIRTIO_CONFIG_S_ACKNOWLEDGE, VIRTIO_CONFIG_S_DRIVER, VIRTIO_CONFIG_S_FEATURES_OK,
+    };
+    use virtio_bindings::virtio_mmio::{VIRTIO_MMIO_INT_CONFIG, VIRTIO_MMIO_INT_VRING};
+    use vm_memory::Bytes;
+    use xen_bindings::bindings::{xenbus_state_XenbusStateInitWait, STATE_IORESP_READY};
+
+    const QUEUE_SIZES: [usize; 3] = [64, 256, 1024];
+    const I2C_DEVICE_ID: u32 = 22;
+    // Guest memory given to each queue's rings.
+    const RINGS_SIZE: u64 = 0x10000;
+
+    // An i2c device served by a backend with the queue sizes, at STUB_ADDR.
+    fn stub_device(stub: &StubGuest, sizes: Vec<u16>) -> (Arc<XenDevice>, Arc<Calls>) {
+        let calls = Arc::new(Calls::default());
+        let backend = StubBackend::new(sizes, 0, calls.clone());
+        let dev = stub.add_device(0, "i2c", backend, true).unwrap();
+
+        (dev, calls)
+    }
+
+    // Places the rings of each queue in its own part of the guest's RAM.
+    fn rings_addr(stub: &StubGuest, index: u32) -> u64 {
+        let (base, _) = *stub.guest.layout.chunks(FOREIGN_CHUNK_SIZE).last().unwrap();
+        base + index as u64 * RINGS_SIZE
+    }
+
+    fn write_addr(stub: &StubGuest, dev: &XenDevice, low: u32, addr: u64) {
+        stub.write(dev, low as u64, addr as u32);
+        stub.write(dev, low as u64 + 4, (addr >> 32) as u32);
+    }
+
+    // Sets up and enables the queue the way the Linux driver does.
+    fn setup_queue(stub: &StubGuest, dev: &XenDevice, index: u32, size: u32) {
+        let addr = rings_addr(stub, index);
+
+        stub.write(dev, VIRTIO_MMIO_QUEUE_SEL as u64, index);
+        stub.write(dev, VIRTIO_MMIO_QUEUE_NUM as u64, size);
+        write_addr(stub, dev, VIRTIO_MMIO_QUEUE_DESC_LOW, addr);
+        write_addr(stub, dev, VIRTIO_MMIO_QUEUE_AVAIL_LOW, addr + 0x4000);
+        write_addr(stub, dev, VIRTIO_MMIO_QUEUE_USED_LOW, addr + 0x5000);
+        stub.write(dev, VIRTIO_MMIO_QUEUE_READY as u64, 1);
+    }
+
+    // Takes the device from reset to DRIVER_OK, with the queues enabled.
+    fn start_device(stub: &StubGuest, dev: &XenDevice, queues: &[(u32, u32)]) {
+        let mut status = VIRTIO_CONFIG_S_ACKNOWLEDGE | VIRTIO_CONFIG_S_DRIVER;
+        stub.write(dev, VIRTIO_MMIO_STATUS as u64, status);
+
+        // VIRTIO_F_VERSION_1 only, the high word goes first.
+        stub.write(dev, VIRTIO_MMIO_DRIVER_FEATURES_SEL as u64, 1);
+        stub.write(dev, VIRTIO_MMIO_DRIVER_FEATURES as u64, 1);
+        stub.write(dev, VIRTIO_MMIO_DRIVER_FEATURES_SEL as u64, 0);
+        stub.write(dev, VIRTIO_MMIO_DRIVER_FEATURES as u64, 0);
+
+        status |= VIRTIO_CONFIG_S_FEATURES_OK;
+        stub.write(dev, VIRTIO_MMIO_STATUS as u64, status);
+
+        for &(index, size) in queues {
+            setup_queue(stub, dev, index, size);
         }
+
+        status |= VIRTIO_CONFIG_S_DRIVER_OK;
+        stub.write(dev, VIRTIO_MMIO_STATUS as u64, status);
+    }
+
+    fn virt_queue(size: u32, desc: u64, avail: u64, used: u64) -> VirtQueue {
+        VirtQueue {
+            ready: 0,
+            size,
+            size_max: 1024,
+            desc_lo: desc as u32,
+            desc_hi: (desc >> 32) as u32,
+            avail_lo: avail as u32,
+            avail_hi: (avail >> 32) as u32,
+            used_lo: used as u32,
+            used_hi: (used >> 32) as u32,
+            queue_reset: 0,
+            kicks: 0,
+            last_kick: None,
+            backlogged: false,
+            kick: EventFd::new(EFD_NONBLOCK).unwrap(),
+        }
+    }
+
+    fn backend_state(stub: &StubGuest, dev: &XenDevice) -> Option<String> {
+        stub.node(&format!("{}/state", dev.be))
+    }
+
+    #[test]
+    fn test_desc_table_size() {
+        // 16 bytes per descriptor: addr, len, flags and next.
+        for size in QUEUE_SIZES {
+            assert_eq!(desc_table_size(size), 16 * size);
+        }
+    }
+
+    #[test]
+    fn test_avail_ring_size() {
+        // flags, idx, ring[size] of 2 byte descriptor heads, and used_event with EVENT_IDX.
+        for size in QUEUE_SIZES {
+            assert_eq!(avail_ring_size(size, false), 2 + 2 + 2 * size);
+            assert_eq!(avail_ring_size(size, true), 2 + 2 + 2 * size + 2);
+        }
+
+        assert_eq!(avail_ring_size(64, false), 132);
+        assert_eq!(avail_ring_size(1024, true), 2054);
+    }
+
+    #[test]
+    fn test_used_ring_size() {
+        // flags, idx, ring[size] of 8 byte id and len, and avail_event with EVENT_IDX.
+        for size in QUEUE_SIZES {
+            assert_eq!(used_ring_size(size, false), 2 + 2 + 8 * size);
+            assert_eq!(used_ring_size(size, true), 2 + 2 + 8 * size + 2);
+        }
+
+        assert_eq!(used_ring_size(256, false), 2052);
+        assert_eq!(used_ring_size(1024, true), 8198);
+    }
+
+    #[test]
+    fn test_map_chunks() {
+        let chunks: Vec<(u64, usize)> = (0..100).map(|i| (i << 30, 1 << 30)).collect();
+
+        let mappings = map_chunks(&chunks, |addr, size| Ok((addr, size))).unwrap();
+        assert_eq!(mappings, chunks);
+
+        let failed = map_chunks(&chunks, |addr, size| {
+            if addr == 42 << 30 {
+                Err(Error::RegionMmap(addr))
+            } else {
+                Ok((addr, size))
+            }
+        });
+        assert!(matches!(failed, Err(Error::RegionMmap(addr)) if addr == 42 << 30));
+
+        assert!(map_chunks(&[], |addr, size| Ok((addr, size)))
+            .unwrap()
+            .is_empty());
+    }
+
+    // Compares mapping a 64GB guest from a single thread and with map_chunks(). Mapping a chunk
+    // is stood in for by a sleep of 1us per MB. Run with:
+    //
+    //   cargo test --release bench_map_chunks -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_map_chunks() {
+        let chunks = GuestRamLayout::new(64 << 30).chunks(FOREIGN_CHUNK_SIZE);
+        let map = |addr: u64, size: usize| -> Result<(u64, usize)> {
+            thread::sleep(Duration::from_micros((size >> 20) as u64));
+            Ok((addr, size))
+        };
+
+        let start = Instant::now();
+        let serial = chunks
+            .iter()
+            .map(|&(addr, size)| map(addr, size))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let serial_time = start.elapsed();
+
+        let start = Instant::now();
+        let parallel = map_chunks(&chunks, map).unwrap();
+        let parallel_time = start.elapsed();
+
+        println!(
+            "{} chunks: serial {:?}, map_chunks() {:?} with {} cpus",
+            chunks.len(),
+            serial_time,
+            parallel_time,
+            thread::available_parallelism().map_or(1, |n| n.get())
+        );
+        assert_eq!(serial, parallel);
+    }
+
+    // Compares reading the device features from the cached copy, as reg_read() does, with reading
+    // them from the backend under the gdev lock, while another thread keeps reading the config
+    // space like a driver would. Run with:
+    //
+    //   cargo test --release bench_reg_read -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_reg_read() {
+        const READS: u32 = 1_000_000;
+
+        let stub = StubGuest::new(stub_args(&[]));
+        let (dev, _) = stub_device(&stub, vec![1024]);
+        let stop = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mut data = [0; 8];
+                while !stop.load(Ordering::Relaxed) {
+                    dev.gdev.lock().unwrap().read_config(0, &mut data);
+                }
+            });
+
+            let mmio = dev.mmio.lock().unwrap();
+            let offset = VIRTIO_MMIO_DEVICE_FEATURES as u64;
+
+            let start = Instant::now();
+            for _ in 0..READS {
+                hint::black_box(mmio.reg_read(&dev, offset).unwrap());
+            }
+            let cached_time = start.elapsed();
+
+            let start = Instant::now();
+            for _ in 0..READS {
+                hint::black_box(mmio.offered_features(&dev.gdev.lock().unwrap()));
+            }
+            let locked_time = start.elapsed();
+
+            stop.store(true, Ordering::Relaxed);
+            println!(
+                "{} reads: cached {:?}, under the gdev lock {:?}",
+                READS, cached_time, locked_time
+            );
+        });
+    }
+
+    #[test]
+    fn test_probe() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let (dev, _) = stub_device(&stub, vec![1024]);
+
+        let req = stub.access(&dev, VIRTIO_MMIO_MAGIC_VALUE as u64, 4, IOREQ_READ, 0);
+        assert_eq!(req.state(), STATE_IORESP_READY as u8);
+        assert_eq!(req.data, 0x74726976);
+
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_VERSION as u64), 2);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_DEVICE_ID as u64), I2C_DEVICE_ID);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_VENDOR_ID as u64), 0x4d564b4c);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_QUEUE_NUM_MAX as u64), 1024);
+
+        // Foreign mappings work with guest physical addresses, no IOMMU needed.
+        stub.write(&dev, VIRTIO_MMIO_DEVICE_FEATURES_SEL as u64, 1);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_DEVICE_FEATURES as u64), 1);
+
+        let calls = stub.xen.calls.take();
+        assert!(calls.contains(&format!("set_ioeventfd {:#x} 0 true", STUB_ADDR)));
+        assert!(calls.contains(&format!("map_io_range {:#x} 0x200", STUB_ADDR)));
+    }
+
+    // Registers read byte by byte or a halfword at a time give the bytes of the 32-bit value.
+    #[test]
+    fn test_narrow_register_reads() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let (dev, _) = stub_device(&stub, vec![1024]);
+        let magic = VIRTIO_MMIO_MAGIC_VALUE as u64;
+        let read = |offset, size| stub.access(&dev, offset, size, IOREQ_READ, 0).data;
+
+        for (i, byte) in b"virt".iter().enumerate() {
+            assert_eq!(read(magic + i as u64, 1), *byte as u64);
+        }
+        assert_eq!(read(magic, 2), 0x6976);
+        assert_eq!(read(magic + 2, 2), 0x7472);
+        assert_eq!(read(magic, 4), 0x74726976);
+
+        assert_eq!(read(VIRTIO_MMIO_QUEUE_NUM_MAX as u64, 2), 1024);
+        assert_eq!(read(VIRTIO_MMIO_QUEUE_NUM_MAX as u64 + 1, 1), 4);
+        assert_eq!(read(VIRTIO_MMIO_QUEUE_NUM_MAX as u64 + 2, 2), 0);
+    }
+
+    #[test]
+    fn test_invalid_access() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let (dev, _) = stub_device(&stub, vec![1024]);
+
+        // The failed ioreqs are completed all the same, and the next ones handled.
+        let req = stub.access(&dev, 0xfc, 4, IOREQ_READ, 0x1234);
+        assert_eq!(req.state(), STATE_IORESP_READY as u8);
+        assert_eq!(req.data, 0x1234);
+
+        let req = stub.access(&dev, VIRTIO_MMIO_MAGIC_VALUE as u64 + 2, 4, IOREQ_READ, 0);
+        assert_eq!(req.state(), STATE_IORESP_READY as u8);
+
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_VERSION as u64), 2);
+    }
+
+    #[test]
+    fn test_config() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let calls = Arc::new(Calls::default());
+        let backend = StubBackend::new(vec![64], 0, calls.clone()).with_config(vec![1, 2, 3, 4]);
+        let dev = stub.add_device(0, "i2c", backend, true).unwrap();
+
+        let offset = VHOST_USER_CONFIG_OFFSET as u64;
+        let req = stub.access(&dev, offset + 1, 2, IOREQ_READ, 0);
+        assert_eq!(req.data, 0x0302);
+        let req = stub.access(&dev, offset, 8, IOREQ_READ, 0);
+        assert_eq!(req.data, 0x04030201);
+
+        // i2c has no writable config, the write doesn't reach the backend.
+        stub.access(&dev, offset, 1, IOREQ_WRITE, 0xff);
+        assert!(calls.take().is_empty());
+    }
+
+    // Exactly the bytes accessed reach the backend, whatever their alignment.
+    #[test]
+    fn test_config_unaligned() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let calls = Arc::new(Calls::default());
+        let config = (0..16).collect();
+        let backend = StubBackend::new(vec![64, 64], 0, calls.clone()).with_config(config);
+        let dev = stub.add_device(0, "input", backend, true).unwrap();
+
+        let req = stub.access(&dev, CONFIG_OFFSET + 6, 4, IOREQ_READ, 0);
+        assert_eq!(req.data, 0x09080706);
+        let req = stub.access(&dev, CONFIG_OFFSET + 5, 8, IOREQ_READ, 0);
+        assert_eq!(req.data, 0x0c0b0a0908070605);
+
+        // subsel of struct virtio_input_config.
+        stub.access(&dev, CONFIG_OFFSET + 1, 1, IOREQ_WRITE, 0x1234);
+        assert_eq!(calls.take(), ["write_config 0x1 [52]"]);
+    }
+
+    // A backend reports config changes by triggering a config interrupt, with the interrupt it was
+    // given on activation.
+    #[test]
+    fn test_config_change() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let (dev, _) = stub_device(&stub, vec![1024]);
+
+        start_device(&stub, &dev, &[(0, 256)]);
+        let generation = stub.read(&dev, VIRTIO_MMIO_CONFIG_GENERATION as u64);
+
+        dev.interrupt()
+            .trigger(VirtioInterruptType::Config)
+            .unwrap();
+        assert_eq!(
+            stub.read(&dev, VIRTIO_MMIO_INTERRUPT_STATUS as u64),
+            VIRTIO_MMIO_INT_CONFIG
+        );
+        assert_eq!(
+            stub.read(&dev, VIRTIO_MMIO_CONFIG_GENERATION as u64),
+            generation + 1
+        );
+
+        stub.write(
+            &dev,
+            VIRTIO_MMIO_INTERRUPT_ACK as u64,
+            VIRTIO_MMIO_INT_CONFIG,
+        );
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_INTERRUPT_STATUS as u64), 0);
+    }
+
+    // The backend raises interrupts while the guest acknowledges the ones it saw, none of them is
+    // lost. Each raise waits for the guest to acknowledge it, the guest would never see one that
+    // got cleared by the acknowledgement of another.
+    #[test]
+    fn test_interrupt_ack_race() {
+        const ROUNDS: usize = 500;
+        let stub = StubGuest::new(stub_args(&[]));
+        let (dev, _) = stub_device(&stub, vec![1024]);
+        // The acknowledgements the guest made of each interrupt.
+        let acks = [
+            (VIRTIO_MMIO_INT_VRING, AtomicUsize::new(0)),
+            (VIRTIO_MMIO_INT_CONFIG, AtomicUsize::new(0)),
+        ];
+        let deadline = Instant::now() + Duration::from_secs(30);
+
+        thread::scope(|s| {
+            for (bit, ack) in &acks {
+                let dev = &dev;
+                let int_type = move || match *bit {
+                    VIRTIO_MMIO_INT_VRING => VirtioInterruptType::Queue(0),
+                    _ => VirtioInterruptType::Config,
+                };
+
+                s.spawn(move || {
+                    for round in 0..ROUNDS {
+                        dev.interrupt().trigger(int_type()).unwrap();
+                        while ack.load(Ordering::Acquire) <= round {
+                            assert!(Instant::now() < deadline, "interrupt lost");
+                            thread::yield_now();
+                        }
+                    }
+                });
+            }
+
+            while acks
+                .iter()
+                .any(|(_, ack)| ack.load(Ordering::Acquire) < ROUNDS)
+            {
+                assert!(Instant::now() < deadline, "interrupt lost");
+
+                let status = stub.read(&dev, VIRTIO_MMIO_INTERRUPT_STATUS as u64);
+                if status == 0 {
+                    continue;
+                }
+
+                stub.write(&dev, VIRTIO_MMIO_INTERRUPT_ACK as u64, status);
+                for (bit, ack) in &acks {
+                    if status & bit != 0 {
+                        ack.fetch_add(1, Ordering::AcqRel);
+                    }
+                }
+            }
+        });
+
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_INTERRUPT_STATUS as u64), 0);
+        assert!(acks
+            .iter()
+            .all(|(_, ack)| ack.load(Ordering::Acquire) == ROUNDS));
+    }
+
+    #[test]
+    fn test_activate() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let (dev, calls) = stub_device(&stub, vec![1024]);
+
+        let state = xenbus_state_XenbusStateInitWait.to_string();
+        assert_eq!(backend_state(&stub, &dev), Some(state));
+
+        start_device(&stub, &dev, &[(0, 256)]);
+        assert_eq!(
+            calls.take(),
+            ["negotiate_features 0x100000000", "activate [0]"]
+        );
+
+        let state = xenbus_state_XenbusStateConnected.to_string();
+        assert_eq!(backend_state(&stub, &dev), Some(state));
+
+        let snapshot = dev.snapshot();
+        assert!(snapshot.mmio.activated);
+        assert_eq!(snapshot.mmio.queues[0].size, 256);
+        assert_eq!(snapshot.mmio.queues[0].pending, Some(0));
+
+        // Kicks with VIRTIO_F_NOTIFICATION_DATA land here, count them.
+        stub.write(&dev, VIRTIO_MMIO_QUEUE_NOTIFY as u64, 0);
+        assert_eq!(dev.snapshot().mmio.queues[0].kicks, 1);
+
+        stub.write(&dev, VIRTIO_MMIO_STATUS as u64, 0);
+        assert_eq!(calls.take(), ["reset"]);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_STATUS as u64), 0);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_QUEUE_READY as u64), 0);
+    }
+
+    #[test]
+    fn test_virt_queue() {
+        let queue = virt_queue(256, 0x1_0000_0000, 0x4000, 0x5000)
+            .queue()
+            .unwrap();
+        assert_eq!(queue.size(), 256);
+        assert_eq!(queue.desc_table(), 0x1_0000_0000);
+        assert_eq!(queue.avail_ring(), 0x4000);
+        assert_eq!(queue.used_ring(), 0x5000);
+
+        assert!(matches!(
+            virt_queue(256, 0, 0x4000, 0x5000).queue(),
+            Err(Error::InvalidRingAddr("descriptor table", 0))
+        ));
+        assert!(matches!(
+            virt_queue(256, 0x1000, 0x4000, 0).queue(),
+            Err(Error::InvalidRingAddr("used ring", 0))
+        ));
+        assert!(matches!(
+            virt_queue(0, 0x1000, 0x4000, 0x5000).queue(),
+            Err(Error::InvalidQueueSize(0, 1024))
+        ));
+    }
+
+    // A guest enabling a queue it hasn't set up gets an error, the guest's thread goes on.
+    #[test]
+    fn test_queue_not_set_up() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let (dev, calls) = stub_device(&stub, vec![1024]);
+
+        stub.write(&dev, VIRTIO_MMIO_QUEUE_READY as u64, 1);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_QUEUE_READY as u64), 0);
+
+        stub.write(&dev, VIRTIO_MMIO_QUEUE_NUM as u64, 256);
+        write_addr(
+            &stub,
+            &dev,
+            VIRTIO_MMIO_QUEUE_DESC_LOW,
+            rings_addr(&stub, 0),
+        );
+        stub.write(&dev, VIRTIO_MMIO_QUEUE_READY as u64, 1);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_QUEUE_READY as u64), 0);
+
+        // Activated without the queue.
+        stub.write(&dev, VIRTIO_MMIO_STATUS as u64, VIRTIO_CONFIG_S_DRIVER_OK);
+        assert_eq!(calls.take(), ["activate []"]);
+        assert_eq!(dev.snapshot().mmio.queues[0].pending, None);
+    }
+
+    // Resetting a queue stops all of them, until the guest enables it again.
+    #[test]
+    fn test_queue_reset() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let (dev, calls) = stub_device(&stub, vec![1024, 1024]);
+
+        start_device(&stub, &dev, &[(0, 256), (1, 256)]);
+        calls.take();
+
+        stub.write(&dev, VIRTIO_MMIO_QUEUE_SEL as u64, 1);
+        stub.write(&dev, VIRTIO_MMIO_QUEUE_RESET as u64, 1);
+        assert_eq!(calls.take(), ["reset"]);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_QUEUE_RESET as u64), 1);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_QUEUE_NUM_MAX as u64), 1024);
+
+        // The rings were cleared by the reset, the queue isn't enabled without them.
+        stub.write(&dev, VIRTIO_MMIO_QUEUE_READY as u64, 1);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_QUEUE_READY as u64), 0);
+        assert!(calls.take().is_empty());
+
+        setup_queue(&stub, &dev, 1, 128);
+        assert_eq!(calls.take(), ["activate [0, 1]"]);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_QUEUE_RESET as u64), 0);
+
+        let queues = dev.snapshot().mmio.queues;
+        assert!(queues[0].ready && queues[1].ready);
+        assert_eq!(queues[1].size, 128);
+    }
+
+    // With grant mappings, resetting a queue unmaps its rings along with the rest of the memory
+    // mapped around them, which is mapped again around the new rings.
+    #[test]
+    fn test_queue_reset_grant() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let calls = Arc::new(Calls::default());
+        let backend = StubBackend::new(vec![1024, 1024], 0, calls.clone());
+        let dev = stub.add_device(0, "i2c", backend, false).unwrap();
+        let off = stub.guest.ctx.args.grant_addr_offset;
+        let regions = || dev.snapshot().mmio.regions;
+
+        let setup_grant_queue = |index: u32, addr: u64| {
+            stub.write(&dev, VIRTIO_MMIO_QUEUE_SEL as u64, index);
+            stub.write(&dev, VIRTIO_MMIO_QUEUE_NUM as u64, 256);
+            write_addr(&stub, &dev, VIRTIO_MMIO_QUEUE_DESC_LOW, addr);
+            write_addr(&stub, &dev, VIRTIO_MMIO_QUEUE_AVAIL_LOW, addr + 0x1000);
+            write_addr(&stub, &dev, VIRTIO_MMIO_QUEUE_USED_LOW, addr + 0x2000);
+            stub.write(&dev, VIRTIO_MMIO_QUEUE_READY as u64, 1);
+        };
+
+        let mut status = VIRTIO_CONFIG_S_ACKNOWLEDGE | VIRTIO_CONFIG_S_DRIVER;
+        stub.write(&dev, VIRTIO_MMIO_STATUS as u64, status);
+        stub.write(&dev, VIRTIO_MMIO_DRIVER_FEATURES_SEL as u64, 1);
+        stub.write(
+            &dev,
+            VIRTIO_MMIO_DRIVER_FEATURES as u64,
+            1 | 1 << (VIRTIO_F_IOMMU_PLATFORM - 32),
+        );
+        stub.write(&dev, VIRTIO_MMIO_DRIVER_FEATURES_SEL as u64, 0);
+        stub.write(&dev, VIRTIO_MMIO_DRIVER_FEATURES as u64, 0);
+        status |= VIRTIO_CONFIG_S_FEATURES_OK;
+        stub.write(&dev, VIRTIO_MMIO_STATUS as u64, status);
+
+        setup_grant_queue(0, off + 0x10_0000);
+        setup_grant_queue(1, off + 0x20_0000);
+        status |= VIRTIO_CONFIG_S_DRIVER_OK;
+        stub.write(&dev, VIRTIO_MMIO_STATUS as u64, status);
+        assert_eq!(calls.take(), ["activate [0, 1]"]);
+
+        // Three rings per queue and the memory before, between and after them.
+        assert_eq!(regions().len(), 9);
+
+        stub.write(&dev, VIRTIO_MMIO_QUEUE_SEL as u64, 1);
+        stub.write(&dev, VIRTIO_MMIO_QUEUE_RESET as u64, 1);
+        assert_eq!(calls.take(), ["reset"]);
+        assert_eq!(
+            regions().iter().map(|region| region.0).collect::<Vec<_>>(),
+            [off + 0x10_0000, off + 0x10_1000, off + 0x10_2000]
+        );
+
+        setup_grant_queue(1, off + 0x30_0000);
+        assert_eq!(calls.take(), ["activate [0, 1]"]);
+
+        // All of the guest's memory is mapped again, without the old rings.
+        let regions = regions();
+        assert_eq!(regions.len(), 9);
+        assert_eq!(regions[0].0, off);
+        for pair in regions.windows(2) {
+            assert_eq!(page_align(pair[0].0 + pair[0].1), pair[1].0);
+        }
+        let (start, len) = regions.last().unwrap();
+        assert_eq!(start + len, off + STUB_GUEST_SIZE as u64);
+        assert!(regions.iter().all(|region| region.0 != off + 0x20_0000));
+        assert!(regions.iter().any(|region| region.0 == off + 0x30_0000));
+    }
+
+    // The driver probes for the queues until one reads QueueNumMax as 0.
+    #[test]
+    fn test_queue_sel_out_of_range() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let (dev, _) = stub_device(&stub, vec![1024]);
+
+        stub.write(&dev, VIRTIO_MMIO_QUEUE_SEL as u64, 1);
+        assert_eq!(dev.snapshot().mmio.queue_sel, 1);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_QUEUE_NUM_MAX as u64), 0);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_QUEUE_READY as u64), 0);
+
+        // Ignored, and doesn't end up in another queue.
+        stub.write(&dev, VIRTIO_MMIO_QUEUE_NUM as u64, 256);
+        write_addr(
+            &stub,
+            &dev,
+            VIRTIO_MMIO_QUEUE_DESC_LOW,
+            rings_addr(&stub, 1),
+        );
+        stub.write(&dev, VIRTIO_MMIO_QUEUE_READY as u64, 1);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_QUEUE_DESC_LOW as u64), 0);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_QUEUE_READY as u64), 0);
+
+        stub.write(&dev, VIRTIO_MMIO_QUEUE_SEL as u64, u32::MAX);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_QUEUE_NUM_MAX as u64), 0);
+
+        stub.write(&dev, VIRTIO_MMIO_QUEUE_SEL as u64, 0);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_QUEUE_NUM_MAX as u64), 1024);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_QUEUE_DESC_LOW as u64), 0);
+        assert_eq!(dev.snapshot().mmio.queues[0].size, 0);
+    }
+
+    // Writes a register directly, returning the error the guest's ioreq fails with.
+    fn try_write(dev: &XenDevice, reg: u32, val: u32) -> Result<()> {
+        dev.mmio.lock().unwrap().io_write(val, dev, reg as u64)
+    }
+
+    // Queue sizes are used to map the rings, the ones over the maximum or not a power of two are
+    // refused and the queue keeps its size.
+    #[test]
+    fn test_queue_num_invalid() {
+        let stub = StubGuest::new(stub_args(&["--max-queue-size", "256"]));
+        let (dev, _) = stub_device(&stub, vec![1024]);
+
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_QUEUE_NUM_MAX as u64), 256);
+        for size in [512, 1024, 100, 0] {
+            assert!(matches!(
+                try_write(&dev, VIRTIO_MMIO_QUEUE_NUM, size),
+                Err(Error::InvalidQueueSize(invalid, 256)) if invalid == size
+            ));
+        }
+        assert_eq!(dev.snapshot().mmio.queues[0].size, 0);
+
+        try_write(&dev, VIRTIO_MMIO_QUEUE_NUM, 128).unwrap();
+        assert_eq!(dev.snapshot().mmio.queues[0].size, 128);
+    }
+
+    // Notifying a queue that doesn't exist fails without kicking any, the guest's thread goes on.
+    #[test]
+    fn test_queue_notify_out_of_range() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let (dev, _) = stub_device(&stub, vec![1024]);
+
+        for index in [1, u32::MAX] {
+            assert!(matches!(
+                try_write(&dev, VIRTIO_MMIO_QUEUE_NOTIFY, index),
+                Err(Error::InvalidQueueIndex(invalid)) if invalid == index
+            ));
+        }
+        assert_eq!(dev.snapshot().mmio.queues[0].kicks, 0);
+
+        stub.write(&dev, VIRTIO_MMIO_QUEUE_NOTIFY as u64, 1);
+        stub.write(&dev, VIRTIO_MMIO_QUEUE_NOTIFY as u64, 0);
+        assert_eq!(dev.snapshot().mmio.queues[0].kicks, 1);
+    }
+
+    // Writes the features the driver accepts, the high word first like Linux does.
+    fn write_features(stub: &StubGuest, dev: &XenDevice, features: u64) {
+        stub.write(dev, VIRTIO_MMIO_DRIVER_FEATURES_SEL as u64, 1);
+        stub.write(
+            dev,
+            VIRTIO_MMIO_DRIVER_FEATURES as u64,
+            (features >> 32) as u32,
+        );
+        stub.write(dev, VIRTIO_MMIO_DRIVER_FEATURES_SEL as u64, 0);
+        stub.write(dev, VIRTIO_MMIO_DRIVER_FEATURES as u64, features as u32);
+    }
+
+    // With VIRTIO_F_NOTIFICATION_DATA, the queue index is in the low 16 bits of the notification
+    // only, the rest is the next available offset and wrap counter.
+    #[test]
+    fn test_queue_notify_data() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let (dev, _) = stub_device(&stub, vec![1024, 1024]);
+        let kicks = || {
+            let queues = dev.snapshot().mmio.queues;
+            (queues[0].kicks, queues[1].kicks)
+        };
+        // Queue 1, next offset 5 and the wrap counter set.
+        let encoded = 1 | 5 << 16 | 1 << 31;
+
+        assert!(matches!(
+            try_write(&dev, VIRTIO_MMIO_QUEUE_NOTIFY, encoded),
+            Err(Error::InvalidQueueIndex(index)) if index == encoded
+        ));
+
+        write_features(
+            &stub,
+            &dev,
+            1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_F_NOTIFICATION_DATA,
+        );
+        try_write(&dev, VIRTIO_MMIO_QUEUE_NOTIFY, encoded).unwrap();
+        assert_eq!(kicks(), (0, 1));
+
+        // Plain indexes are the same encoded with no offset.
+        try_write(&dev, VIRTIO_MMIO_QUEUE_NOTIFY, 0).unwrap();
+        assert_eq!(kicks(), (1, 1));
+    }
+
+    // Grant mappings need the guest to use its Xen grant DMA ops, which it only does for devices
+    // that need the platform's IOMMU.
+    #[test]
+    fn test_grant_features() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let calls = Arc::new(Calls::default());
+        let backend = StubBackend::new(vec![1024], 0, calls.clone());
+        let dev = stub.add_device(0, "i2c", backend, false).unwrap();
+        let version_1 = 1 << (VIRTIO_F_VERSION_1 - 32);
+        let iommu = 1 << (VIRTIO_F_IOMMU_PLATFORM - 32);
+
+        stub.write(&dev, VIRTIO_MMIO_DEVICE_FEATURES_SEL as u64, 1);
+        assert_eq!(
+            stub.read(&dev, VIRTIO_MMIO_DEVICE_FEATURES as u64),
+            version_1 | iommu
+        );
+
+        try_write(&dev, VIRTIO_MMIO_DRIVER_FEATURES_SEL, 1).unwrap();
+        assert!(matches!(
+            try_write(&dev, VIRTIO_MMIO_DRIVER_FEATURES, version_1),
+            Err(Error::GrantRequiresIommu)
+        ));
+
+        stub.write(&dev, VIRTIO_MMIO_STATUS as u64, 0);
+        write_features(
+            &stub,
+            &dev,
+            1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_F_IOMMU_PLATFORM,
+        );
+        assert_eq!(calls.take(), ["negotiate_features 0x300000000"]);
+    }
+
+    // Drivers not accepting VIRTIO_F_VERSION_1 are refused, unless legacy ones are allowed, which
+    // doesn't offer the feature either.
+    #[test]
+    fn test_allow_legacy() {
+        for allow_legacy in [false, true] {
+            let args = if allow_legacy {
+                stub_args(&["--allow-legacy"])
+            } else {
+                stub_args(&[])
+            };
+            let stub = StubGuest::new(args);
+            let (dev, calls) = stub_device(&stub, vec![1024]);
+
+            stub.write(&dev, VIRTIO_MMIO_DEVICE_FEATURES_SEL as u64, 1);
+            assert_eq!(
+                stub.read(&dev, VIRTIO_MMIO_DEVICE_FEATURES as u64),
+                !allow_legacy as u32
+            );
+
+            try_write(&dev, VIRTIO_MMIO_DRIVER_FEATURES_SEL, 1).unwrap();
+            let result = try_write(&dev, VIRTIO_MMIO_DRIVER_FEATURES, 0);
+            if allow_legacy {
+                result.unwrap();
+                try_write(&dev, VIRTIO_MMIO_DRIVER_FEATURES_SEL, 0).unwrap();
+                try_write(&dev, VIRTIO_MMIO_DRIVER_FEATURES, 0).unwrap();
+                assert_eq!(calls.take(), ["negotiate_features 0x0"]);
+            } else {
+                assert!(matches!(result, Err(Error::MmioLegacyNotSupported)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_grant_coverage_gaps() {
+        let off: u64 = 1 << 63;
+        let end = off + 0x10_0000;
+
+        // Rings sharing a page, the rest of it is left to the next mapping.
+        let regions = [
+            (off, 0x1000),
+            (off + 0x1000, 0x806),
+            (off + 0x2000, 0x4000),
+            (off + 0x6000, 0xfa000),
+        ];
+        assert!(grant_coverage_gaps(&regions, off, end).is_empty());
+
+        let regions = [(off, 0x1000), (off + 0x3000, 0xfd000)];
+        assert_eq!(
+            grant_coverage_gaps(&regions, off, end),
+            [CoverageGap::Hole(off + 0x1000, off + 0x3000)]
+        );
+
+        let regions = [(off, 0x2000), (off + 0x1000, 0xff000)];
+        assert_eq!(
+            grant_coverage_gaps(&regions, off, end),
+            [CoverageGap::Overlap(off + 0x1000, off + 0x2000)]
+        );
+
+        let regions = [(off, 0x1000), (off + 0x1000, 0x1000)];
+        assert_eq!(
+            grant_coverage_gaps(&regions, off, end),
+            [CoverageGap::End(off + 0x2000)]
+        );
+        assert_eq!(grant_coverage_gaps(&[], off, end), [CoverageGap::End(off)]);
+    }
+
+    #[test]
+    fn test_grant_remaining_regions() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let backend = StubBackend::new(vec![1024], 0, Arc::new(Calls::default()));
+        let dev = stub.add_device(0, "i2c", backend, false).unwrap();
+        let off = stub.guest.ctx.args.grant_addr_offset;
+        let domid = dev.guest.fe_domid;
+
+        let remaining = |rings: &[(u64, usize)]| {
+            let mut mmio = dev.mmio.lock().unwrap();
+            mmio.regions.clear();
+            for &(addr, size) in rings {
+                mmio.map_grant_region(off + addr, size, domid, 0).unwrap();
+            }
+            mmio.map_grant_remaining_regions(domid).unwrap();
+
+            let regions: Vec<(u64, usize)> = mmio
+                .regions
+                .iter()
+                .map(|region| (region.start_addr().0 - off, region.len() as usize))
+                .collect();
+            assert!(grant_coverage_gaps(&regions, 0, STUB_GUEST_SIZE as u64).is_empty());
+            regions
+        };
+
+        // Adjacent page aligned rings leave nothing to map in between.
+        assert_eq!(
+            remaining(&[(0x1000, 0x1000), (0x2000, 0x2000)]),
+            [
+                (0, 0x1000),
+                (0x1000, 0x1000),
+                (0x2000, 0x2000),
+                (0x4000, STUB_GUEST_SIZE - 0x4000)
+            ]
+        );
+
+        // Rings sharing a page, or a few bytes apart in it, neither.
+        assert_eq!(
+            remaining(&[(0x1000, 0x806), (0x1810, 0x100), (0x2000, 0x1000)]),
+            [
+                (0, 0x1000),
+                (0x1000, 0x806),
+                (0x1810, 0x100),
+                (0x2000, 0x1000),
+                (0x3000, STUB_GUEST_SIZE - 0x3000)
+            ]
+        );
+
+        // Rings right at the end of the guest's memory, or ending in its last page.
+        let last = STUB_GUEST_SIZE as u64 - 0x1000;
+        assert_eq!(
+            remaining(&[(last, 0x1000)]),
+            [(0, last as usize), (last, 0x1000)]
+        );
+        assert_eq!(
+            remaining(&[(last, 0x806)]),
+            [(0, last as usize), (last, 0x806)]
+        );
+    }
+
+    const CONFIG_OFFSET: u64 = VHOST_USER_CONFIG_OFFSET as u64;
+
+    #[test]
+    fn test_decode_mmio() {
+        let read = IOREQ_READ as u8;
+        let write = IOREQ_WRITE as u8;
+
+        assert_eq!(
+            decode_mmio(CONFIG_OFFSET - 1, 1, write).unwrap(),
+            MmioAccess::RegWrite(CONFIG_OFFSET - 1, 1)
+        );
+        assert_eq!(
+            decode_mmio(CONFIG_OFFSET + 6, 8, read).unwrap(),
+            MmioAccess::ConfigRead(6, 8)
+        );
+
+        // Config space has no alignment, a byte at an odd offset and a read straddling 8 bytes.
+        assert_eq!(
+            decode_mmio(CONFIG_OFFSET + 3, 1, write).unwrap(),
+            MmioAccess::ConfigWrite(3, 1)
+        );
+        assert_eq!(
+            decode_mmio(CONFIG_OFFSET + 6, 4, read).unwrap(),
+            MmioAccess::ConfigRead(6, 4)
+        );
+
+        // Across two registers, and not a power of two.
+        assert!(matches!(
+            decode_mmio(VIRTIO_MMIO_STATUS as u64 + 2, 4, read),
+            Err(Error::InvalidMmioSize(4))
+        ));
+        assert!(matches!(
+            decode_mmio(VIRTIO_MMIO_STATUS as u64, 3, read),
+            Err(Error::InvalidMmioSize(3))
+        ));
+        assert!(matches!(
+            decode_mmio(VIRTIO_MMIO_STATUS as u64, 8, read),
+            Err(Error::InvalidMmioSize(8))
+        ));
+        assert!(matches!(
+            decode_mmio(CONFIG_OFFSET, 3, write),
+            Err(Error::InvalidMmioSize(3))
+        ));
+        assert!(matches!(
+            decode_mmio(VIRTIO_MMIO_STATUS as u64, 4, 2),
+            Err(Error::InvalidMmioDir(2))
+        ));
+    }
+
+    proptest! {
+        // Whatever the guest puts in the ioreq, decoding it doesn't panic and the accesses let
+        // through stay within a register, or in config space.
+        #[test]
+        fn proptest_decode_mmio(offset in any::<u64>(), size in any::<u32>(), dir in any::<u8>()) {
+            let access = match decode_mmio(offset, size, dir) {
+                Ok(access) => access,
+                Err(_) => return Ok(()),
+            };
+
+            prop_assert!(dir as u32 == IOREQ_READ || dir as u32 == IOREQ_WRITE);
+            let write = dir as u32 == IOREQ_WRITE;
+
+            match access {
+                MmioAccess::RegRead(reg, len) | MmioAccess::RegWrite(reg, len) => {
+                    prop_assert_eq!(matches!(access, MmioAccess::RegWrite(..)), write);
+                    prop_assert_eq!((reg, len), (offset, size as usize));
+                    prop_assert!(reg < CONFIG_OFFSET && (reg & 3) + len as u64 <= 4);
+                }
+                MmioAccess::ConfigRead(config, len) | MmioAccess::ConfigWrite(config, len) => {
+                    prop_assert_eq!(matches!(access, MmioAccess::ConfigWrite(..)), write);
+                    prop_assert_eq!((config + CONFIG_OFFSET, len), (offset, size as usize));
+                    prop_assert!(matches!(len, 1 | 2 | 4 | 8));
+                }
+            }
+        }
+
+        // Aligned accesses to the registers are decoded as such, in either direction.
+        #[test]
+        fn proptest_decode_registers(
+            reg in prop::sample::select(vec![
+                VIRTIO_MMIO_MAGIC_VALUE,
+                VIRTIO_MMIO_DEVICE_FEATURES,
+                VIRTIO_MMIO_QUEUE_SEL,
+                VIRTIO_MMIO_QUEUE_NUM_MAX,
+                VIRTIO_MMIO_QUEUE_READY,
+                VIRTIO_MMIO_QUEUE_NOTIFY,
+                VIRTIO_MMIO_INTERRUPT_STATUS,
+                VIRTIO_MMIO_STATUS,
+                VIRTIO_MMIO_QUEUE_USED_HIGH,
+                VIRTIO_MMIO_CONFIG_GENERATION,
+            ]),
+            size in prop::sample::select(vec![1u32, 2, 4]),
+            byte in 0u64..4
+        ) {
+            let offset = reg as u64 + byte / size as u64 * size as u64;
+
+            prop_assert_eq!(
+                decode_mmio(offset, size, IOREQ_READ as u8).unwrap(),
+                MmioAccess::RegRead(offset, size as usize)
+            );
+            prop_assert_eq!(
+                decode_mmio(offset, size, IOREQ_WRITE as u8).unwrap(),
+                MmioAccess::RegWrite(offset, size as usize)
+            );
+        }
+    }
+
+    fn set_ring_idx(dev: &XenDevice, ring: u64, idx: u16) {
+        let mem = dev.mmio.lock().unwrap().mem().unwrap();
+        mem.memory().write_obj(idx, GuestAddress(ring + 2)).unwrap();
+    }
+
+    // Kicks made without the backend using any buffers take the queue over the threshold.
+    #[test]
+    fn test_backlog() {
+        let stub = StubGuest::new(stub_args(&["--backlog-threshold", "4"]));
+        let (dev, _) = stub_device(&stub, vec![1024]);
+        let avail = rings_addr(&stub, 0) + 0x4000;
+        let used = rings_addr(&stub, 0) + 0x5000;
+        let backlogged = || dev.mmio.lock().unwrap().vq[0].backlogged;
+
+        start_device(&stub, &dev, &[(0, 256)]);
+
+        for idx in 1..=5 {
+            assert!(!backlogged());
+            set_ring_idx(&dev, avail, idx);
+            stub.write(&dev, VIRTIO_MMIO_QUEUE_NOTIFY as u64, 0);
+        }
+
+        assert!(backlogged());
+        assert_eq!(dev.snapshot().mmio.queues[0].pending, Some(5));
+
+        // The backend caught up.
+        set_ring_idx(&dev, used, 3);
+        dev.check_backlog();
+        assert!(!backlogged());
+
+        // Reset along with the queue.
+        set_ring_idx(&dev, avail, 10);
+        dev.check_backlog();
+        assert!(backlogged());
+        stub.write(&dev, VIRTIO_MMIO_STATUS as u64, 0);
+        assert!(!backlogged());
+    }
+
+    // Without a threshold, the backend's notifications don't contend for the mmio lock.
+    #[test]
+    fn test_backlog_disabled() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let (dev, _) = stub_device(&stub, vec![1024]);
+        start_device(&stub, &dev, &[(0, 256)]);
+
+        let mmio = dev.mmio.lock().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let checker = dev.clone();
+        thread::spawn(move || {
+            checker.check_backlog();
+            tx.send(()).unwrap();
+        });
+
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        drop(mmio);
+    }
+
+    #[test]
+    fn test_activate_failed() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let calls = Arc::new(Calls::default());
+        let backend = StubBackend::new(vec![1024], 0, calls.clone()).with_failed_activations(1);
+        let dev = stub.add_device(0, "i2c", backend, true).unwrap();
+
+        start_device(&stub, &dev, &[(0, 256)]);
+        assert_eq!(
+            calls.take(),
+            ["negotiate_features 0x100000000", "activate [0]"]
+        );
+
+        // The driver is asked to reset the device, with none of the queues left ready.
+        let status = stub.read(&dev, VIRTIO_MMIO_STATUS as u64);
+        assert_ne!(status & VIRTIO_CONFIG_S_NEEDS_RESET, 0);
+        assert_eq!(
+            stub.read(&dev, VIRTIO_MMIO_INTERRUPT_STATUS as u64),
+            VIRTIO_MMIO_INT_CONFIG
+        );
+        stub.write(&dev, VIRTIO_MMIO_QUEUE_SEL as u64, 0);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_QUEUE_READY as u64), 0);
+        assert!(!dev.mmio.lock().unwrap().activated);
+
+        // It comes up again after the reset.
+        stub.write(&dev, VIRTIO_MMIO_STATUS as u64, 0);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_STATUS as u64), 0);
+        assert_eq!(stub.read(&dev, VIRTIO_MMIO_INTERRUPT_STATUS as u64), 0);
+        // Nothing was left activated for the backend to reset.
+        assert!(calls.take().is_empty());
+
+        start_device(&stub, &dev, &[(0, 256)]);
+        assert_eq!(
+            calls.take(),
+            ["negotiate_features 0x100000000", "activate [0]"]
+        );
+        let status = stub.read(&dev, VIRTIO_MMIO_STATUS as u64);
+        assert_eq!(status & VIRTIO_CONFIG_S_NEEDS_RESET, 0);
+        assert!(dev.mmio.lock().unwrap().activated);
+    }
+
+    // Kernels built with a different XEN_GRANT_DMA_ADDR_OFF place the guest's memory elsewhere
+    // in the grant address space.
+    #[test]
+    fn test_grant_addr_offset() {
+        let off: u64 = 1 << 40;
+        let stub = StubGuest::new(stub_args(&["--grant-addr-offset", &off.to_string()]));
+        let backend = StubBackend::new(vec![1024], 0, Arc::new(Calls::default()));
+        let dev = stub.add_device(0, "i2c", backend, false).unwrap();
+        let regions = || dev.snapshot().mmio.regions;
+
+        stub.write(
+            &dev,
+            VIRTIO_MMIO_STATUS as u64,
+            VIRTIO_CONFIG_S_ACKNOWLEDGE | VIRTIO_CONFIG_S_DRIVER,
+        );
+        write_features(
+            &stub,
+            &dev,
+            1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_F_IOMMU_PLATFORM,
+        );
+
+        let setup_grant_queue = |addr: u64| {
+            stub.write(&dev, VIRTIO_MMIO_QUEUE_SEL as u64, 0);
+            stub.write(&dev, VIRTIO_MMIO_QUEUE_NUM as u64, 256);
+            write_addr(&stub, &dev, VIRTIO_MMIO_QUEUE_DESC_LOW, addr);
+            write_addr(&stub, &dev, VIRTIO_MMIO_QUEUE_AVAIL_LOW, addr + 0x1000);
+            write_addr(&stub, &dev, VIRTIO_MMIO_QUEUE_USED_LOW, addr + 0x2000);
+            try_write(&dev, VIRTIO_MMIO_QUEUE_READY, 1)
+        };
+
+        // Addresses with the default offset are past the guest's memory now, as are the ones
+        // below the offset.
+        assert!(matches!(
+            setup_grant_queue(1 << 63),
+            Err(Error::InvalidRingAddr("descriptor table", addr)) if addr == 1 << 63
+        ));
+        assert!(matches!(
+            setup_grant_queue(0x10_0000),
+            Err(Error::InvalidRingAddr("descriptor table", 0x10_0000))
+        ));
+        assert!(regions().is_empty());
+
+        setup_grant_queue(off + 0x10_0000).unwrap();
+        assert_eq!(
+            regions(),
+            [
+                (off + 0x10_0000, 0x1000),
+                (off + 0x10_1000, 0x204),
+                (off + 0x10_2000, 0x804)
+            ]
+        );
+
+        stub.write(
+            &dev,
+            VIRTIO_MMIO_STATUS as u64,
+            VIRTIO_CONFIG_S_ACKNOWLEDGE
+                | VIRTIO_CONFIG_S_DRIVER
+                | VIRTIO_CONFIG_S_FEATURES_OK
+                | VIRTIO_CONFIG_S_DRIVER_OK,
+        );
+
+        // The rest of the guest's memory is mapped from the offset on.
+        let regions = regions();
+        assert_eq!(regions.len(), 5);
+        assert_eq!(regions[0], (off, 0x10_0000));
+        assert_eq!(
+            regions[4],
+            (off + 0x10_3000, STUB_GUEST_SIZE as u64 - 0x10_3000)
+        );
+    }
+
+    // A driver reading a config field wider than an access reads the generation before and after
+    // it, and reads it all again if the backend changed the config in between.
+    #[test]
+    fn test_config_change_during_read() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let backend = StubBackend::new(vec![1024], 0, Arc::new(Calls::default()))
+            .with_config([1u32, 1].iter().flat_map(|v| v.to_le_bytes()).collect());
+        let config = backend.config();
+        let dev = stub.add_device(0, "i2c", backend, true).unwrap();
+        start_device(&stub, &dev, &[(0, 256)]);
+
+        let generation = || stub.read(&dev, VIRTIO_MMIO_CONFIG_GENERATION as u64);
+        let read_field = || {
+            (
+                stub.read(&dev, CONFIG_OFFSET),
+                stub.read(&dev, CONFIG_OFFSET + 4),
+            )
+        };
+
+        let before = generation();
+        let low = stub.read(&dev, CONFIG_OFFSET);
+
+        // The backend changes both halves of the field, then reports it.
+        *config.lock().unwrap() = [2u32, 2].iter().flat_map(|v| v.to_le_bytes()).collect();
+        dev.interrupt()
+            .trigger(VirtioInterruptType::Config)
+            .unwrap();
+
+        let high = stub.read(&dev, CONFIG_OFFSET + 4);
+        assert_eq!((low, high), (1, 2));
+        assert_ne!(generation(), before);
+
+        let before = generation();
+        assert_eq!(read_field(), (2, 2));
+        assert_eq!(generation(), before);
+    }
+
+    // The guest reads the device id and features over and over while probing, none of which
+    // waits for the backend.
+    #[test]
+    fn test_id_read_unlocked() {
+        let stub = StubGuest::new(stub_args(&[]));
+        let (dev, _) = stub_device(&stub, vec![1024]);
+        let (tx, rx) = mpsc::channel();
+
+        let gdev = dev.gdev.lock().unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                stub.write(&dev, VIRTIO_MMIO_DEVICE_FEATURES_SEL as u64, 1);
+                tx.send((
+                    stub.read(&dev, VIRTIO_MMIO_DEVICE_ID as u64),
+                    stub.read(&dev, VIRTIO_MMIO_DEVICE_FEATURES as u64),
+                ))
+                .unwrap();
+            });
+
+            let ids = rx.recv_timeout(Duration::from_secs(5));
+            drop(gdev);
+            // i2c, with VIRTIO_F_VERSION_1 added to the backend's features.
+            assert_eq!(ids.unwrap(), (22, 1));
+        });
     }
 }
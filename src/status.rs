@@ -0,0 +1,203 @@
+// Copyright 2022-2023 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Dumps the state of the frontend as JSON, on SIGUSR1.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    mem::{size_of, zeroed},
+    os::unix::io::FromRawFd,
+    ptr,
+    sync::Arc,
+    thread::Builder,
+};
+
+use super::{
+    device::DeviceSnapshot, frontend::XenFrontend, guest::GuestSnapshot, mmio::QueueStats, Error,
+    Result,
+};
+
+pub fn json_str(val: &str) -> String {
+    let mut out = String::from("\"");
+
+    for c in val.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+fn json_list<T>(items: &[T], f: fn(&T) -> String) -> String {
+    format!("[{}]", items.iter().map(f).collect::<Vec<_>>().join(","))
+}
+
+fn json_opt<T: ToString>(val: Option<T>) -> String {
+    val.map_or("null".to_string(), |val| val.to_string())
+}
+
+fn queue_json(queue: &QueueStats) -> String {
+    format!(
+        concat!(
+            "{{\"ready\":{},\"size\":{},\"pending\":{},\"kicks\":{},",
+            "\"last_kick_ms_ago\":{},\"calls\":{}}}"
+        ),
+        queue.ready,
+        queue.size,
+        json_opt(queue.pending),
+        queue.kicks,
+        json_opt(queue.last_kick.map(|time| time.elapsed().as_millis())),
+        queue.calls
+    )
+}
+
+fn region_json(region: &(u64, u64)) -> String {
+    format!("{{\"addr\":{},\"size\":{}}}", region.0, region.1)
+}
+
+fn device_json(dev: &DeviceSnapshot) -> String {
+    format!(
+        "{{\"dev_id\":{},\"compatible\":{},\"addr\":{},\"irq\":{},\"status\":{},\
+         \"driver_features\":{},\"queue_sel\":{},\"activated\":{},\"queues\":{},\
+         \"regions\":{}}}",
+        dev.dev_id,
+        json_str(&dev.compatible),
+        dev.addr,
+        dev.irq,
+        dev.mmio.status,
+        dev.mmio.driver_features,
+        dev.mmio.queue_sel,
+        dev.mmio.activated,
+        json_list(&dev.mmio.queues, queue_json),
+        json_list(&dev.mmio.regions, region_json)
+    )
+}
+
+fn guest_json(guest: &GuestSnapshot) -> String {
+    format!(
+        "{{\"fe_domid\":{},\"ioreq_stalls\":{},\"devices\":{}}}",
+        guest.fe_domid,
+        guest.ioreq_stalls,
+        json_list(&guest.devices, device_json)
+    )
+}
+
+pub fn status_json(guests: &[GuestSnapshot]) -> String {
+    format!("{{\"guests\":{}}}", json_list(guests, guest_json))
+}
+
+// SIGUSR1 is only delivered through the signalfd if all the threads block it. This must be called
+// before any other threads are started, they inherit the signal mask.
+pub fn setup_status_dump(frontend: Arc<XenFrontend>) -> Result<()> {
+    // SAFETY: sigset_t is a plain bitmask, all zeroes is an empty set.
+    let mut set: libc::sigset_t = unsafe { zeroed() };
+
+    // SAFETY: Safe as set is a valid sigset_t.
+    unsafe {
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGUSR1);
+    }
+
+    // SAFETY: Safe as set is a valid sigset_t and the old mask isn't requested.
+    let ret = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+    if ret != 0 {
+        return Err(Error::SignalFd(io::Error::from_raw_os_error(ret)));
+    }
+
+    // SAFETY: Safe as set is a valid sigset_t.
+    let fd = unsafe { libc::signalfd(-1, &set, libc::SFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(Error::SignalFd(io::Error::last_os_error()));
+    }
+
+    // SAFETY: Safe as fd was just created and isn't owned by anyone else.
+    let mut file = unsafe { File::from_raw_fd(fd) };
+
+    Builder::new()
+        .name("status".to_string())
+        .spawn(move || {
+            let mut info = [0u8; size_of::<libc::signalfd_siginfo>()];
+
+            while file.read_exact(&mut info).is_ok() {
+                println!("{}", status_json(&frontend.list()));
+            }
+        })
+        .unwrap();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmio::MmioSnapshot;
+
+    fn queue(ready: bool, size: u32, pending: Option<u16>, kicks: u64, calls: u64) -> QueueStats {
+        QueueStats {
+            ready,
+            size,
+            pending,
+            kicks,
+            last_kick: None,
+            calls,
+        }
+    }
+
+    #[test]
+    fn test_json_str() {
+        assert_eq!(json_str("virtio,device22"), r#""virtio,device22""#);
+        assert_eq!(json_str("a\"b\\c\nd\u{1}é"), r#""a\"b\\c\u000ad\u0001é""#);
+    }
+
+    #[test]
+    fn test_status_json() {
+        let dev = DeviceSnapshot {
+            dev_id: 0,
+            compatible: "virtio,\"i2c\"".to_string(),
+            addr: 0x2000000,
+            irq: 33,
+            mmio: MmioSnapshot {
+                status: 15,
+                driver_features: 1 << 32,
+                queue_sel: 1,
+                activated: true,
+                queues: vec![queue(true, 256, Some(3), 5, 2), queue(false, 0, None, 0, 0)],
+                regions: vec![(0x40000000, 0x1000)],
+            },
+        };
+        let guests = [
+            GuestSnapshot {
+                fe_domid: 1,
+                devices: vec![dev],
+                ioreq_stalls: 1,
+            },
+            GuestSnapshot {
+                fe_domid: 2,
+                devices: Vec::new(),
+                ioreq_stalls: 0,
+            },
+        ];
+
+        assert_eq!(
+            status_json(&guests),
+            concat!(
+                r#"{"guests":[{"fe_domid":1,"ioreq_stalls":1,"devices":[{"dev_id":0,"#,
+                r#""compatible":"virtio,\"i2c\"","addr":33554432,"irq":33,"status":15,"#,
+                r#""driver_features":4294967296,"queue_sel":1,"activated":true,"queues":["#,
+                r#"{"ready":true,"size":256,"pending":3,"kicks":5,"last_kick_ms_ago":null,"#,
+                r#""calls":2},{"ready":false,"size":0,"pending":null,"kicks":0,"#,
+                r#""last_kick_ms_ago":null,"calls":0}],"regions":[{"addr":1073741824,"#,
+                r#""size":4096}]}]},{"fe_domid":2,"ioreq_stalls":0,"devices":[]}]}"#
+            )
+        );
+        assert_eq!(status_json(&[]), r#"{"guests":[]}"#);
+    }
+}
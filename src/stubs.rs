@@ -0,0 +1,708 @@
+// Copyright 2022-2023 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Stand-ins for the Xen handles, Xenstore and the vhost-user backend, to run the frontend in tests
+// without Xen. The stubs record the calls made to them, and raise ioreqs on the event channel the
+// way Xen does for a guest accessing a device.
+
+use std::{
+    cell::UnsafeCell,
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io,
+    mem::zeroed,
+    os::unix::io::AsRawFd,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::Duration,
+};
+
+use clap::Parser;
+use vhost::vhost_user::message::VhostUserProtocolFeatures;
+use vhost_user_frontend::{GuestMemoryMmap, GuestRegionMmap, VirtioInterrupt};
+use virtio_queue::Queue;
+use vm_memory::{GuestAddress, GuestMemoryAtomic, MmapRange, MmapRegion};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+use xen_bindings::bindings::{
+    ioreq, ioservid_t, xenbus_state_XenbusStateInitWait, xs_watch_type, IOREQ_READ,
+    IOREQ_TYPE_COPY, IOREQ_WRITE, STATE_IOREQ_READY, XC_PAGE_SIZE,
+};
+
+use super::{
+    backend::Backend,
+    device::{
+        DeviceArgs, DeviceContext, DeviceSetup, InterruptMode, XenDevice, VIRTIO_MMIO_IO_SIZE,
+    },
+    guest::XenGuest,
+    xdm::DeviceModel,
+    xec::EventChannel,
+    xfm::{ForeignMemory, MemoryMapper},
+    xs::{Store, Transaction, XsHandle},
+    Error, Result, BACKEND_PATH,
+};
+
+pub const STUB_DOMID: u16 = 1;
+pub const STUB_VCPUS: u32 = 2;
+pub const STUB_GUEST_SIZE: usize = 16 << 20;
+pub const STUB_GUEST_PAGES: u64 = (STUB_GUEST_SIZE / XC_PAGE_SIZE as usize) as u64;
+// Base address and irq of the first device, the next ones follow.
+pub const STUB_ADDR: u64 = 0x2000000;
+pub const STUB_IRQ: u32 = 33;
+// Event channel port of the first vcpu, the next ones follow.
+const STUB_PORT: u32 = 10;
+// Local ports bound to the ports of the guest's devices start here.
+const STUB_BOUND_PORT: u32 = 100;
+// The port the guest allocates for the interrupt of device 0, with --interrupt event-channel.
+pub const STUB_GUEST_PORT: u32 = 7;
+const IOREQ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Options of the frontend, with the ones passed added to the mandatory ones.
+pub fn stub_args(args: &[&str]) -> DeviceArgs {
+    let mut argv = vec!["xen-vhost-frontend", "--socket-path", "/tmp/"];
+    argv.extend_from_slice(args);
+
+    DeviceArgs::parse_from(argv)
+}
+
+// Calls made to a stub, like "set_irqfd 33 true", in order.
+#[derive(Default)]
+pub struct Calls(Mutex<Vec<String>>);
+
+impl Calls {
+    fn push(&self, call: String) {
+        self.0.lock().unwrap().push(call);
+    }
+
+    // Returns the calls made since the last time.
+    pub fn take(&self) -> Vec<String> {
+        self.0.lock().unwrap().drain(..).collect()
+    }
+}
+
+// What Xen shares with the frontend, the ioreq of each vcpu and the event channel they are raised
+// on.
+pub struct StubXen {
+    pub calls: Calls,
+    // Makes registering irqfds fail, like Xen does for an irq the guest doesn't have.
+    pub fail_irqfd: AtomicBool,
+    // Number of pages reported for the domain by each read, the last one stays.
+    dom_pages: Mutex<VecDeque<u64>>,
+    ioreqs: Vec<UnsafeCell<ioreq>>,
+    event: EventFd,
+    // Vcpus with an ioreq ready, in the order they were raised.
+    pending: Mutex<VecDeque<u32>>,
+    // Ports notified by the frontend on completing an ioreq.
+    completed: Mutex<Vec<u32>>,
+    completion: Condvar,
+}
+
+// SAFETY: Like the shared ioreq page, an ioreq is only accessed by the test while it isn't pending,
+// and by the frontend while it is.
+unsafe impl Sync for StubXen {}
+
+impl StubXen {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            calls: Calls::default(),
+            fail_irqfd: AtomicBool::new(false),
+            dom_pages: Mutex::new(VecDeque::from(vec![STUB_GUEST_PAGES])),
+            // SAFETY: ioreq is plain data, all zeroes is a valid value.
+            ioreqs: (0..STUB_VCPUS)
+                .map(|_| UnsafeCell::new(unsafe { zeroed() }))
+                .collect(),
+            event: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pending: Mutex::new(VecDeque::new()),
+            completed: Mutex::new(Vec::new()),
+            completion: Condvar::new(),
+        })
+    }
+
+    // Makes the domain report these numbers of pages, like a domain that is still being built. At
+    // least one is needed.
+    pub fn set_dom_pages(&self, pages: &[u64]) {
+        *self.dom_pages.lock().unwrap() = pages.iter().copied().collect();
+    }
+
+    pub fn dom_pages(&self, domid: u16) -> Result<u64> {
+        let mut pages = self.dom_pages.lock().unwrap();
+
+        if domid != STUB_DOMID {
+            return Err(Error::InvalidDomainInfo(0, domid, 0));
+        }
+
+        if pages.len() > 1 {
+            Ok(pages.pop_front().unwrap())
+        } else {
+            Ok(pages[0])
+        }
+    }
+
+    // Raises the ioreq on the vcpu and waits for the frontend to complete it. Returns the ioreq as
+    // completed, or None if it wasn't.
+    pub fn send_ioreq(&self, vcpu: u32, req: ioreq) -> Option<ioreq> {
+        let cell = &self.ioreqs[vcpu as usize];
+
+        // SAFETY: The ioreq isn't pending, the frontend doesn't access it.
+        unsafe { *cell.get() = req };
+
+        // Other vcpus may be sending ioreqs at the same time, only the completions notified on
+        // the port of this one count.
+        let port = STUB_PORT + vcpu;
+        let count = |completed: &Vec<u32>| completed.iter().filter(|&&p| p == port).count();
+
+        let done = count(&self.completed.lock().unwrap());
+        self.pending.lock().unwrap().push_back(vcpu);
+        self.event.write(1).unwrap();
+
+        let (completed, _) = self
+            .completion
+            .wait_timeout_while(self.completed.lock().unwrap(), IOREQ_TIMEOUT, |c| {
+                count(c) == done
+            })
+            .unwrap();
+
+        if count(&completed) == done {
+            return None;
+        }
+
+        // SAFETY: The ioreq is completed, the frontend doesn't access it anymore.
+        Some(unsafe { *cell.get() })
+    }
+
+    // Returns the pending vcpu, along with its port.
+    fn pending(&self) -> Option<(u32, u32)> {
+        let mut pending = self.pending.lock().unwrap();
+        let vcpu = pending.pop_front()?;
+
+        // The event counts all the pending ioreqs at once, raise it again for the rest.
+        if !pending.is_empty() {
+            self.event.write(1).unwrap();
+        }
+
+        Some((STUB_PORT + vcpu, vcpu))
+    }
+
+    fn complete(&self, port: u32) {
+        self.completed.lock().unwrap().push(port);
+        self.completion.notify_all();
+    }
+}
+
+pub struct StubDeviceModel(Arc<StubXen>);
+
+impl StubDeviceModel {
+    pub fn new(xen: Arc<StubXen>) -> Self {
+        Self(xen)
+    }
+}
+
+impl DeviceModel for StubDeviceModel {
+    fn ioserver_id(&self) -> u16 {
+        0
+    }
+
+    fn vcpus(&self) -> u32 {
+        STUB_VCPUS
+    }
+
+    fn create_ioreq_server(&mut self) -> Result<()> {
+        self.0.calls.push("create_ioreq_server".to_string());
+        Ok(())
+    }
+
+    fn set_ioreq_server_state(&self, enabled: i32) -> Result<()> {
+        self.0
+            .calls
+            .push(format!("set_ioreq_server_state {}", enabled));
+        Ok(())
+    }
+
+    fn map_io_range_to_ioreq_server(&mut self, start: u64, size: u64) -> Result<()> {
+        self.0
+            .calls
+            .push(format!("map_io_range {:#x} {:#x}", start, size));
+        Ok(())
+    }
+
+    fn ummap_io_range_from_ioreq_server(&self, start: u64, size: u64) -> Result<()> {
+        self.0
+            .calls
+            .push(format!("unmap_io_range {:#x} {:#x}", start, size));
+        Ok(())
+    }
+
+    fn set_irqfd(&self, _fd: EventFd, irq: u32, set: bool) -> Result<()> {
+        self.0.calls.push(format!("set_irqfd {} {}", irq, set));
+
+        if self.0.fail_irqfd.load(Ordering::Acquire) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL).into());
+        }
+        Ok(())
+    }
+
+    fn set_ioeventfd(
+        &self,
+        _kick: &EventFd,
+        _ioreq: &mut ioreq,
+        _ports: &[u32],
+        addr: u64,
+        vq: u32,
+        set: bool,
+    ) -> Result<()> {
+        self.0
+            .calls
+            .push(format!("set_ioeventfd {:#x} {} {}", addr, vq, set));
+        Ok(())
+    }
+}
+
+pub struct StubEventChannel {
+    xen: Arc<StubXen>,
+    ports: Vec<u32>,
+}
+
+impl StubEventChannel {
+    pub fn new(xen: Arc<StubXen>) -> Self {
+        Self {
+            xen,
+            ports: (0..STUB_VCPUS).map(|vcpu| STUB_PORT + vcpu).collect(),
+        }
+    }
+}
+
+impl EventChannel for StubEventChannel {
+    fn ports(&self) -> &[u32] {
+        &self.ports
+    }
+
+    fn bind(&mut self, _xfm: &dyn ForeignMemory, _domid: u16, _vcpus: u32) -> Result<()> {
+        Ok(())
+    }
+
+    fn unbind(&self) {}
+
+    fn fd(&self) -> Result<u32> {
+        Ok(self.xen.event.as_raw_fd() as u32)
+    }
+
+    fn pending(&mut self) -> Result<(u32, u32)> {
+        self.xen.event.read()?;
+        self.xen.pending().ok_or(Error::UnknownEventPort(0))
+    }
+
+    fn unmask(&mut self, _port: u32) -> Result<()> {
+        Ok(())
+    }
+
+    // Ports bound with bind_port() raise the interrupt of a device, the others complete ioreqs.
+    fn notify(&self, port: u32) -> Result<()> {
+        if port >= STUB_BOUND_PORT {
+            self.xen.calls.push(format!("notify {}", port));
+        } else {
+            self.xen.complete(port);
+        }
+        Ok(())
+    }
+
+    fn open(&self) -> Result<Box<dyn EventChannel>> {
+        Ok(Box::new(Self {
+            xen: self.xen.clone(),
+            ports: Vec::new(),
+        }))
+    }
+
+    fn bind_port(&mut self, domid: u16, remote: u32) -> Result<u32> {
+        let port = STUB_BOUND_PORT + remote;
+
+        self.xen
+            .calls
+            .push(format!("bind_port {} {} {}", domid, remote, port));
+        self.ports.push(port);
+        Ok(port)
+    }
+}
+
+pub struct StubForeignMemory(Arc<StubXen>);
+
+impl StubForeignMemory {
+    pub fn new(xen: Arc<StubXen>) -> Self {
+        Self(xen)
+    }
+}
+
+impl ForeignMemory for StubForeignMemory {
+    fn map_resource(&mut self, _domid: u16, _id: ioservid_t) -> Result<()> {
+        Ok(())
+    }
+
+    fn ioreq(&self, vcpu: u32) -> Result<&mut ioreq> {
+        // SAFETY: Safe as the ioreqs are only accessed with the xfm lock held, see StubXen.
+        Ok(unsafe { &mut *self.0.ioreqs[vcpu as usize].get() })
+    }
+
+    fn mapper(&self) -> Arc<dyn MemoryMapper> {
+        Arc::new(StubMemoryMapper)
+    }
+}
+
+// Guest memory is plain anonymous memory, there are no Xen device nodes to map it from.
+pub struct StubMemoryMapper;
+
+impl MemoryMapper for StubMemoryMapper {
+    // Any file does, it isn't mapped.
+    fn open_dev(&self, _path: &str) -> Result<File> {
+        Ok(File::open("/dev/null")?)
+    }
+
+    fn mmap_region(
+        &self,
+        addr: GuestAddress,
+        size: usize,
+        _file: Arc<File>,
+        _flags: u32,
+        _data: u32,
+    ) -> Result<GuestRegionMmap> {
+        let range = MmapRange::new_unix(size, None, addr);
+        let region = MmapRegion::from_range(range).map_err(|_| Error::RegionMmap(addr.0))?;
+
+        GuestRegionMmap::new(region, addr).map_err(|_| Error::RegionMmap(addr.0))
+    }
+}
+
+// Xenstore nodes, shared by all the handles of a guest.
+pub type StubNodes = Arc<Mutex<HashMap<String, String>>>;
+
+pub struct StubStore {
+    nodes: StubNodes,
+    watches: Arc<StubWatches>,
+}
+
+impl StubStore {
+    pub fn new(nodes: StubNodes) -> Self {
+        Self {
+            nodes,
+            watches: Arc::new(StubWatches {
+                events: Mutex::new(VecDeque::new()),
+                fd: EventFd::new(EFD_NONBLOCK).unwrap(),
+            }),
+        }
+    }
+
+    // The watch events of the store, fired by the test. Nothing fires them otherwise.
+    pub fn watches(&self) -> Arc<StubWatches> {
+        self.watches.clone()
+    }
+}
+
+// Watch events not read yet, the eventfd is readable as long as there are some.
+pub struct StubWatches {
+    events: Mutex<VecDeque<String>>,
+    fd: EventFd,
+}
+
+impl StubWatches {
+    // Queues a watch event for the path, like Xenstore does when a watched node changes. The path
+    // is also the token, like for the watches of XsHandle.
+    pub fn fire(&self, path: &str) {
+        self.events.lock().unwrap().push_back(path.to_string());
+        self.fd.write(1).unwrap();
+    }
+
+    fn next(&self) -> io::Result<String> {
+        let mut events = self.events.lock().unwrap();
+        let path = events
+            .pop_front()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+
+        if events.is_empty() {
+            self.fd.read().ok();
+        }
+        Ok(path)
+    }
+}
+
+impl Store for StubStore {
+    fn read_str(&self, path: &str) -> io::Result<String> {
+        self.nodes
+            .lock()
+            .unwrap()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn write_str(&self, path: &str, val: &str) -> io::Result<()> {
+        self.nodes
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), val.to_string());
+        Ok(())
+    }
+
+    fn fileno(&self) -> io::Result<i32> {
+        Ok(self.watches.fd.as_raw_fd())
+    }
+
+    fn create_watch(&mut self, _path: &str, _token: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_watch(&self, _index: xs_watch_type) -> io::Result<String> {
+        self.watches.next()
+    }
+
+    // The nodes are locked for the whole transaction, which never has to be run again.
+    fn transaction(&self, f: &mut dyn FnMut(&dyn Transaction) -> Result<()>) -> Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let t = StubTransaction(Mutex::new(nodes.clone()));
+
+        f(&t)?;
+        *nodes = t.0.into_inner().unwrap();
+        Ok(())
+    }
+}
+
+// A copy of the nodes, which replaces them once the transaction is committed.
+struct StubTransaction(Mutex<HashMap<String, String>>);
+
+impl Transaction for StubTransaction {
+    fn read_str(&self, path: &str) -> io::Result<String> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn write_str(&self, path: &str, val: &str) -> io::Result<()> {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), val.to_string());
+        Ok(())
+    }
+}
+
+pub struct StubBackend {
+    sizes: Vec<u16>,
+    features: u64,
+    // Shared with the test, which changes it the way a backend does.
+    config: Arc<Mutex<Vec<u8>>>,
+    calls: Arc<Calls>,
+    // Number of activations to fail, before the next ones succeed.
+    failed_activations: usize,
+}
+
+impl StubBackend {
+    pub fn new(sizes: Vec<u16>, features: u64, calls: Arc<Calls>) -> Self {
+        Self {
+            sizes,
+            features,
+            config: Arc::default(),
+            calls,
+            failed_activations: 0,
+        }
+    }
+
+    pub fn with_config(self, config: Vec<u8>) -> Self {
+        *self.config.lock().unwrap() = config;
+        self
+    }
+
+    pub fn config(&self) -> Arc<Mutex<Vec<u8>>> {
+        self.config.clone()
+    }
+
+    pub fn with_failed_activations(mut self, count: usize) -> Self {
+        self.failed_activations = count;
+        self
+    }
+}
+
+impl Backend for StubBackend {
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.sizes
+    }
+
+    fn device_features(&self) -> u64 {
+        self.features
+    }
+
+    fn negotiate_features(
+        &mut self,
+        features: u64,
+        _protocol: VhostUserProtocolFeatures,
+    ) -> Result<()> {
+        self.calls
+            .push(format!("negotiate_features {:#x}", features));
+        Ok(())
+    }
+
+    // Reads past the end of the config space return zeroes.
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let config = self.config.lock().unwrap();
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = *config.get(offset as usize + i).unwrap_or(&0);
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        self.calls
+            .push(format!("write_config {:#x} {:?}", offset, data));
+    }
+
+    fn activate(
+        &mut self,
+        _mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        _interrupt: Arc<dyn VirtioInterrupt>,
+        queues: Vec<(usize, Queue, EventFd)>,
+    ) -> Result<()> {
+        let queues: Vec<usize> = queues.iter().map(|(index, _, _)| *index).collect();
+        self.calls.push(format!("activate {:?}", queues));
+
+        // Like a backend that went away.
+        if self.failed_activations > 0 {
+            self.failed_activations -= 1;
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe).into());
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.calls.push("reset".to_string());
+    }
+
+    fn shutdown(&mut self) {
+        self.calls.push("shutdown".to_string());
+    }
+}
+
+// A guest served over the stubs, exited when dropped.
+pub struct StubGuest {
+    pub guest: Arc<XenGuest>,
+    pub xen: Arc<StubXen>,
+    pub nodes: StubNodes,
+}
+
+impl StubGuest {
+    pub fn new(args: DeviceArgs) -> Self {
+        let xen = StubXen::new();
+        let ctx = Arc::new(DeviceContext::new(args).unwrap());
+
+        let guest = XenGuest::with_handles(
+            STUB_DOMID,
+            ctx,
+            STUB_GUEST_SIZE,
+            Box::new(StubDeviceModel::new(xen.clone())),
+            Box::new(StubEventChannel::new(xen.clone())),
+            Box::new(StubForeignMemory::new(xen.clone())),
+        )
+        .unwrap();
+
+        Self {
+            guest,
+            xen,
+            nodes: StubNodes::default(),
+        }
+    }
+
+    // Adds a device of the type over the backend, as if the toolstack created it and the guest
+    // moved to the xenbus InitWait state already.
+    pub fn add_device(
+        &self,
+        dev_id: u32,
+        dev_type: &str,
+        backend: StubBackend,
+        foreign_mapping: bool,
+    ) -> Result<Arc<XenDevice>> {
+        let dev = self.new_device(dev_id, dev_type, backend, foreign_mapping)?;
+        self.guest.add_device(dev.clone())?;
+
+        Ok(dev)
+    }
+
+    // Sets up a device like add_device(), without adding it to the guest.
+    pub fn new_device(
+        &self,
+        dev_id: u32,
+        dev_type: &str,
+        backend: StubBackend,
+        foreign_mapping: bool,
+    ) -> Result<Arc<XenDevice>> {
+        let be = format!("{}/{}/{}", BACKEND_PATH, STUB_DOMID, dev_id);
+        let fe = format!("/local/domain/{}/device/virtio/{}", STUB_DOMID, dev_id);
+
+        let xsh = XsHandle::with_store(Box::new(StubStore::new(self.nodes.clone())));
+        xsh.set_backend_state(&be, xenbus_state_XenbusStateInitWait)?;
+
+        let setup = DeviceSetup {
+            dev_id,
+            be,
+            fe,
+            addr: STUB_ADDR + dev_id as u64 * VIRTIO_MMIO_IO_SIZE,
+            irq: STUB_IRQ + dev_id,
+            port: match self.guest.ctx.args.interrupt {
+                InterruptMode::Irqfd => None,
+                InterruptMode::EventChannel => Some(STUB_GUEST_PORT + dev_id),
+            },
+            foreign_mapping,
+        };
+
+        XenDevice::with_test_backend(self.guest.clone(), xsh, setup, dev_type, Box::new(backend))
+    }
+
+    pub fn node(&self, path: &str) -> Option<String> {
+        self.nodes.lock().unwrap().get(path).cloned()
+    }
+
+    // Accesses the device at the offset from vcpu 0, the way the guest does. Returns the ioreq as
+    // completed by the frontend.
+    pub fn access(&self, dev: &XenDevice, offset: u64, size: u32, dir: u32, data: u64) -> ioreq {
+        self.access_from(0, dev, offset, size, dir, data)
+    }
+
+    // Like access(), from the vcpu. Each vcpu has one ioreq at a time, the accesses made from the
+    // same vcpu must not overlap.
+    pub fn access_from(
+        &self,
+        vcpu: u32,
+        dev: &XenDevice,
+        offset: u64,
+        size: u32,
+        dir: u32,
+        data: u64,
+    ) -> ioreq {
+        // SAFETY: ioreq is plain data, all zeroes is a valid value.
+        let mut req: ioreq = unsafe { zeroed() };
+        req.addr = dev.addr + offset;
+        req.size = size;
+        req.count = 1;
+        req.data = data;
+        req.type_ = IOREQ_TYPE_COPY as u8;
+        req.set_dir(dir as u8);
+        req.set_state(STATE_IOREQ_READY as u8);
+
+        self.xen
+            .send_ioreq(vcpu, req)
+            .expect("ioreq wasn't completed")
+    }
+
+    pub fn read(&self, dev: &XenDevice, offset: u64) -> u32 {
+        self.access(dev, offset, 4, IOREQ_READ, 0).data as u32
+    }
+
+    pub fn write(&self, dev: &XenDevice, offset: u64, val: u32) {
+        self.access(dev, offset, 4, IOREQ_WRITE, val as u64);
+    }
+}
+
+impl Drop for StubGuest {
+    fn drop(&mut self) {
+        self.guest.exit();
+    }
+}
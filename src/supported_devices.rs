@@ -5,9 +5,86 @@
 //
 // This file keeps list of the supported devices and their designated Virtio device ids.
 
+use std::ops::Range;
+
 use lazy_static::lazy_static;
 
 lazy_static! {
-    pub static ref SUPPORTED_DEVICES: Vec<(&'static str, u32)> =
-        vec![("i2c", 22), ("fs", 26), ("gpio", 29)];
+    pub static ref SUPPORTED_DEVICES: Vec<(&'static str, u32)> = vec![
+        ("scsi", 8),
+        ("input", 18),
+        ("i2c", 22),
+        ("fs", 26),
+        ("gpio", 29)
+    ];
+}
+
+// Number and maximum size of the virtqueues, for the devices vhost-user-frontend doesn't know
+// about.
+pub fn queues(name: &str) -> Option<(usize, u16)> {
+    match name {
+        // controlq, eventq and a single request queue, until the backend's config space tells how
+        // many request queues there are, see config_queues().
+        "scsi" => Some((3, 1024)),
+        _ => None,
+    }
+}
+
+// Number of virtqueues of the devices that tell it in their config space, read with `read_config`,
+// None for the other ones or if the config space doesn't tell.
+pub fn config_queues<F>(name: &str, read_config: F) -> Option<usize>
+where
+    F: Fn(u64, &mut [u8]),
+{
+    match name {
+        // controlq and eventq, followed by num_queues request queues, the first field of struct
+        // virtio_scsi_config.
+        "scsi" => {
+            let mut num_queues = [0u8; 4];
+            read_config(0, &mut num_queues);
+
+            match u32::from_le_bytes(num_queues) {
+                0 => None,
+                num => Some(2 + num as usize),
+            }
+        }
+        _ => None,
+    }
+}
+
+// Part of the device's config space the driver is allowed to write, the rest is read-only.
+pub fn writable_config(name: &str) -> Option<Range<u64>> {
+    match name {
+        // select and subsel of struct virtio_input_config.
+        "input" => Some(0..2),
+        // sense_size and cdb_size of struct virtio_scsi_config.
+        "scsi" => Some(20..28),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_queues() {
+        let read = |config: [u8; 4]| {
+            move |offset: u64, data: &mut [u8]| {
+                data.copy_from_slice(&config[offset as usize..][..data.len()])
+            }
+        };
+
+        assert_eq!(config_queues("scsi", read([1, 0, 0, 0])), Some(3));
+        assert_eq!(config_queues("scsi", read([0, 1, 0, 0])), Some(258));
+        assert_eq!(config_queues("scsi", read([0, 0, 0, 0])), None);
+        assert_eq!(config_queues("i2c", read([1, 0, 0, 0])), None);
+    }
+
+    #[test]
+    fn test_writable_config() {
+        assert_eq!(writable_config("scsi"), Some(20..28));
+        assert_eq!(writable_config("input"), Some(0..2));
+        assert_eq!(writable_config("gpio"), None);
+    }
 }
@@ -0,0 +1,131 @@
+// Copyright 2022-2023 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Trace of the MMIO accesses of all the devices, for debugging guest drivers. Each access is
+// written as a line of JSON, once it is handled:
+//
+//   {"time_us":..,"domid":..,"dev_id":..,"offset":..,"size":..,"dir":"read"|"write","data":..,
+//    "error":null|".."}
+//
+// "data" is the value written, or the value returned to the guest for reads.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use xen_bindings::bindings::{ioreq, IOREQ_READ};
+
+use super::{status::json_str, Error, Result};
+
+pub struct MmioTrace(Mutex<File>);
+
+impl MmioTrace {
+    pub fn new(path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(Error::MmioTrace)?;
+
+        Ok(Self(Mutex::new(file)))
+    }
+
+    pub fn record(&self, domid: u16, dev_id: u32, offset: u64, ioreq: &ioreq, result: &Result<()>) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_micros());
+        let dir = if ioreq.dir() as u32 == IOREQ_READ {
+            "read"
+        } else {
+            "write"
+        };
+        let error = match result {
+            Ok(()) => "null".to_string(),
+            Err(e) => json_str(&e.to_string()),
+        };
+
+        let line = format!(
+            concat!(
+                "{{\"time_us\":{},\"domid\":{},\"dev_id\":{},\"offset\":{},\"size\":{},",
+                "\"dir\":\"{}\",\"data\":{},\"error\":{}}}"
+            ),
+            time, domid, dev_id, offset, ioreq.size, dir, ioreq.data, error
+        );
+
+        // The trace is best effort, the guest is served either way.
+        if let Err(e) = writeln!(self.0.lock().unwrap(), "{}", line) {
+            println!("Failed to write MMIO trace: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stubs::{stub_args, Calls, StubBackend, StubGuest, STUB_DOMID};
+    use std::{env, fs, path::Path, process, sync::Arc};
+    use virtio_bindings::virtio_mmio::{
+        VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_QUEUE_NUM, VIRTIO_MMIO_STATUS,
+    };
+
+    // The lines of the trace, without their time.
+    fn trace_lines(path: &Path) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let (time, rest) = line.split_once(',').unwrap();
+                assert!(time.starts_with("{\"time_us\":"));
+                rest.to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_mmio_trace() {
+        let path = env::temp_dir().join(format!("xen-vhost-frontend-{}-trace", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let stub = StubGuest::new(stub_args(&[
+            "--mmio-trace",
+            path.to_str().unwrap(),
+            "--max-queue-size",
+            "256",
+        ]));
+        let backend = StubBackend::new(vec![1024], 0, Arc::new(Calls::default()));
+        let dev = stub.add_device(1, "i2c", backend, true).unwrap();
+
+        stub.read(&dev, VIRTIO_MMIO_MAGIC_VALUE as u64);
+        stub.write(&dev, VIRTIO_MMIO_STATUS as u64, 1);
+        // Not a power of two.
+        stub.write(&dev, VIRTIO_MMIO_QUEUE_NUM as u64, 3);
+
+        let prefix = format!("\"domid\":{},\"dev_id\":1", STUB_DOMID);
+        let error = json_str(&Error::InvalidQueueSize(3, 256).to_string());
+        assert_eq!(
+            trace_lines(&path),
+            [
+                format!(
+                    r#"{},"offset":0,"size":4,"dir":"read","data":1953655158,"error":null}}"#,
+                    prefix
+                ),
+                format!(
+                    r#"{},"offset":112,"size":4,"dir":"write","data":1,"error":null}}"#,
+                    prefix
+                ),
+                format!(
+                    r#"{},"offset":56,"size":4,"dir":"write","data":3,"error":{}}}"#,
+                    prefix, error
+                ),
+            ]
+        );
+
+        drop(stub);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -5,12 +5,33 @@
 
 use vmm_sys_util::eventfd::EventFd;
 
-use super::{Error, Result};
+use super::Result;
 use xen_bindings::bindings::ioreq;
 use xen_ioctls::{XenDeviceModelHandle, HVM_IOREQSRV_BUFIOREQ_OFF};
 
 pub const VIRTIO_IRQ_HIGH: u32 = 1;
 
+// Device model operations on a guest's ioreq server, implemented over the Xen device model handle by
+// XenDeviceModel.
+pub trait DeviceModel {
+    fn ioserver_id(&self) -> u16;
+    fn vcpus(&self) -> u32;
+    fn create_ioreq_server(&mut self) -> Result<()>;
+    fn set_ioreq_server_state(&self, enabled: i32) -> Result<()>;
+    fn map_io_range_to_ioreq_server(&mut self, start: u64, size: u64) -> Result<()>;
+    fn ummap_io_range_from_ioreq_server(&self, start: u64, size: u64) -> Result<()>;
+    fn set_irqfd(&self, fd: EventFd, irq: u32, set: bool) -> Result<()>;
+    fn set_ioeventfd(
+        &self,
+        kick: &EventFd,
+        ioreq: &mut ioreq,
+        ports: &[u32],
+        addr: u64,
+        vq: u32,
+        set: bool,
+    ) -> Result<()>;
+}
+
 pub struct XenDeviceModel {
     xdmh: XenDeviceModelHandle,
     id: Option<u16>,
@@ -20,7 +41,7 @@ pub struct XenDeviceModel {
 
 impl XenDeviceModel {
     pub fn new(domid: u16) -> Result<Self> {
-        let xdmh = XenDeviceModelHandle::new().map_err(Error::XenIoctlError)?;
+        let xdmh = XenDeviceModelHandle::new()?;
 
         // Create the domain struct earlier so Drop can be called in case of errors.
         let mut xdm = Self {
@@ -30,73 +51,77 @@ impl XenDeviceModel {
             vcpus: 0,
         };
 
-        xdm.vcpus = xdm.xdmh.nr_vcpus(domid).map_err(Error::XenIoctlError)?;
+        xdm.vcpus = xdm.xdmh.nr_vcpus(domid)?;
 
         Ok(xdm)
     }
 
-    pub fn ioserver_id(&self) -> u16 {
+    fn destroy_ioreq_server(&mut self) -> Result<()> {
+        if let Some(id) = self.id.take() {
+            self.xdmh.destroy_ioreq_server(self.domid, id)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl DeviceModel for XenDeviceModel {
+    fn ioserver_id(&self) -> u16 {
         self.id.unwrap()
     }
 
-    pub fn vcpus(&self) -> u32 {
+    fn vcpus(&self) -> u32 {
         self.vcpus
     }
 
-    pub fn create_ioreq_server(&mut self) -> Result<()> {
+    fn create_ioreq_server(&mut self) -> Result<()> {
         self.id = Some(
             self.xdmh
-                .create_ioreq_server(self.domid, HVM_IOREQSRV_BUFIOREQ_OFF)
-                .map_err(Error::XenIoctlError)?,
+                .create_ioreq_server(self.domid, HVM_IOREQSRV_BUFIOREQ_OFF)?,
         );
 
         Ok(())
     }
 
-    fn destroy_ioreq_server(&mut self) -> Result<()> {
-        if let Some(id) = self.id.take() {
-            self.xdmh
-                .destroy_ioreq_server(self.domid, id)
-                .map_err(Error::XenIoctlError)
-        } else {
-            Ok(())
-        }
-    }
-
-    pub fn set_ioreq_server_state(&self, enabled: i32) -> Result<()> {
-        self.xdmh
-            .set_ioreq_server_state(self.domid, self.ioserver_id(), enabled)
-            .map_err(Error::XenIoctlError)
+    fn set_ioreq_server_state(&self, enabled: i32) -> Result<()> {
+        Ok(self
+            .xdmh
+            .set_ioreq_server_state(self.domid, self.ioserver_id(), enabled)?)
     }
 
-    pub fn map_io_range_to_ioreq_server(&mut self, start: u64, size: u64) -> Result<()> {
+    fn map_io_range_to_ioreq_server(&mut self, start: u64, size: u64) -> Result<()> {
         let end = start + size - 1;
 
-        self.xdmh
-            .map_io_range_to_ioreq_server(self.domid, self.ioserver_id(), 1, start, end)
-            .map_err(Error::XenIoctlError)
+        Ok(self
+            .xdmh
+            .map_io_range_to_ioreq_server(self.domid, self.ioserver_id(), 1, start, end)?)
     }
 
-    pub fn ummap_io_range_from_ioreq_server(&self, start: u64, size: u64) -> Result<()> {
+    fn ummap_io_range_from_ioreq_server(&self, start: u64, size: u64) -> Result<()> {
         let end = start + size - 1;
 
-        self.xdmh
-            .unmap_io_range_from_ioreq_server(self.domid, self.ioserver_id(), 1, start, end)
-            .map_err(Error::XenIoctlError)
+        Ok(self.xdmh.unmap_io_range_from_ioreq_server(
+            self.domid,
+            self.ioserver_id(),
+            1,
+            start,
+            end,
+        )?)
     }
 
-    pub fn set_irqfd(&self, fd: EventFd, irq: u32, set: bool) -> Result<()> {
+    fn set_irqfd(&self, fd: EventFd, irq: u32, set: bool) -> Result<()> {
         if set {
             self.xdmh
-                .set_irqfd(fd, self.domid, irq, VIRTIO_IRQ_HIGH as u8)
+                .set_irqfd(fd, self.domid, irq, VIRTIO_IRQ_HIGH as u8)?;
         } else {
             self.xdmh
-                .clear_irqfd(fd, self.domid, irq, VIRTIO_IRQ_HIGH as u8)
+                .clear_irqfd(fd, self.domid, irq, VIRTIO_IRQ_HIGH as u8)?;
         }
-        .map_err(Error::XenIoctlError)
+
+        Ok(())
     }
 
-    pub fn set_ioeventfd(
+    fn set_ioeventfd(
         &self,
         kick: &EventFd,
         ioreq: &mut ioreq,
@@ -107,12 +132,13 @@ impl XenDeviceModel {
     ) -> Result<()> {
         if set {
             self.xdmh
-                .set_ioeventfd(kick, ioreq, ports, addr, 4, vq, self.vcpus, self.domid)
+                .set_ioeventfd(kick, ioreq, ports, addr, 4, vq, self.vcpus, self.domid)?;
         } else {
             self.xdmh
-                .clear_ioeventfd(kick, ioreq, ports, addr, 4, vq, self.vcpus, self.domid)
+                .clear_ioeventfd(kick, ioreq, ports, addr, 4, vq, self.vcpus, self.domid)?;
         }
-        .map_err(Error::XenIoctlError)
+
+        Ok(())
     }
 }
 
@@ -3,9 +3,27 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use super::{xfm::XenForeignMemory, Error, Result};
+use super::{xfm::ForeignMemory, Error, Result};
 use xen_ioctls::XenEventChannelHandle;
 
+// Event channels bound to a guest's vcpus, implemented over the Xen event channel handle by
+// XenEventChannel.
+pub trait EventChannel {
+    fn ports(&self) -> &[u32];
+    fn bind(&mut self, xfm: &dyn ForeignMemory, domid: u16, vcpus: u32) -> Result<()>;
+    fn unbind(&self);
+    fn fd(&self) -> Result<u32>;
+    // Returns the pending port, along with the vcpu it is bound to.
+    fn pending(&mut self) -> Result<(u32, u32)>;
+    fn unmask(&mut self, port: u32) -> Result<()>;
+    fn notify(&self, port: u32) -> Result<()>;
+    // Opens another event channel of the same kind, for the ports of a device.
+    fn open(&self) -> Result<Box<dyn EventChannel>>;
+    // Binds an unbound port the guest allocated for the frontend's domain and returns the local
+    // port, which notifies the guest. It is unbound along with the other ports on drop.
+    fn bind_port(&mut self, domid: u16, remote: u32) -> Result<u32>;
+}
+
 pub struct XenEventChannel {
     channel: XenEventChannelHandle,
     ports: Vec<u32>,
@@ -13,57 +31,107 @@ pub struct XenEventChannel {
 
 impl XenEventChannel {
     pub fn new() -> Result<Self> {
-        let channel = XenEventChannelHandle::new().map_err(Error::XenIoctlError)?;
+        let channel = XenEventChannelHandle::new()?;
 
         Ok(Self {
             channel,
             ports: Vec::new(),
         })
     }
+}
 
-    pub fn ports(&self) -> &[u32] {
+impl EventChannel for XenEventChannel {
+    fn ports(&self) -> &[u32] {
         &self.ports
     }
 
-    pub fn bind(&mut self, xfm: &XenForeignMemory, domid: u16, vcpus: u32) -> Result<()> {
-        for cpu in 0..vcpus {
-            let ioreq = xfm.ioreq(cpu)?;
+    fn bind(&mut self, xfm: &dyn ForeignMemory, domid: u16, vcpus: u32) -> Result<()> {
+        let channel = &self.channel;
+        let ports = bind_vcpus(
+            vcpus,
+            |cpu| Ok(channel.bind_interdomain(domid as u32, xfm.ioreq(cpu)?.vp_eport)?),
+            |port| unbind_port(channel, port),
+        )?;
 
-            self.ports.push(
-                self.channel
-                    .bind_interdomain(domid as u32, ioreq.vp_eport)
-                    .map_err(Error::XenIoctlError)?,
-            );
-        }
+        self.ports.extend(ports);
         Ok(())
     }
 
-    pub fn unbind(&self) {
+    fn unbind(&self) {
         for port in &self.ports {
-            if self.channel.unbind(*port).is_err() {
-                println!("XenEventChannel: Failed to unbind port: {}", *port);
-            }
+            unbind_port(&self.channel, *port);
         }
     }
 
-    pub fn fd(&self) -> Result<u32> {
-        Ok(self.channel.fd().map_err(Error::XenIoctlError)? as u32)
+    fn fd(&self) -> Result<u32> {
+        Ok(self.channel.fd()? as u32)
     }
 
-    pub fn pending(&mut self) -> Result<(u32, u32)> {
-        let port = self.channel.pending().map_err(Error::XenIoctlError)?;
-        let cpu = self.ports.iter().position(|&x| x == port).unwrap();
-        Ok((port, cpu as u32))
+    fn pending(&mut self) -> Result<(u32, u32)> {
+        let port = self.channel.pending()?;
+
+        // Ports are bound for all the vcpus the domain can ever have, nr_vcpus() is the maximum and
+        // not the count of online vcpus, so hotplug doesn't need them to be bound again. Anything
+        // else is left masked.
+        Ok((port, port_vcpu(&self.ports, port)?))
     }
 
-    pub fn unmask(&mut self, port: u32) -> Result<()> {
-        self.channel.unmask(port).map_err(Error::XenIoctlError)
+    fn unmask(&mut self, port: u32) -> Result<()> {
+        Ok(self.channel.unmask(port)?)
     }
 
-    pub fn notify(&self, port: u32) -> Result<()> {
-        self.channel.notify(port).map_err(Error::XenIoctlError)?;
+    fn notify(&self, port: u32) -> Result<()> {
+        self.channel.notify(port)?;
         Ok(())
     }
+
+    fn open(&self) -> Result<Box<dyn EventChannel>> {
+        Ok(Box::new(Self::new()?))
+    }
+
+    fn bind_port(&mut self, domid: u16, remote: u32) -> Result<u32> {
+        let port = self.channel.bind_interdomain(domid as u32, remote)?;
+
+        self.ports.push(port);
+        Ok(port)
+    }
+}
+
+fn unbind_port(channel: &XenEventChannelHandle, port: u32) {
+    if channel.unbind(port).is_err() {
+        println!("XenEventChannel: Failed to unbind port: {}", port);
+    }
+}
+
+// Binds a port for each vcpu with bind(), which returns the local port. Don't leave the ports of
+// the other vcpus bound if one fails, they are unbound with unbind() and the caller may try again.
+fn bind_vcpus(
+    vcpus: u32,
+    mut bind: impl FnMut(u32) -> Result<u32>,
+    unbind: impl Fn(u32),
+) -> Result<Vec<u32>> {
+    let mut ports = Vec::new();
+
+    for cpu in 0..vcpus {
+        match bind(cpu) {
+            Ok(port) => ports.push(port),
+            Err(e) => {
+                ports.into_iter().for_each(unbind);
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(ports)
+}
+
+// Returns the vcpu the local port is bound to, the ports are bound in the order of the vcpus.
+fn port_vcpu(ports: &[u32], port: u32) -> Result<u32> {
+    ports
+        .iter()
+        .position(|&x| x == port)
+        .map(|cpu| cpu as u32)
+        .ok_or(Error::UnknownEventPort(port))
 }
 
 impl Drop for XenEventChannel {
@@ -71,3 +139,60 @@ impl Drop for XenEventChannel {
         self.unbind();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stubs::{StubForeignMemory, StubXen, STUB_VCPUS};
+    use std::{io, sync::Mutex};
+
+    // Binding the port of the last vcpu fails, the ones of the other vcpus are unbound.
+    #[test]
+    fn test_bind_vcpus_unwind() {
+        let xfm = StubForeignMemory::new(StubXen::new());
+        for cpu in 0..STUB_VCPUS {
+            xfm.ioreq(cpu).unwrap().vp_eport = 100 + cpu;
+        }
+
+        let unbound = Mutex::new(Vec::new());
+        let bind = |fail: Option<u32>| {
+            bind_vcpus(
+                STUB_VCPUS,
+                |cpu| match fail {
+                    Some(fail) if fail == cpu => {
+                        Err(io::Error::from(io::ErrorKind::AddrInUse).into())
+                    }
+                    _ => Ok(xfm.ioreq(cpu)?.vp_eport + 100),
+                },
+                |port| unbound.lock().unwrap().push(port),
+            )
+        };
+
+        assert!(matches!(bind(Some(STUB_VCPUS - 1)), Err(Error::IoError(_))));
+        let expected: Vec<u32> = (0..STUB_VCPUS - 1).map(|cpu| 200 + cpu).collect();
+        assert_eq!(*unbound.lock().unwrap(), expected);
+
+        // Trying again binds them all.
+        unbound.lock().unwrap().clear();
+        let ports = bind(None).unwrap();
+        assert_eq!(
+            ports,
+            (0..STUB_VCPUS).map(|cpu| 200 + cpu).collect::<Vec<_>>()
+        );
+        assert!(unbound.lock().unwrap().is_empty());
+    }
+
+    // Events on ports not bound for a vcpu are reported, not taken for another vcpu's.
+    #[test]
+    fn test_port_vcpu() {
+        let ports = [12, 10, 11];
+
+        assert_eq!(port_vcpu(&ports, 12).unwrap(), 0);
+        assert_eq!(port_vcpu(&ports, 11).unwrap(), 2);
+        assert!(matches!(
+            port_vcpu(&ports, 13),
+            Err(Error::UnknownEventPort(13))
+        ));
+        assert!(matches!(port_vcpu(&[], 0), Err(Error::UnknownEventPort(0))));
+    }
+}
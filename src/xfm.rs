@@ -4,9 +4,14 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use field_offset::offset_of;
+use std::fs::{File, OpenOptions};
 use std::os::raw::c_void;
 use std::ptr;
 use std::slice;
+use std::sync::Arc;
+
+use vhost_user_frontend::GuestRegionMmap;
+use vm_memory::{guest_memory::FileOffset, GuestAddress, MmapRange, MmapRegion};
 
 use super::{Error, Result};
 use xen_bindings::bindings::{ioreq, ioservid_t, shared_iopage, XENMEM_resource_ioreq_server};
@@ -14,6 +19,55 @@ use xen_ioctls::{
     xenforeignmemory_map_resource, xenforeignmemory_unmap_resource, XenForeignMemoryResourceHandle,
 };
 
+// Access to the ioreq server pages of a guest, implemented over Xen foreign memory mappings by
+// XenForeignMemory.
+pub trait ForeignMemory {
+    fn map_resource(&mut self, domid: u16, id: ioservid_t) -> Result<()>;
+    fn ioreq(&self, vcpu: u32) -> Result<&mut ioreq>;
+    // Devices map guest memory while the guest's thread holds xfm, they get a mapper of their own.
+    fn mapper(&self) -> Arc<dyn MemoryMapper>;
+}
+
+// Maps guest memory for the backends through the Xen device nodes, privcmd for foreign mappings
+// and gntdev for grant mappings.
+pub trait MemoryMapper: Send + Sync {
+    fn open_dev(&self, path: &str) -> Result<File>;
+    fn mmap_region(
+        &self,
+        addr: GuestAddress,
+        size: usize,
+        file: Arc<File>,
+        flags: u32,
+        data: u32,
+    ) -> Result<GuestRegionMmap>;
+}
+
+pub struct XenMemoryMapper;
+
+impl MemoryMapper for XenMemoryMapper {
+    fn open_dev(&self, path: &str) -> Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(Error::XenDeviceNodeOpen)
+    }
+
+    fn mmap_region(
+        &self,
+        addr: GuestAddress,
+        size: usize,
+        file: Arc<File>,
+        flags: u32,
+        data: u32,
+    ) -> Result<GuestRegionMmap> {
+        let range = MmapRange::new(size, Some(FileOffset::from_arc(file, 0)), addr, flags, data);
+        let region = MmapRegion::from_range(range).map_err(|_| Error::RegionMmap(addr.0))?;
+
+        GuestRegionMmap::new(region, addr).map_err(|_| Error::RegionMmap(addr.0))
+    }
+}
+
 pub struct XenForeignMemory {
     res: Option<XenForeignMemoryResourceHandle>,
     ioreq: *mut ioreq,
@@ -27,7 +81,23 @@ impl XenForeignMemory {
         })
     }
 
-    pub fn map_resource(&mut self, domid: u16, id: ioservid_t) -> Result<()> {
+    fn unmap_resource(&mut self) -> Result<()> {
+        if let Some(res) = &self.res {
+            xenforeignmemory_unmap_resource(res)?;
+            self.res = None;
+        }
+
+        Ok(())
+    }
+
+    fn ioreq_offset(&self, vcpu: u32) -> *mut ioreq {
+        // SAFETY: Safe as offset is within range.
+        unsafe { self.ioreq.offset(vcpu as isize) }
+    }
+}
+
+impl ForeignMemory for XenForeignMemory {
+    fn map_resource(&mut self, domid: u16, id: ioservid_t) -> Result<()> {
         let paddr = ptr::null_mut::<c_void>();
         let resource_handle = xenforeignmemory_map_resource(
             domid,
@@ -38,8 +108,7 @@ impl XenForeignMemory {
             paddr,
             libc::PROT_READ | libc::PROT_WRITE,
             0,
-        )
-        .map_err(Error::XenIoctlError)?;
+        )?;
 
         let offset = offset_of!(shared_iopage => vcpu_ioreq).get_byte_offset();
 
@@ -49,26 +118,16 @@ impl XenForeignMemory {
         Ok(())
     }
 
-    fn unmap_resource(&mut self) -> Result<()> {
-        if let Some(res) = &self.res {
-            xenforeignmemory_unmap_resource(res).map_err(Error::XenIoctlError)?;
-            self.res = None;
-        }
-
-        Ok(())
-    }
-
-    fn ioreq_offset(&self, vcpu: u32) -> *mut ioreq {
-        // SAFETY: Safe as offset is within range.
-        unsafe { self.ioreq.offset(vcpu as isize) }
-    }
-
-    pub fn ioreq(&self, vcpu: u32) -> Result<&mut ioreq> {
+    fn ioreq(&self, vcpu: u32) -> Result<&mut ioreq> {
         let ioreq = self.ioreq_offset(vcpu);
 
         // SAFETY: Safe as we slice is guaranteed to be valid.
         Ok(unsafe { &mut slice::from_raw_parts_mut(ioreq, 1)[0] })
     }
+
+    fn mapper(&self) -> Arc<dyn MemoryMapper> {
+        Arc::new(XenMemoryMapper)
+    }
 }
 
 impl Drop for XenForeignMemory {
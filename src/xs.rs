@@ -3,58 +3,242 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::str;
+use std::{
+    ffi::CString,
+    io,
+    num::ParseIntError,
+    process, slice, str,
+    time::{Duration, Instant},
+};
 
-use xen_bindings::bindings::{xs_watch_type, xs_watch_type_XS_WATCH_PATH};
+use libc::{c_void, EAGAIN};
+use xen_bindings::bindings::{
+    xs_close, xs_handle, xs_open, xs_read, xs_transaction_end, xs_transaction_start,
+    xs_transaction_t, xs_watch_type, xs_watch_type_XS_WATCH_PATH, xs_write,
+};
 use xen_store::XenStoreHandle;
 
-use super::{epoll::XenEpoll, Error, Result, BACKEND_PATH};
+use super::{epoll::XenEpoll, Error, Result};
 
 use xen_bindings::bindings::{
     xenbus_state_XenbusStateInitWait, xenbus_state_XenbusStateInitialising,
     xenbus_state_XenbusStateUnknown,
 };
 
+pub const RELEASE_DOMAIN_PATH: &str = "@releaseDomain";
+
+pub enum XsEvent {
+    // A device directory was created (true) or removed (false) under the backend path.
+    Device(u16, u32, bool),
+    // Some domain has been destroyed or has rebooted.
+    ReleaseDomain,
+}
+
+// The Xenstore primitives XsHandle is built upon, implemented by the Xenstore handle. Everything
+// else, the xenbus handshake and the parsing of watch events, is done by XsHandle over them.
+pub trait Store: Send + Sync {
+    fn read_str(&self, path: &str) -> io::Result<String>;
+    fn write_str(&self, path: &str, val: &str) -> io::Result<()>;
+    fn fileno(&self) -> io::Result<i32>;
+    fn create_watch(&mut self, path: &str, token: &str) -> io::Result<()>;
+    fn read_watch(&self, index: xs_watch_type) -> io::Result<String>;
+    // Runs f() in a transaction, which is committed if it returns Ok and aborted otherwise. f()
+    // is run again if another client changed the nodes meanwhile.
+    fn transaction(&self, f: &mut dyn FnMut(&dyn Transaction) -> Result<()>) -> Result<()>;
+}
+
+// The nodes as seen from within a transaction.
+pub trait Transaction {
+    fn read_str(&self, path: &str) -> io::Result<String>;
+    fn write_str(&self, path: &str, val: &str) -> io::Result<()>;
+}
+
+// xen-store doesn't do transactions, they are made over a libxenstore handle of their own.
+struct XsTransaction {
+    xsh: *mut xs_handle,
+    t: xs_transaction_t,
+}
+
+impl Transaction for XsTransaction {
+    fn read_str(&self, path: &str) -> io::Result<String> {
+        let path = CString::new(path)?;
+        let mut len = 0;
+
+        // SAFETY: Safe as the path is NUL terminated and the handle is open, the returned buffer
+        // is owned by us and freed once copied.
+        let val = unsafe { xs_read(self.xsh, self.t, path.as_ptr(), &mut len) };
+        if val.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: Safe as xs_read() returned a buffer of len bytes.
+        let bytes = unsafe { slice::from_raw_parts(val as *const u8, len as usize) }.to_vec();
+        // SAFETY: Safe as the buffer was allocated with malloc() by xs_read() and isn't used
+        // anymore.
+        unsafe { libc::free(val) };
+
+        String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn write_str(&self, path: &str, val: &str) -> io::Result<()> {
+        let path = CString::new(path)?;
+
+        // SAFETY: Safe as the path is NUL terminated, the value is passed with its length and the
+        // handle is open.
+        let ok = unsafe {
+            xs_write(
+                self.xsh,
+                self.t,
+                path.as_ptr(),
+                val.as_ptr() as *const c_void,
+                val.len() as u32,
+            )
+        };
+
+        if ok {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+impl Store for XenStoreHandle {
+    fn read_str(&self, path: &str) -> io::Result<String> {
+        XenStoreHandle::read_str(self, path)
+    }
+
+    fn write_str(&self, path: &str, val: &str) -> io::Result<()> {
+        XenStoreHandle::write_str(self, path, val)
+    }
+
+    fn fileno(&self) -> io::Result<i32> {
+        XenStoreHandle::fileno(self)
+    }
+
+    fn create_watch(&mut self, path: &str, token: &str) -> io::Result<()> {
+        XenStoreHandle::create_watch(self, path, token)
+    }
+
+    fn read_watch(&self, index: xs_watch_type) -> io::Result<String> {
+        XenStoreHandle::read_watch(self, index)
+    }
+
+    fn transaction(&self, f: &mut dyn FnMut(&dyn Transaction) -> Result<()>) -> Result<()> {
+        // SAFETY: Safe as the handle is checked and closed below.
+        let xsh = unsafe { xs_open(0) };
+        if xsh.is_null() {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let result = loop {
+            // SAFETY: Safe as the handle is open.
+            let t = unsafe { xs_transaction_start(xsh) };
+            // XBT_NULL
+            if t == 0 {
+                break Err(io::Error::last_os_error().into());
+            }
+
+            let result = f(&XsTransaction { xsh, t });
+
+            // SAFETY: Safe as the transaction was started over the handle.
+            let committed = unsafe { xs_transaction_end(xsh, t, result.is_err()) };
+            let err = io::Error::last_os_error();
+
+            match result {
+                Ok(()) if !committed && err.raw_os_error() == Some(EAGAIN) => continue,
+                Ok(()) if !committed => break Err(err.into()),
+                result => break result,
+            }
+        };
+
+        // SAFETY: Safe as the handle was opened above and isn't used anymore.
+        unsafe { xs_close(xsh) };
+        result
+    }
+}
+
+// Integers read from Xenstore nodes, written in decimal or in hex with a 0x prefix. Values that
+// don't fit fail to parse.
+pub trait XsInt: Sized {
+    fn from_str_radix(src: &str, radix: u32) -> std::result::Result<Self, ParseIntError>;
+}
+
+macro_rules! impl_xs_int {
+    ($($t:ty),*) => {
+        $(impl XsInt for $t {
+            fn from_str_radix(src: &str, radix: u32) -> std::result::Result<Self, ParseIntError> {
+                <$t>::from_str_radix(src, radix)
+            }
+        })*
+    };
+}
+
+impl_xs_int!(u32, u64);
+
 pub struct XsHandle {
-    handle: XenStoreHandle,
+    handle: Box<dyn Store>,
     epoll: Option<XenEpoll>,
 }
 
 impl XsHandle {
     pub fn new() -> Result<Self> {
-        Ok(Self {
-            handle: XenStoreHandle::new().map_err(Error::XenIoctlError)?,
+        let handle = XenStoreHandle::new()?;
+
+        Ok(Self::with_store(Box::new(handle)))
+    }
+
+    pub fn with_store(handle: Box<dyn Store>) -> Self {
+        Self {
+            handle,
             epoll: None,
-        })
+        }
     }
 
     pub fn new_with_epoll() -> Result<Self> {
-        let mut xsh = Self::new()?;
-        xsh.epoll = Some(XenEpoll::new(vec![xsh.fileno()?])?);
+        Self::new()?.with_epoll()
+    }
+
+    // Sets up waiting for watch events with wait_for_event().
+    pub fn with_epoll(mut self) -> Result<Self> {
+        self.epoll = Some(XenEpoll::new(vec![self.fileno()?])?);
 
-        Ok(xsh)
+        Ok(self)
     }
 
     pub fn read_str(&self, base: &str, node: &str) -> Result<String> {
+        let path = format!("{}/{}", base, node);
+
         self.handle
-            .read_str(format!("{}/{}", base, node).as_str())
-            .map_err(Error::XenIoctlError)
+            .read_str(&path)
+            .map_err(|e| Error::XsRead(path, e))
+    }
+
+    // Returns None if the node doesn't exist.
+    pub fn read_str_opt(&self, base: &str, node: &str) -> Result<Option<String>> {
+        match self.read_str(base, node) {
+            Ok(val) => Ok(Some(val)),
+            Err(Error::XsRead(_, e)) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
     }
 
     fn write_str(&self, base: &str, node: &str, val: &str) -> Result<()> {
+        let path = format!("{}/{}", base, node);
+
         self.handle
-            .write_str(format!("{}/{}", base, node).as_str(), val)
-            .map_err(Error::XenIoctlError)
+            .write_str(&path, val)
+            .map_err(|e| Error::XsWrite(path, e))
     }
 
-    pub fn read_int(&self, base: &str, node: &str) -> Result<u32> {
+    pub fn read_int<T: XsInt>(&self, base: &str, node: &str) -> Result<T> {
         let res = self.read_str(base, node)?;
+        let val = match res.strip_prefix("0x") {
+            Some(x) => T::from_str_radix(x, 16),
+            None => T::from_str_radix(&res, 10),
+        }?;
 
-        match res.strip_prefix("0x") {
-            Some(x) => u32::from_str_radix(x, 16),
-            None => res.parse::<u32>(),
-        }
-        .map_err(Error::ParseFailure)
+        Ok(val)
     }
 
     fn write_int(&self, base: &str, node: &str, val: u32) -> Result<()> {
@@ -64,82 +248,418 @@ impl XsHandle {
     }
 
     pub fn fileno(&self) -> Result<i32> {
-        self.handle.fileno().map_err(Error::XenIoctlError)
+        Ok(self.handle.fileno()?)
     }
 
-    fn wait_state(&self, base: &str, state: u32) -> Result<u32> {
+    fn wait_state(&self, base: &str, state: u32, timeout: Duration) -> Result<u32> {
         let state = state | 1 << xenbus_state_XenbusStateUnknown;
+        let epoll = XenEpoll::new(vec![self.fileno()?])?;
+        let deadline = Instant::now() + timeout;
+        let mut val: u32 = self.read_int(base, "state")?;
 
         loop {
-            let val = self.read_int(base, "state")?;
-
             if ((1 << val) & state) != 0 {
                 return Ok(val);
             }
 
-            self.read_path()?;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || epoll.wait_timeout(remaining.as_millis() as i32)?.is_none() {
+                println!(
+                    "Timed out waiting for {}/state, last seen state {}",
+                    base, val
+                );
+                return Err(Error::XBStateTimeout(val));
+            }
+
+            // Watches are set on the other end's directory as well, ignore events for it.
+            if self.read_path()?.starts_with(base) {
+                val = self.read_int(base, "state")?;
+            }
         }
     }
 
     pub fn create_watch(&mut self, path: String, token: String) -> Result<()> {
         self.handle
             .create_watch(path.as_str(), token.as_str())
-            .map_err(Error::XenIoctlError)
+            .map_err(|e| Error::XsWatch(path, e))
     }
 
     pub fn read_watch(&self, index: xs_watch_type) -> Result<String> {
-        self.handle.read_watch(index).map_err(Error::XenIoctlError)
+        Ok(self.handle.read_watch(index)?)
     }
 
     pub fn read_path(&self) -> Result<String> {
         self.read_watch(xs_watch_type_XS_WATCH_PATH)
     }
 
-    pub fn connect_dom(&mut self, dev_id: u32, fe_domid: u16) -> Result<String> {
-        let be = format!("{}/{}/{}", BACKEND_PATH, fe_domid, dev_id);
+    pub fn set_backend_state(&self, be: &str, state: u32) -> Result<()> {
+        self.write_int(be, "state", state)
+    }
 
-        let state = self.read_int(&be, "state")?;
-        if state != xenbus_state_XenbusStateInitialising {
-            return Err(Error::XBInvalidState);
-        }
-        self.write_int(&be, "state", xenbus_state_XenbusStateInitWait)?;
+    // Moves a new device to InitWait, marking it as served by this process, in case another
+    // instance of the frontend runs in the same domain. This is done in a transaction, only one
+    // of the instances claiming a device at the same time sees it Initialising. The owner is left
+    // behind when the process exits, it doesn't count anymore once the toolstack creates the
+    // device again, Initialising.
+    fn claim_device(&self, be: &str) -> Result<()> {
+        let pid = process::id().to_string();
+        let state_path = format!("{}/state", be);
+        let owner_path = format!("{}/frontend-owner", be);
+
+        self.handle.transaction(&mut |t| {
+            let state = t
+                .read_str(&state_path)
+                .map_err(|e| Error::XsRead(state_path.clone(), e))?;
+
+            if state.parse::<u32>()? != xenbus_state_XenbusStateInitialising {
+                return match t.read_str(&owner_path) {
+                    Ok(owner) => Err(Error::DeviceAlreadyOwned(be.to_string(), owner)),
+                    Err(_) => Err(Error::XBInvalidState),
+                };
+            }
+
+            t.write_str(&owner_path, &pid)
+                .map_err(|e| Error::XsWrite(owner_path.clone(), e))?;
+            t.write_str(&state_path, &xenbus_state_XenbusStateInitWait.to_string())
+                .map_err(|e| Error::XsWrite(state_path.clone(), e))
+        })
+    }
+
+    pub fn connect_dom(
+        &mut self,
+        backend_path: &str,
+        dev_id: u32,
+        fe_domid: u16,
+        timeout: Duration,
+    ) -> Result<(String, String)> {
+        let be = format!("{}/{}/{}", backend_path, fe_domid, dev_id);
+
+        self.claim_device(&be)?;
 
         let fe = self.read_str(&be, "frontend")?;
-        let state = self.read_int(&fe, "state")?;
+        let state: u32 = self.read_int(&fe, "state")?;
         if state != xenbus_state_XenbusStateInitialising {
             return Err(Error::XBInvalidState);
         }
 
         self.create_watch(be.clone(), be.clone())?;
-        self.create_watch(fe.clone(), fe)?;
+        self.create_watch(fe.clone(), fe.clone())?;
 
-        let state = self.wait_state(&be, 1 << xenbus_state_XenbusStateInitWait)?;
+        let state = self.wait_state(&be, 1 << xenbus_state_XenbusStateInitWait, timeout)?;
         if state != xenbus_state_XenbusStateInitWait {
             return Err(Error::XBInvalidState);
         }
 
-        Ok(be)
+        Ok((be, fe))
     }
 
-    pub fn wait_for_device(&mut self) -> Result<(u16, u32, bool)> {
+    // Waits for a device event under `backend_path`, which must be watched already.
+    pub fn wait_for_event(&mut self, backend_path: &str) -> Result<XsEvent> {
         loop {
             self.epoll.as_ref().unwrap().wait()?;
 
             let path = self.read_path()?;
-            let list: Vec<&str> = path.split('/').collect();
+            if path == RELEASE_DOMAIN_PATH {
+                return Ok(XsEvent::ReleaseDomain);
+            }
 
-            // Only parse events where path matches "BACKEND_PATH/<Guest Num>/<Device Num>"
-            if list.len() == 4 {
-                let dev_id = list[3].parse::<u32>().map_err(Error::ParseFailure)?;
-                let fe_domid = list[2].parse::<u16>().map_err(Error::ParseFailure)?;
+            // A watch event carries both the path and the token, but only one of them can be read
+            // per event with xen-store. The watches here use their path as token, match the path
+            // against the backend path watch instead.
+            let rest = match path
+                .strip_prefix(backend_path)
+                .and_then(|rest| rest.strip_prefix('/'))
+            {
+                Some(rest) => rest,
+                None => continue,
+            };
+
+            let list: Vec<&str> = rest.split('/').collect();
+
+            // Only parse events where path matches "<backend path>/<Guest Num>/<Device Num>"
+            if list.len() == 2 {
+                let dev_id = list[1].parse::<u32>()?;
+                let fe_domid = list[0].parse::<u16>()?;
+
+                // The toolstack creates and removes the device directory along with its nodes,
+                // check for the state node instead of the directory itself. Only a missing node
+                // means removal, the event is dropped on other failures.
+                let dir = format!("{}/{}/{}", backend_path, fe_domid, dev_id);
+                let new = match self.read_str(&dir, "state") {
+                    Ok(_) => true,
+                    Err(Error::XsRead(_, e)) if e.kind() == io::ErrorKind::NotFound => false,
+                    Err(e) => {
+                        println!("Failed to read {}/state: {}", dir, e);
+                        continue;
+                    }
+                };
+
+                return Ok(XsEvent::Device(fe_domid, dev_id, new));
+            }
+        }
+    }
+}
 
-                let new = matches!(
-                    self.read_str(BACKEND_PATH, format!("{}/{}", fe_domid, dev_id).as_str()),
-                    Ok(_)
-                );
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stubs::{StubNodes, StubStore, StubWatches};
+    use std::{sync::Arc, thread};
 
-                return Ok((fe_domid, dev_id, new));
-            }
+    const BE: &str = "backend/virtio/1/0";
+
+    fn stub_handle(nodes: &StubNodes) -> XsHandle {
+        XsHandle::with_store(Box::new(StubStore::new(nodes.clone())))
+    }
+
+    fn stub_nodes(state: u32, owner: Option<&str>) -> StubNodes {
+        let nodes = StubNodes::default();
+        let mut map = nodes.lock().unwrap();
+
+        map.insert(format!("{}/state", BE), state.to_string());
+        if let Some(owner) = owner {
+            map.insert(format!("{}/frontend-owner", BE), owner.to_string());
+        }
+
+        drop(map);
+        nodes
+    }
+
+    #[test]
+    fn test_claim_device() {
+        let nodes = stub_nodes(xenbus_state_XenbusStateInitialising, None);
+        let pid = process::id().to_string();
+
+        stub_handle(&nodes).claim_device(BE).unwrap();
+        let xsh = stub_handle(&nodes);
+        assert_eq!(xsh.read_str(BE, "frontend-owner").unwrap(), pid);
+        assert_eq!(
+            xsh.read_int::<u32>(BE, "state").unwrap(),
+            xenbus_state_XenbusStateInitWait
+        );
+
+        // Claimed already, by this process or another one.
+        assert!(matches!(
+            xsh.claim_device(BE),
+            Err(Error::DeviceAlreadyOwned(be, owner)) if be == BE && owner == pid
+        ));
+
+        let nodes = stub_nodes(xenbus_state_XenbusStateInitWait, None);
+        assert!(matches!(
+            stub_handle(&nodes).claim_device(BE),
+            Err(Error::XBInvalidState)
+        ));
+    }
+
+    // The owner left behind by a frontend that went away doesn't count for a device that is new.
+    #[test]
+    fn test_claim_device_stale_owner() {
+        let nodes = stub_nodes(xenbus_state_XenbusStateInitialising, Some("4194304"));
+
+        stub_handle(&nodes).claim_device(BE).unwrap();
+        assert_eq!(
+            stub_handle(&nodes).read_str(BE, "frontend-owner").unwrap(),
+            process::id().to_string()
+        );
+    }
+
+    // Only one of the frontends claiming a device at the same time gets it.
+    #[test]
+    fn test_claim_device_conflict() {
+        let nodes = stub_nodes(xenbus_state_XenbusStateInitialising, None);
+
+        let claims: Vec<_> = (0..8)
+            .map(|_| {
+                let xsh = stub_handle(&nodes);
+                thread::spawn(move || xsh.claim_device(BE))
+            })
+            .map(|handle| handle.join().unwrap())
+            .collect();
+
+        assert_eq!(claims.iter().filter(|claim| claim.is_ok()).count(), 1);
+        assert!(claims
+            .iter()
+            .all(|claim| matches!(claim, Ok(()) | Err(Error::DeviceAlreadyOwned(..)))));
+    }
+
+    // Device bases above 4GB don't fit in 32 bits.
+    #[test]
+    fn test_read_int() {
+        let nodes = StubNodes::default();
+        let mut map = nodes.lock().unwrap();
+        map.insert(format!("{}/base", BE), "0x100000000".to_string());
+        map.insert(format!("{}/irq", BE), "33".to_string());
+        drop(map);
+        let xsh = stub_handle(&nodes);
+
+        assert_eq!(xsh.read_int::<u64>(BE, "base").unwrap(), 1 << 32);
+        assert!(matches!(
+            xsh.read_int::<u32>(BE, "base"),
+            Err(Error::ParseFailure(_))
+        ));
+        assert_eq!(xsh.read_int::<u32>(BE, "irq").unwrap(), 33);
+        assert_eq!(xsh.read_int::<u64>(BE, "irq").unwrap(), 33);
+    }
+
+    // The frontend never gets the device to InitWait, events for its directory don't count.
+    #[test]
+    fn test_wait_state_timeout() {
+        let nodes = stub_nodes(xenbus_state_XenbusStateInitialising, None);
+        let store = StubStore::new(nodes.clone());
+        let watches = store.watches();
+        let xsh = XsHandle::with_store(Box::new(store));
+        let init_wait = 1 << xenbus_state_XenbusStateInitWait;
+
+        watches.fire("/local/domain/1/device/virtio/0/state");
+        let start = Instant::now();
+        assert!(matches!(
+            xsh.wait_state(BE, init_wait, Duration::from_millis(50)),
+            Err(Error::XBStateTimeout(state)) if state == xenbus_state_XenbusStateInitialising
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // It gets there this time.
+        let setter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            nodes.lock().unwrap().insert(
+                format!("{}/state", BE),
+                xenbus_state_XenbusStateInitWait.to_string(),
+            );
+            watches.fire(&format!("{}/state", BE));
+        });
+        assert_eq!(
+            xsh.wait_state(BE, init_wait, Duration::from_secs(5))
+                .unwrap(),
+            xenbus_state_XenbusStateInitWait
+        );
+        setter.join().unwrap();
+    }
+
+    // A handle waiting for the watch events fired by the test.
+    fn watched_handle(nodes: &StubNodes) -> (XsHandle, Arc<StubWatches>) {
+        let store = StubStore::new(nodes.clone());
+        let watches = store.watches();
+
+        (
+            XsHandle::with_store(Box::new(store)).with_epoll().unwrap(),
+            watches,
+        )
+    }
+
+    // Only the events for device directories right under the backend path, and the domain
+    // releases, are reported. The per-device watches fire for the nodes of the devices and of their
+    // frontends too.
+    #[test]
+    fn test_wait_for_event_filter() {
+        let nodes = stub_nodes(xenbus_state_XenbusStateInitialising, None);
+        let (mut xsh, watches) = watched_handle(&nodes);
+
+        for path in [
+            "backend/virtio",
+            "backend/virtio/1",
+            "backend/virtio/1/0/state",
+            "backend/virtio/1/0/frontend-owner",
+            "backend/virtio-other/1/0",
+            "/local/domain/1/device/virtio/0",
+            "/local/domain/1/device/virtio/0/state",
+        ] {
+            watches.fire(path);
         }
+        watches.fire(RELEASE_DOMAIN_PATH);
+        watches.fire(BE);
+
+        assert!(matches!(
+            xsh.wait_for_event("backend/virtio").unwrap(),
+            XsEvent::ReleaseDomain
+        ));
+        assert!(matches!(
+            xsh.wait_for_event("backend/virtio").unwrap(),
+            XsEvent::Device(1, 0, true)
+        ));
+    }
+
+    // The toolstack writes the nodes of a new device and removes them all along with the
+    // directory, which fires the watch for the directory and each of the nodes.
+    #[test]
+    fn test_wait_for_event_add_remove() {
+        let nodes = StubNodes::default();
+        let (mut xsh, watches) = watched_handle(&nodes);
+        let fire_all = || {
+            watches.fire(BE);
+            watches.fire(&format!("{}/state", BE));
+            watches.fire(&format!("{}/type", BE));
+        };
+
+        nodes.lock().unwrap().extend([
+            (format!("{}/state", BE), "1".to_string()),
+            (format!("{}/type", BE), "virtio,device22".to_string()),
+        ]);
+        fire_all();
+        assert!(matches!(
+            xsh.wait_for_event("backend/virtio").unwrap(),
+            XsEvent::Device(1, 0, true)
+        ));
+
+        nodes.lock().unwrap().clear();
+        fire_all();
+        assert!(matches!(
+            xsh.wait_for_event("backend/virtio").unwrap(),
+            XsEvent::Device(1, 0, false)
+        ));
+    }
+
+    // Devices under another backend path, for a frontend started with --backend-path.
+    #[test]
+    fn test_custom_backend_path() {
+        let root = "test/vhost";
+        let be = format!("{}/1/0", root);
+        let fe = "/local/domain/1/device/virtio/0";
+        let initialising = xenbus_state_XenbusStateInitialising.to_string();
+
+        let nodes = StubNodes::default();
+        nodes.lock().unwrap().extend([
+            (format!("{}/state", be), initialising.clone()),
+            (format!("{}/frontend", be), fe.to_string()),
+            (format!("{}/state", fe), initialising),
+        ]);
+        let (mut xsh, watches) = watched_handle(&nodes);
+
+        // Events for devices under the default path are skipped.
+        watches.fire(BE);
+        watches.fire(&be);
+        assert!(matches!(
+            xsh.wait_for_event(root).unwrap(),
+            XsEvent::Device(1, 0, true)
+        ));
+
+        let (found_be, found_fe) = xsh
+            .connect_dom(root, 0, 1, Duration::from_millis(100))
+            .unwrap();
+        assert_eq!((found_be.as_str(), found_fe.as_str()), (be.as_str(), fe));
+        assert_eq!(
+            xsh.read_int::<u32>(&be, "state").unwrap(),
+            xenbus_state_XenbusStateInitWait
+        );
+    }
+
+    // Failures tell which node it was about.
+    #[test]
+    fn test_read_error_path() {
+        let nodes = stub_nodes(xenbus_state_XenbusStateInitialising, None);
+        let xsh = stub_handle(&nodes);
+        let irq = format!("{}/irq", BE);
+
+        let e = xsh.read_int::<u32>(BE, "irq").unwrap_err();
+        assert!(e.to_string().contains(&irq));
+        assert!(
+            matches!(e, Error::XsRead(path, e) if path == irq && e.kind() == io::ErrorKind::NotFound)
+        );
+        assert!(xsh.read_str_opt(BE, "irq").unwrap().is_none());
+
+        // Including the ones within a transaction.
+        assert!(matches!(
+            xsh.claim_device("backend/virtio/1/1"),
+            Err(Error::XsRead(path, _)) if path == "backend/virtio/1/1/state"
+        ));
     }
 }
@@ -0,0 +1,84 @@
+// Copyright 2022-2023 Linaro Ltd. All Rights Reserved.
+//          Viresh Kumar <viresh.kumar@linaro.org>
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Runs the frontend over the stub Xen handles and backends for many device add/remove cycles,
+// with ioreqs and interrupts going through each device. Needs the stubs feature:
+//
+//     cargo test --features stubs --test soak
+
+use std::{sync::Arc, thread, time::Duration};
+
+use vhost_user_frontend::{VirtioInterrupt, VirtioInterruptType};
+use virtio_bindings::virtio_mmio::{
+    VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INTERRUPT_STATUS, VIRTIO_MMIO_INT_VRING,
+    VIRTIO_MMIO_MAGIC_VALUE,
+};
+use xen_vhost_frontend::{
+    stubs::{stub_args, Calls, StubBackend, StubGuest},
+    XenDevice,
+};
+
+const CYCLES: usize = 1000;
+
+// The device's thread forwards the backend's notification to the guest on its own time.
+fn wait_interrupt(stub: &StubGuest, dev: &XenDevice) -> u32 {
+    for _ in 0..1000 {
+        let status = stub.read(dev, VIRTIO_MMIO_INTERRUPT_STATUS as u64);
+        if status != 0 {
+            return status;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    0
+}
+
+// A device references its guest, and is referenced by its own threads and interrupt until it
+// exits. Nothing of a removed device must be left behind.
+#[test]
+fn test_add_remove_cycles() {
+    let stub = StubGuest::new(stub_args(&[]));
+    let calls = Arc::new(Calls::default());
+    let guest_refs = Arc::strong_count(&stub.guest);
+
+    for _ in 0..CYCLES {
+        let backend = StubBackend::new(vec![1024], 0, calls.clone());
+        let dev = stub.add_device(0, "i2c", backend, true).unwrap();
+
+        // An ioreq dispatched to the device by the guest's thread.
+        assert_eq!(
+            stub.read(&dev, VIRTIO_MMIO_MAGIC_VALUE as u64),
+            u32::from_le_bytes(*b"virt")
+        );
+
+        // A used buffer notification of the backend, delivered to the guest.
+        let call = dev
+            .interrupt()
+            .notifier(VirtioInterruptType::Queue(0))
+            .unwrap();
+        call.write(1).unwrap();
+        assert_eq!(wait_interrupt(&stub, &dev), VIRTIO_MMIO_INT_VRING);
+        stub.write(
+            &dev,
+            VIRTIO_MMIO_INTERRUPT_ACK as u64,
+            VIRTIO_MMIO_INT_VRING,
+        );
+        drop(call);
+
+        stub.guest.remove_device(dev.dev_id);
+        assert!(stub.guest.is_empty());
+        assert_eq!(Arc::strong_count(&dev), 1);
+    }
+
+    assert_eq!(Arc::strong_count(&stub.guest), guest_refs);
+
+    let xen_calls = stub.xen.calls.take();
+    let count = |calls: &[String], call: &str| calls.iter().filter(|c| *c == call).count();
+    assert_eq!(count(&xen_calls, "map_io_range 0x2000000 0x200"), CYCLES);
+    assert_eq!(count(&xen_calls, "unmap_io_range 0x2000000 0x200"), CYCLES);
+    assert_eq!(count(&xen_calls, "set_irqfd 33 true"), CYCLES);
+    assert_eq!(count(&xen_calls, "set_irqfd 33 false"), CYCLES);
+    assert_eq!(count(&calls.take(), "shutdown"), CYCLES);
+}